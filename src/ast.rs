@@ -4,7 +4,6 @@ use std::borrow::Borrow;
 
 use diff_enum::common_fields;
 
-use super::symgen;
 use super::syntax;
 use super::values::*;
 use super::lambda::*;
@@ -18,131 +17,16 @@ pub enum Act {
 }
 #[derive(Clone, Debug)] pub enum Process {
     Restriction (String, f64, Rc<Process>),
-    LetVal (String, Lambda, Rc<Process>),
+    LetVal (Pattern, Lambda, Rc<Process>),
     Parallel (Rc<Process>, Rc<Process>),
     Summation (Rc<Summ>),
     Instance (String, Vec<Lambda>),
     Repetition (usize, Rc<Process>),
     Replication (Act, Rc<Process>),
+    Match (Lambda, Vec<(Pattern, Rc<Process>)>),
     Termination
 }
 
-impl Substitutable<&str> for Act {
-    fn substitute(&self, src : &str, dest : &str) -> Act {
-        match self {
-            Act::Input (c) => 
-                Act::Input (if *c == *src {dest.to_string()} else {c.to_string()}),
-            Act::Output (c) => 
-                Act::Output (if *c == *src {dest.to_string()} else {c.to_string()})
-        }
-    }
-}
-
-impl Substitutable<Lambda> for Act {
-    fn substitute(&self, _src : &str, _dest : Lambda) -> Act {
-        match self {
-            /* Act::Input (c) => 
-                Act::Input (if *c == *src {dest.to_string()} else {c.to_string()}),
-            Act::Output (c) => 
-                Act::Output (if *c == *src {dest.to_string()} else {c.to_string()}) */
-            _ => self.clone()
-        }
-    }
-}
-
-impl Substitutable<&str> for Process {
-    fn substitute(&self, src : &str, dest : &str) -> Process {
-        match self {
-            Process::Restriction (c, r, ref p) => 
-                Process::Restriction (c.clone(), *r, Rc::new(p.substitute(src, dest))),
-            Process::LetVal (ref v, ref l, ref p) => {
-                Process::LetVal (v.to_string(), l.clone(), Rc::new(p.substitute(src, dest)))
-            },
-            Process::Parallel (ref p1, ref p2) => 
-                Process::Parallel (
-                    Rc::new(p1.substitute(src, dest)), 
-                    Rc::new(p2.substitute(src, dest))),
-            Process::Summation (apvec) => 
-                Process::Summation (
-                    Rc::new(apvec.clone().iter()
-                    .map(|(a, p)| (a.substitute(src, dest), Rc::new(p.substitute(src, dest))))
-                    .collect())),
-            Process::Instance (name, params) => {
-                Process::Instance (name.to_string(), params.clone())
-            },
-            Process::Repetition (i, ref p) => 
-                Process::Repetition (*i, Rc::new(p.substitute(src, dest))),
-            Process::Replication (a, ref p) =>
-                Process::Replication (
-                    a.substitute(src, dest),
-                    Rc::new(p.substitute(src, dest))),
-            Process::Termination => Process::Termination
-        }
-    }
-}
-
-impl Substitutable<Lambda> for Process {
-    fn substitute(&self, src : &str, dest : Lambda) -> Process {
-        match self {
-            Process::Restriction (c, r, ref p) => 
-                Process::Restriction (c.clone(), *r, Rc::new(p.substitute(src, dest))),
-            Process::LetVal (ref v, ref l, ref p) => {
-                Process::LetVal (v.to_string(), l.substitute(src, dest.clone()), Rc::new(p.substitute(src, dest.clone())))
-            },
-            Process::Parallel (ref p1, ref p2) => 
-                Process::Parallel (
-                    Rc::new(p1.substitute(src, dest.clone())), 
-                    Rc::new(p2.substitute(src, dest.clone()))),
-            Process::Summation (apvec) => 
-                Process::Summation (
-                    Rc::new(apvec.clone().iter()
-                    .map(|(a, p)| (a.substitute(src, dest.clone()), Rc::new(p.substitute(src, dest.clone()))))
-                    .collect())),
-            Process::Instance (name, params) => {
-                Process::Instance (name.to_string(), params.iter().map(|x| x.substitute(src, dest.clone())).collect())
-            },
-            Process::Repetition (i, ref p) => 
-                Process::Repetition (*i, Rc::new(p.substitute(src, dest.clone()))),
-            Process::Replication (a, ref p) =>
-                Process::Replication (
-                    a.substitute(src, dest.clone()),
-                    Rc::new(p.substitute(src, dest.clone()))),
-            Process::Termination => Process::Termination
-        }
-    }
-}
-
-impl Substitutable<&Lambda> for Process {
-    fn substitute(&self, src : &str, dest : &Lambda) -> Process {
-        match self {
-            Process::Restriction (c, r, ref p) => 
-                Process::Restriction (c.clone(), *r, Rc::new(p.substitute(src, dest))),
-            Process::LetVal (ref v, ref l, ref p) => {
-                Process::LetVal (v.to_string(), l.substitute(src, dest.clone()), Rc::new(p.substitute(src, dest.clone())))
-            },
-            Process::Parallel (ref p1, ref p2) => 
-                Process::Parallel (
-                    Rc::new(p1.substitute(src, dest.clone())), 
-                    Rc::new(p2.substitute(src, dest.clone()))),
-            Process::Summation (apvec) => 
-                Process::Summation (
-                    Rc::new(apvec.clone().iter()
-                    .map(|(a, p)| (a.substitute(src, dest.clone()), Rc::new(p.substitute(src, dest.clone()))))
-                    .collect())),
-            Process::Instance (name, params) => {
-                Process::Instance (name.to_string(), params.iter().map(|x| x.substitute(src, dest.clone())).collect())
-            },
-            Process::Repetition (i, ref p) => 
-                Process::Repetition (*i, Rc::new(p.substitute(src, dest.clone()))),
-            Process::Replication (a, ref p) =>
-                Process::Replication (
-                    a.substitute(src, dest.clone()),
-                    Rc::new(p.substitute(src, dest.clone()))),
-            Process::Termination => Process::Termination
-        }
-    }
-}
-
 impl From<&syntax::Act> for Act {
     fn from(syn : &syntax::Act) -> Act {
         match syn {
@@ -152,34 +36,6 @@ impl From<&syntax::Act> for Act {
     }
 }
 
-fn recurse (pat : &Pattern, parent : String) -> Vec<(String, Lambda)> {
-    let mut vl = Vec::new();
-    match pat {
-        Pattern::Wildcard => panic!(),
-        Pattern::Name (n) => {
-            vl.push((n.to_string(), Lambda::Var { v : parent.to_string(), t : Type::Unit }));
-        }
-        Pattern::Tuple (pl) => {
-            for (i, p) in pl.iter().enumerate() {
-                let v = symgen::next();
-                vl.push((v.clone(), Lambda::Index { i : i as i64, e : Rc::new(Lambda::Var { v : parent.to_string(), t : Type::Unit }), t : Type::Unit }));
-                recurse(p.borrow(), v.clone());
-            }
-        }
-    }
-    vl
-}
-
-fn destruct(pat : &Pattern, expr : Lambda, base : Process) -> Process {
-    let newvar = symgen::next();
-    let vl = recurse(pat, newvar.to_string());
-    let mut current = base;
-    for (v, l) in vl.iter().rev() {
-        current = Process::LetVal (v.to_string(), l.clone(), Rc::new(current));
-    }
-    Process::LetVal (newvar, expr, Rc::new(current))
-}
-
 impl From<&syntax::Process> for Process {
     fn from(syn : &syntax::Process) -> Process {
         match *syn {
@@ -187,13 +43,7 @@ impl From<&syntax::Process> for Process {
                 Process::Restriction (c.to_string(), r, Rc::new(Process::from(p.borrow())))
             },
             syntax::Process::LetVal (ref pat, ref l, ref p) => {
-                match pat {
-                    Pattern::Wildcard => panic!(),
-                    Pattern::Name (n) => Process::LetVal (n.to_string(), l.clone(), Rc::new(Process::from(p.borrow()))),
-                    Pattern::Tuple (pl) => {
-                        destruct(&pat.clone(), l.clone(), Process::from(p.borrow()))
-                    }
-                }
+                Process::LetVal (pat.clone(), l.clone(), Rc::new(Process::from(p.borrow())))
             },
             syntax::Process::Parallel (ref p) => {
                 let pborrow : &Vec<Rc<syntax::Process>> = p.borrow();
@@ -230,21 +80,33 @@ impl From<&syntax::Process> for Process {
                 let pbb : Process = Process::from(pb);
                 Process::Replication (a.into(), Rc::new(pbb))
             },
+            syntax::Process::Match (ref l, ref arms) =>
+                Process::Match (l.clone(), arms.iter().map(|(pat, p)| (pat.clone(), Rc::new(Process::from(p.borrow())))).collect()),
             syntax::Process::Termination => Process::Termination
         }
     }
 }
 
 impl Process {
-    pub fn replace(&self, formals : &Pattern, vals : &Lambda) -> Process {
-        match (formals, vals) {
-            (Pattern::Wildcard, _) => self.clone(),
-            (Pattern::Name (n), l) => self.substitute(n.as_str(), l),
-            (Pattern::Tuple (ref tv), Lambda::Tuple { tup, t }) => {
-                tv.iter().zip(tup.iter()).fold(self.clone(), |p1, (pat, v)| p1.replace(pat, v))
+    // the first case of a match that accepts the value, with the environment it runs in
+    pub fn select<'a>(arms : &'a [(Pattern, Rc<Process>)], v : &Lambda, env : &Env) -> Option<(&'a Rc<Process>, Env)> {
+        arms.iter().find_map(|(pat, p)| bindings(pat, v).map(|bs| (p, env.extend(bs))))
+    }
+    // a definition whose body is a match or a val is an instance of whatever it settles on
+    pub fn settle(p : &Rc<Process>, env : Env) -> Result<(Rc<Process>, Env), String> {
+        match &**p {
+            Process::LetVal (pat, l, q) => {
+                let bs = destructure(pat, &l.eval_in(&env)?)?;
+                Process::settle(q, env.extend(bs))
             },
-            (Pattern::Tuple (_), _) => panic!()
+            Process::Match (l, arms) => {
+                let v = l.eval_in(&env)?;
+                match Process::select(arms, &v, &env) {
+                    Some ((q, env)) => Process::settle(q, env),
+                    None => Err (format!("no case of the match accepts {}", v))
+                }
+            },
+            _ => Ok ((p.clone(), env))
         }
     }
 }
-
@@ -0,0 +1,550 @@
+
+use std::fmt;
+use std::rc::Rc;
+
+use super::ast;
+use super::values::*;
+use super::lambda::*;
+use super::machineterm;
+
+#[derive(Clone, Debug)]
+pub enum Sexp {
+    Atom (String),
+    List (Vec<Sexp>)
+}
+
+impl fmt::Display for Sexp {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sexp::Atom (a) => write!(f, "{}", a),
+            Sexp::List (l) => {
+                write!(f, "(")?;
+                for (i, x) in l.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", x)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+fn atom<T : ToString>(x : T) -> Sexp {
+    Sexp::Atom (x.to_string())
+}
+
+fn list(tag : &str, mut rest : Vec<Sexp>) -> Sexp {
+    rest.insert(0, atom(tag));
+    Sexp::List (rest)
+}
+
+pub fn parse(s : &str) -> Result<Sexp, String> {
+    fn go(toks : &[String], pos : &mut usize) -> Result<Sexp, String> {
+        match toks.get(*pos).map(|t| t.as_str()) {
+            Some ("(") => {
+                *pos += 1;
+                let mut v = Vec::new();
+                loop {
+                    match toks.get(*pos).map(|t| t.as_str()) {
+                        Some (")") => {
+                            *pos += 1;
+                            return Ok (Sexp::List (v));
+                        },
+                        Some (_) => v.push(go(toks, pos)?),
+                        None => return Err ("unbalanced parentheses".to_string())
+                    }
+                }
+            },
+            Some (")") => Err ("unexpected )".to_string()),
+            Some (a) => {
+                *pos += 1;
+                Ok (Sexp::Atom (a.to_string()))
+            },
+            None => Err ("unexpected end of input".to_string())
+        }
+    }
+    let toks : Vec<String> = s.replace("(", " ( ").replace(")", " ) ")
+        .split_whitespace().map(|t| t.to_string()).collect();
+    let mut pos = 0;
+    let e = go(&toks, &mut pos)?;
+    if pos != toks.len() {
+        return Err ("trailing input after checkpoint".to_string());
+    }
+    Ok (e)
+}
+
+impl Sexp {
+    pub fn tagged(&self) -> Result<(&str, &[Sexp]), String> {
+        match self {
+            Sexp::List (l) => match l.split_first() {
+                Some ((Sexp::Atom (t), rest)) => Ok ((t.as_str(), rest)),
+                _ => Err (format!("expected a tagged list, found {}", self))
+            },
+            Sexp::Atom (a) => Ok ((a.as_str(), &[]))
+        }
+    }
+    pub fn string(&self) -> Result<String, String> {
+        match self {
+            Sexp::Atom (a) => Ok (a.clone()),
+            _ => Err (format!("expected an atom, found {}", self))
+        }
+    }
+    pub fn num<T : std::str::FromStr>(&self) -> Result<T, String> {
+        let a = self.string()?;
+        a.parse::<T>().map_err(|_| format!("malformed number {}", a))
+    }
+    pub fn field(&self, tag : &str) -> Result<&[Sexp], String> {
+        match self {
+            Sexp::List (l) => {
+                for x in l.iter() {
+                    if let Ok ((t, rest)) = x.tagged() {
+                        if t == tag {
+                            if let Sexp::List (_) = x {
+                                return Ok (rest);
+                            }
+                        }
+                    }
+                }
+                Err (format!("missing field {}", tag))
+            },
+            _ => Err (format!("missing field {}", tag))
+        }
+    }
+}
+
+fn arity(tag : &str, args : &[Sexp], n : usize) -> Result<(), String> {
+    if args.len() != n {
+        Err (format!("{} expects {} fields but found {}", tag, n, args.len()))
+    }
+    else {
+        Ok (())
+    }
+}
+
+pub fn encode_type(t : &Type) -> Sexp {
+    match t {
+        Type::Unit => atom("unit"),
+        Type::TVar => atom("tvar"),
+        Type::Integer => atom("int"),
+        Type::Float => atom("float"),
+        Type::Str => atom("string"),
+        Type::Bool => atom("bool"),
+        Type::Channel (None) => list("chan", vec![]),
+        Type::Channel (Some (t)) => list("chan", vec![encode_type(t)]),
+        Type::Constructor (ts) => list("ctor", ts.iter().map(encode_type).collect()),
+        Type::Tuple => atom("tuple"),
+        Type::List => atom("list"),
+        Type::Function (a, b) => list("fun", vec![encode_type(a), encode_type(b)])
+    }
+}
+
+pub fn decode_type(s : &Sexp) -> Result<Type, String> {
+    let (tag, args) = s.tagged()?;
+    match tag {
+        "unit" => Ok (Type::Unit),
+        "tvar" => Ok (Type::TVar),
+        "int" => Ok (Type::Integer),
+        "float" => Ok (Type::Float),
+        "string" => Ok (Type::Str),
+        "bool" => Ok (Type::Bool),
+        "chan" => match args.first() {
+            Some (t) => Ok (Type::Channel (Some (Rc::new(decode_type(t)?)))),
+            None => Ok (Type::Channel (None))
+        },
+        "ctor" => Ok (Type::Constructor (args.iter().map(decode_type).collect::<Result<_, _>>()?)),
+        "tuple" => Ok (Type::Tuple),
+        "list" => Ok (Type::List),
+        "fun" => {
+            arity(tag, args, 2)?;
+            Ok (Type::Function (Rc::new(decode_type(&args[0])?), Rc::new(decode_type(&args[1])?)))
+        },
+        _ => Err (format!("unknown type {}", tag))
+    }
+}
+
+pub fn encode_lambda(l : &Lambda) -> Sexp {
+    match l {
+        Lambda::IntLiteral { i, t } => list("int", vec![atom(i), encode_type(t)]),
+        Lambda::FloatLiteral { f, t } => list("float", vec![atom(f), encode_type(t)]),
+        // a string goes byte by byte, since an atom cannot hold spaces or parentheses
+        Lambda::StrLiteral { s, t } => {
+            let mut v = vec![encode_type(t)];
+            v.extend(s.bytes().map(atom));
+            list("str", v)
+        },
+        Lambda::True { t } => list("true", vec![encode_type(t)]),
+        Lambda::False { t } => list("false", vec![encode_type(t)]),
+        Lambda::Var { v, t } => list("var", vec![atom(v), encode_type(t)]),
+        Lambda::Tuple { tup, t } => {
+            let mut v = vec![encode_type(t)];
+            v.extend(tup.iter().map(encode_lambda));
+            list("tuple", v)
+        },
+        Lambda::Index { i, e, t } => list("index", vec![atom(i), encode_lambda(e), encode_type(t)]),
+        Lambda::Abs { x, e, t } => list("abs", vec![atom(x), encode_lambda(e), encode_type(t)]),
+        Lambda::App { lhs, rhs, t } => list("app", vec![encode_lambda(lhs), encode_lambda(rhs), encode_type(t)]),
+        Lambda::IfExpr { c, e1, e2, t } =>
+            list("if", vec![encode_lambda(c), encode_lambda(e1), encode_lambda(e2), encode_type(t)]),
+        Lambda::BinExpr { b, l, r, t } =>
+            list("bin", vec![atom(b), encode_lambda(l), encode_lambda(r), encode_type(t)]),
+        Lambda::Call { f, args, t } => {
+            let mut v = vec![atom(f), encode_type(t)];
+            v.extend(args.iter().map(encode_lambda));
+            list("call", v)
+        },
+        Lambda::Construct { c, args, t } => {
+            let mut v = vec![atom(c), encode_type(t)];
+            v.extend(args.iter().map(encode_lambda));
+            list("con", v)
+        },
+        Lambda::Match { e, arms, t } => {
+            let mut v = vec![encode_lambda(e), encode_type(t)];
+            v.extend(arms.iter().map(|(p, x)| Sexp::List (vec![encode_pattern(p), encode_lambda(x)])));
+            list("lmatch", v)
+        },
+        Lambda::List { elems, t } => {
+            let mut v = vec![encode_type(t)];
+            v.extend(elems.iter().map(encode_lambda));
+            list("list", v)
+        },
+        Lambda::LetRec { f, x, e1, e2, t } =>
+            list("letrec", vec![atom(f), atom(x), encode_lambda(e1), encode_lambda(e2), encode_type(t)]),
+        Lambda::Closure { f : None, x, e, env, t } =>
+            list("closure", vec![atom(x), encode_lambda(e), encode_env(env), encode_type(t)]),
+        Lambda::Closure { f : Some (f), x, e, env, t } =>
+            list("recclosure", vec![atom(f), atom(x), encode_lambda(e), encode_env(env), encode_type(t)])
+    }
+}
+
+// the bindings are written outermost first so that decoding can rebuild the chain in order
+pub fn encode_env(env : &Env) -> Sexp {
+    list("env", env.bindings().into_iter().rev().map(|(x, v)| Sexp::List (vec![atom(x), encode_lambda(v)])).collect())
+}
+
+pub fn decode_env(s : &Sexp) -> Result<Env, String> {
+    let (tag, args) = s.tagged()?;
+    if tag != "env" {
+        return Err (format!("malformed environment {}", s));
+    }
+    args.iter().try_fold(Env::new(), |env, b| match b {
+        Sexp::List (l) if l.len() == 2 => Ok (env.bind(&l[0].string()?, decode_lambda(&l[1])?)),
+        _ => Err (format!("malformed binding {}", b))
+    })
+}
+
+pub fn encode_pattern(p : &Pattern) -> Sexp {
+    match p {
+        Pattern::Wildcard => atom("_"),
+        Pattern::Name (n) => list("name", vec![atom(n)]),
+        Pattern::Tuple (pl) => list("ptuple", pl.iter().map(encode_pattern).collect()),
+        Pattern::Constructor (c, pl) => {
+            let mut v = vec![atom(c)];
+            v.extend(pl.iter().map(encode_pattern));
+            list("pcon", v)
+        }
+    }
+}
+
+pub fn decode_pattern(s : &Sexp) -> Result<Pattern, String> {
+    let (tag, args) = s.tagged()?;
+    match tag {
+        "_" => Ok (Pattern::Wildcard),
+        "name" => {
+            arity(tag, args, 1)?;
+            Ok (Pattern::Name (args[0].string()?))
+        },
+        "ptuple" => Ok (Pattern::Tuple (args.iter().map(decode_pattern).collect::<Result<_, _>>()?)),
+        "pcon" => match args.split_first() {
+            Some ((c, pl)) => Ok (Pattern::Constructor (c.string()?, pl.iter().map(decode_pattern).collect::<Result<_, _>>()?)),
+            None => Err ("constructor pattern is missing its name".to_string())
+        },
+        _ => Err (format!("unknown pattern {}", tag))
+    }
+}
+
+fn decode_binop(s : &Sexp) -> Result<BinOp, String> {
+    match s.string()?.as_str() {
+        "+" => Ok (BinOp::Plus),
+        "-" => Ok (BinOp::Sub),
+        "*" => Ok (BinOp::Times),
+        "/" => Ok (BinOp::Div),
+        "=" => Ok (BinOp::Equal),
+        "<" => Ok (BinOp::Less),
+        ">" => Ok (BinOp::Greater),
+        "<=" => Ok (BinOp::LEq),
+        ">=" => Ok (BinOp::GEq),
+        "<>" => Ok (BinOp::NotEqual),
+        o => Err (format!("unknown operator {}", o))
+    }
+}
+
+pub fn decode_lambda(s : &Sexp) -> Result<Lambda, String> {
+    let (tag, args) = s.tagged()?;
+    let rc = |i : usize| -> Result<Rc<Lambda>, String> { Ok (Rc::new(decode_lambda(&args[i])?)) };
+    match tag {
+        "int" => {
+            arity(tag, args, 2)?;
+            Ok (Lambda::IntLiteral { i : args[0].num()?, t : decode_type(&args[1])? })
+        },
+        "float" => {
+            arity(tag, args, 2)?;
+            Ok (Lambda::FloatLiteral { f : args[0].num()?, t : decode_type(&args[1])? })
+        },
+        "str" => {
+            match args.split_first() {
+                Some ((t, rest)) => Ok (Lambda::StrLiteral {
+                    s : String::from_utf8(rest.iter().map(|b| b.num()).collect::<Result<_, _>>()?)
+                        .map_err(|_| "malformed string".to_string())?,
+                    t : decode_type(t)? }),
+                None => Err ("str is missing its type".to_string())
+            }
+        },
+        "true" => {
+            arity(tag, args, 1)?;
+            Ok (Lambda::True { t : decode_type(&args[0])? })
+        },
+        "false" => {
+            arity(tag, args, 1)?;
+            Ok (Lambda::False { t : decode_type(&args[0])? })
+        },
+        "var" => {
+            arity(tag, args, 2)?;
+            Ok (Lambda::Var { v : args[0].string()?, t : decode_type(&args[1])? })
+        },
+        "tuple" => {
+            match args.split_first() {
+                Some ((t, rest)) => Ok (Lambda::Tuple {
+                    tup : rest.iter().map(decode_lambda).collect::<Result<_, _>>()?,
+                    t : decode_type(t)? }),
+                None => Err ("tuple is missing its type".to_string())
+            }
+        },
+        "index" => {
+            arity(tag, args, 3)?;
+            Ok (Lambda::Index { i : args[0].num()?, e : rc(1)?, t : decode_type(&args[2])? })
+        },
+        "abs" => {
+            arity(tag, args, 3)?;
+            Ok (Lambda::Abs { x : args[0].string()?, e : rc(1)?, t : decode_type(&args[2])? })
+        },
+        "app" => {
+            arity(tag, args, 3)?;
+            Ok (Lambda::App { lhs : rc(0)?, rhs : rc(1)?, t : decode_type(&args[2])? })
+        },
+        "if" => {
+            arity(tag, args, 4)?;
+            Ok (Lambda::IfExpr { c : rc(0)?, e1 : rc(1)?, e2 : rc(2)?, t : decode_type(&args[3])? })
+        },
+        "bin" => {
+            arity(tag, args, 4)?;
+            Ok (Lambda::BinExpr { b : decode_binop(&args[0])?, l : rc(1)?, r : rc(2)?, t : decode_type(&args[3])? })
+        },
+        "call" => {
+            if args.len() < 2 {
+                return Err ("call is missing its function or type".to_string());
+            }
+            Ok (Lambda::Call {
+                f : args[0].string()?,
+                args : args[2..].iter().map(decode_lambda).collect::<Result<_, _>>()?,
+                t : decode_type(&args[1])? })
+        },
+        "list" => {
+            match args.split_first() {
+                Some ((t, rest)) => Ok (Lambda::List {
+                    elems : rest.iter().map(decode_lambda).collect::<Result<_, _>>()?,
+                    t : decode_type(t)? }),
+                None => Err ("list is missing its type".to_string())
+            }
+        },
+        "con" => {
+            if args.len() < 2 {
+                return Err ("constructor is missing its name or type".to_string());
+            }
+            Ok (Lambda::Construct {
+                c : args[0].string()?,
+                args : args[2..].iter().map(decode_lambda).collect::<Result<_, _>>()?,
+                t : decode_type(&args[1])? })
+        },
+        "lmatch" => {
+            if args.len() < 2 {
+                return Err ("match is missing its scrutinee or type".to_string());
+            }
+            Ok (Lambda::Match {
+                e : rc(0)?,
+                arms : args[2..].iter().map(|a| match a {
+                    Sexp::List (c) if c.len() == 2 => Ok ((decode_pattern(&c[0])?, decode_lambda(&c[1])?)),
+                    _ => Err (format!("malformed case {}", a))
+                }).collect::<Result<_, String>>()?,
+                t : decode_type(&args[1])? })
+        },
+        "letrec" => {
+            arity(tag, args, 5)?;
+            Ok (Lambda::LetRec { f : args[0].string()?, x : args[1].string()?, e1 : rc(2)?, e2 : rc(3)?, t : decode_type(&args[4])? })
+        },
+        "closure" => {
+            arity(tag, args, 4)?;
+            Ok (Lambda::Closure { f : None, x : args[0].string()?, e : rc(1)?, env : decode_env(&args[2])?, t : decode_type(&args[3])? })
+        },
+        "recclosure" => {
+            arity(tag, args, 5)?;
+            Ok (Lambda::Closure { f : Some (args[0].string()?), x : args[1].string()?, e : rc(2)?,
+                env : decode_env(&args[3])?, t : decode_type(&args[4])? })
+        },
+        _ => Err (format!("unknown expression {}", tag))
+    }
+}
+
+pub fn encode_act(a : &ast::Act) -> Sexp {
+    match a {
+        ast::Act::Input (c) => list("in", vec![atom(c)]),
+        ast::Act::Output (c) => list("out", vec![atom(c)])
+    }
+}
+
+pub fn decode_act(s : &Sexp) -> Result<ast::Act, String> {
+    let (tag, args) = s.tagged()?;
+    arity(tag, args, 1)?;
+    match tag {
+        "in" => Ok (ast::Act::Input (args[0].string()?)),
+        "out" => Ok (ast::Act::Output (args[0].string()?)),
+        _ => Err (format!("unknown action {}", tag))
+    }
+}
+
+fn encode_branch((a, p) : &(ast::Act, Rc<ast::Process>)) -> Sexp {
+    Sexp::List (vec![encode_act(a), encode_process(p)])
+}
+
+fn decode_branch(s : &Sexp) -> Result<(ast::Act, Rc<ast::Process>), String> {
+    match s {
+        Sexp::List (l) if l.len() == 2 => Ok ((decode_act(&l[0])?, Rc::new(decode_process(&l[1])?))),
+        _ => Err (format!("malformed branch {}", s))
+    }
+}
+
+pub fn encode_process(p : &ast::Process) -> Sexp {
+    match p {
+        ast::Process::Restriction (c, r, p) => list("restr", vec![atom(c), atom(r), encode_process(p)]),
+        ast::Process::LetVal (pat, l, p) => list("letval", vec![encode_pattern(pat), encode_lambda(l), encode_process(p)]),
+        ast::Process::Parallel (p1, p2) => list("par", vec![encode_process(p1), encode_process(p2)]),
+        ast::Process::Summation (apvec) => list("sum", apvec.iter().map(encode_branch).collect()),
+        ast::Process::Instance (name, params) => {
+            let mut v = vec![atom(name)];
+            v.extend(params.iter().map(encode_lambda));
+            list("inst", v)
+        },
+        ast::Process::Repetition (i, p) => list("rep", vec![atom(i), encode_process(p)]),
+        ast::Process::Replication (a, p) => list("repl", vec![encode_act(a), encode_process(p)]),
+        ast::Process::Match (l, arms) => {
+            let mut v = vec![encode_lambda(l)];
+            v.extend(arms.iter().map(|(pat, p)| Sexp::List (vec![encode_pattern(pat), encode_process(p)])));
+            list("match", v)
+        },
+        ast::Process::Termination => atom("end")
+    }
+}
+
+pub fn decode_process(s : &Sexp) -> Result<ast::Process, String> {
+    let (tag, args) = s.tagged()?;
+    let rc = |i : usize| -> Result<Rc<ast::Process>, String> { Ok (Rc::new(decode_process(&args[i])?)) };
+    match tag {
+        "restr" => {
+            arity(tag, args, 3)?;
+            Ok (ast::Process::Restriction (args[0].string()?, args[1].num()?, rc(2)?))
+        },
+        "letval" => {
+            arity(tag, args, 3)?;
+            Ok (ast::Process::LetVal (decode_pattern(&args[0])?, decode_lambda(&args[1])?, rc(2)?))
+        },
+        "par" => {
+            arity(tag, args, 2)?;
+            Ok (ast::Process::Parallel (rc(0)?, rc(1)?))
+        },
+        "sum" => Ok (ast::Process::Summation (Rc::new(args.iter().map(decode_branch).collect::<Result<_, _>>()?))),
+        "inst" => {
+            match args.split_first() {
+                Some ((name, params)) => Ok (ast::Process::Instance (
+                    name.string()?,
+                    params.iter().map(decode_lambda).collect::<Result<_, _>>()?)),
+                None => Err ("instance is missing its name".to_string())
+            }
+        },
+        "rep" => {
+            arity(tag, args, 2)?;
+            Ok (ast::Process::Repetition (args[0].num()?, rc(1)?))
+        },
+        "repl" => {
+            arity(tag, args, 2)?;
+            Ok (ast::Process::Replication (decode_act(&args[0])?, rc(1)?))
+        },
+        "match" => {
+            match args.split_first() {
+                Some ((l, arms)) => Ok (ast::Process::Match (decode_lambda(l)?, arms.iter().map(|a| match a {
+                    Sexp::List (c) if c.len() == 2 => Ok ((decode_pattern(&c[0])?, Rc::new(decode_process(&c[1])?))),
+                    _ => Err (format!("malformed case {}", a))
+                }).collect::<Result<_, String>>()?)),
+                None => Err ("match is missing its scrutinee".to_string())
+            }
+        },
+        "end" => Ok (ast::Process::Termination),
+        _ => Err (format!("unknown process {}", tag))
+    }
+}
+
+pub fn encode_summ(s : &machineterm::Summ) -> Sexp {
+    let name = match s.0 {
+        Some ((ref n, ref params)) => {
+            let mut v = vec![atom(n)];
+            v.extend(params.iter().map(encode_lambda));
+            Sexp::List (v)
+        },
+        None => Sexp::List (vec![])
+    };
+    let mut v = vec![name, encode_env(&s.2)];
+    v.extend(s.1.iter().map(encode_branch));
+    list("summ", v)
+}
+
+pub fn decode_summ(s : &Sexp) -> Result<machineterm::Summ, String> {
+    let (tag, args) = s.tagged()?;
+    if tag != "summ" || args.len() < 2 {
+        return Err (format!("malformed summation {}", s));
+    }
+    let name = match &args[0] {
+        Sexp::List (l) if l.is_empty() => None,
+        Sexp::List (l) => Some ((l[0].string()?, l[1..].iter().map(decode_lambda).collect::<Result<_, _>>()?)),
+        _ => return Err (format!("malformed summation {}", s))
+    };
+    let branches = args[2..].iter().map(decode_branch).collect::<Result<_, _>>()?;
+    Ok (machineterm::Summ (name, Rc::new(branches), decode_env(&args[1])?))
+}
+
+pub fn encode_term(mt : &machineterm::MachineTerm) -> Sexp {
+    match mt {
+        machineterm::MachineTerm::TopRestriction (c, r, t) => list("restr", vec![atom(c), atom(r), encode_term(t)]),
+        machineterm::MachineTerm::SummList (sl) => list("summs", sl.iter().map(|x| encode_summ(x)).collect())
+    }
+}
+
+pub fn decode_term(s : &Sexp) -> Result<machineterm::MachineTerm, String> {
+    let (tag, args) = s.tagged()?;
+    match tag {
+        "restr" => {
+            arity(tag, args, 3)?;
+            Ok (machineterm::MachineTerm::TopRestriction (args[0].string()?, args[1].num()?, Rc::new(decode_term(&args[2])?)))
+        },
+        "summs" => Ok (machineterm::MachineTerm::SummList (
+            args.iter().map(|x| decode_summ(x).map(Rc::new)).collect::<Result<_, _>>()?)),
+        _ => Err (format!("unknown machine term {}", tag))
+    }
+}
+
+pub fn entry(tag : &str, rest : Vec<Sexp>) -> Sexp {
+    list(tag, rest)
+}
+
+pub fn value<T : ToString>(x : T) -> Sexp {
+    atom(x)
+}
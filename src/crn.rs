@@ -0,0 +1,344 @@
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::rc::Rc;
+use std::borrow::Borrow;
+
+use super::syntax;
+use super::ast;
+use super::lambda::*;
+use super::machineterm;
+use super::store;
+
+#[derive(Clone, Debug)]
+pub struct Species {
+    pub name : String,
+    pub def : String,
+    pub branches : Rc<ast::Summ>,
+    pub env : Env,
+    pub next : Vec<Vec<usize>>
+}
+
+#[derive(Clone, Debug)]
+pub struct Reaction {
+    pub chan : String,
+    pub rate : f64,
+    pub reactants : Vec<(usize, usize)>,
+    pub products : Vec<(usize, usize)>
+}
+
+#[derive(Clone, Debug)]
+pub struct Crn {
+    pub species : Vec<Species>,
+    pub reactions : Vec<Reaction>,
+    pub initial : Vec<usize>,
+    pub defs : Vec<String>
+}
+
+const MAX_UNFOLD : usize = 256;
+
+struct Explorer<'a> {
+    s : &'a store::Store,
+    species : Vec<Species>,
+    index : BTreeMap<String, usize>,
+    limit : usize
+}
+
+impl<'a> Explorer<'a> {
+    fn species(&mut self, name : &str, params : &[Lambda], branches : Rc<ast::Summ>, env : Env) -> Result<usize, String> {
+        let label = machineterm::label(name, params);
+        if let Some (i) = self.index.get(&label) {
+            return Ok (*i);
+        }
+        if self.species.len() >= self.limit {
+            return Err (format!("the model generates more than {} species (last reached {}); \
+                it is probably unbounded", self.limit, label));
+        }
+        self.species.push(Species { name : label.clone(), def : name.to_string(), branches : branches, env : env, next : Vec::new() });
+        self.index.insert(label, self.species.len() - 1);
+        Ok (self.species.len() - 1)
+    }
+    fn products(&mut self, p : &ast::Process, env : &Env, depth : usize, out : &mut Vec<usize>) -> Result<(), String> {
+        if depth > MAX_UNFOLD {
+            return Err (format!("process definitions unfold more than {} times without reaching an action; \
+                the model is probably unbounded", MAX_UNFOLD));
+        }
+        match p {
+            ast::Process::Termination => Ok (()),
+            ast::Process::Parallel (p1, p2) => {
+                self.products(p1, env, depth, out)?;
+                self.products(p2, env, depth, out)
+            },
+            ast::Process::Repetition (n, q) => {
+                for _ in 0..*n {
+                    self.products(q, env, depth, out)?;
+                }
+                Ok (())
+            },
+            ast::Process::LetVal (pat, l, q) => self.products(q, &env.extend(destructure(pat, &l.eval_in(env)?)?), depth, out),
+            ast::Process::Match (l, arms) => {
+                let v = l.eval_in(env)?;
+                match ast::Process::select(arms, &v, env) {
+                    Some ((q, env)) => self.products(q, &env, depth, out),
+                    None => Err (format!("no case of the match accepts {}", v))
+                }
+            },
+            ast::Process::Instance (name, params) => {
+                let params : Vec<Lambda> = params.iter().map(|l| l.eval_in(env)).collect::<Result<_, _>>()?;
+                let (body, env) = self.s.instantiate(name, &params)?;
+                match &*body {
+                    ast::Process::Summation (apvec) => {
+                        let i = self.species(name, &params, apvec.clone(), env)?;
+                        out.push(i);
+                        Ok (())
+                    },
+                    // the simulator keeps counting an instance whose body is end, so it stays in the
+                    // network as an inert species; otherwise its count would be lost from every export
+                    ast::Process::Termination => {
+                        let i = self.species(name, &params, Rc::new(Vec::new()), env)?;
+                        out.push(i);
+                        Ok (())
+                    },
+                    b => self.products(b, &env, depth + 1, out)
+                }
+            },
+            ast::Process::Summation (_) =>
+                Err ("an anonymous action prefix is not a species; give every intermediate state its own definition".to_string()),
+            ast::Process::Restriction (c, _, _) =>
+                Err (format!("restricted channel {} creates complexes, which have no finite reaction network", c)),
+            ast::Process::Replication (_, _) =>
+                Err ("replication is not supported in reaction networks".to_string())
+        }
+    }
+}
+
+fn multiset(v : &[usize]) -> Vec<(usize, usize)> {
+    let mut m : BTreeMap<usize, usize> = BTreeMap::new();
+    for s in v.iter() {
+        *m.entry(*s).or_insert(0) += 1;
+    }
+    m.into_iter().collect()
+}
+
+impl Crn {
+    pub fn from_program(p : &syntax::Program, limit : usize) -> Result<Crn, String> {
+        let mut s = store::Store::new();
+        let mut runs = Vec::new();
+        match *p {
+            syntax::Program::Prog (ref decs) => {
+                for d in decs.iter() {
+                    match (*d).borrow() {
+                        syntax::Declaration::NewChannel (ref c, r) => s.add_channel(c, *r),
+                        syntax::Declaration::Run (p) => runs.push(ast::Process::from((*p).borrow())),
+                        syntax::Declaration::Def (n, params, ref d) => {
+                            s.defs.insert(n.to_string(), (params.to_vec(), Rc::new(ast::Process::from((*d).borrow()))));
+                        },
+                        syntax::Declaration::Val (_, _) => return Err ("top level val declarations are not supported".to_string()),
+                        // the network has a single fixed initial state and no clock, so it cannot
+                        // agree with a simulation that intervenes partway through
+                        syntax::Declaration::Directive (_, _) | syntax::Declaration::Trigger (_) =>
+                            return Err ("directives and triggers have no counterpart in a reaction network".to_string()),
+                        syntax::Declaration::Type (_, _) => ()
+                    }
+                }
+            }
+        }
+        Crn::explore(&s, &runs, limit)
+    }
+    pub fn explore(s : &store::Store, runs : &[ast::Process], limit : usize) -> Result<Crn, String> {
+        let mut ex = Explorer { s : s, species : Vec::new(), index : BTreeMap::new(), limit : limit };
+        let mut init = Vec::new();
+        for p in runs.iter() {
+            ex.products(p, &Env::new(), 0, &mut init)?;
+        }
+        let mut i = 0;
+        while i < ex.species.len() {
+            let branches = ex.species[i].branches.clone();
+            let env = ex.species[i].env.clone();
+            let mut next = Vec::new();
+            for (_, p) in branches.iter() {
+                let mut out = Vec::new();
+                ex.products(p, &env, 0, &mut out)
+                    .map_err(|e| format!("in {}: {}", ex.species[i].name, e))?;
+                next.push(out);
+            }
+            ex.species[i].next = next;
+            i += 1;
+        }
+        let species = ex.species;
+
+        let mut inputs : BTreeMap<&str, Vec<(usize, usize)>> = BTreeMap::new();
+        let mut outputs : BTreeMap<&str, Vec<(usize, usize)>> = BTreeMap::new();
+        for (i, sp) in species.iter().enumerate() {
+            for (j, (a, _)) in sp.branches.iter().enumerate() {
+                match a {
+                    ast::Act::Input (c) => inputs.entry(c).or_insert_with(Vec::new).push((i, j)),
+                    ast::Act::Output (c) => outputs.entry(c).or_insert_with(Vec::new).push((i, j))
+                }
+            }
+        }
+        let mut reactions = Vec::new();
+        for (c, ins) in inputs.iter() {
+            let outs = match outputs.get(c) {
+                Some (outs) => outs,
+                None => continue
+            };
+            let rate = match s.chans.get(*c) {
+                Some (r) => r.rate,
+                None => return Err (format!("channel {} is used but never declared", c))
+            };
+            for (si, bi) in ins.iter() {
+                for (so, bo) in outs.iter() {
+                    let mut prods = species[*si].next[*bi].clone();
+                    prods.extend(species[*so].next[*bo].iter());
+                    reactions.push(Reaction {
+                        chan : c.to_string(),
+                        rate : rate,
+                        reactants : multiset(&[*si, *so]),
+                        products : multiset(&prods)
+                    });
+                }
+            }
+        }
+        let mut initial = vec![0; species.len()];
+        for i in init.iter() {
+            initial[*i] += 1;
+        }
+        Ok (Crn { species : species, reactions : reactions, initial : initial, defs : s.observables() })
+    }
+    pub fn stoichiometry(&self, r : &Reaction) -> Vec<i64> {
+        let mut v = vec![0; self.species.len()];
+        for (s, n) in r.reactants.iter() {
+            v[*s] -= *n as i64;
+        }
+        for (s, n) in r.products.iter() {
+            v[*s] += *n as i64;
+        }
+        v
+    }
+    pub fn propensity(&self, r : &Reaction, counts : &[usize]) -> f64 {
+        let x : Vec<f64> = counts.iter().map(|n| *n as f64).collect();
+        self.flux(r, &x)
+    }
+    pub fn flux(&self, r : &Reaction, x : &[f64]) -> f64 {
+        r.reactants.iter().fold(r.rate, |acc, (s, n)| {
+            (0..*n).fold(acc, |a, k| a * (x[*s] - k as f64).max(0.0))
+        })
+    }
+    pub fn observables(&self) -> Vec<String> {
+        self.defs.clone()
+    }
+    pub fn observe(&self, counts : &[usize]) -> BTreeMap<String, usize> {
+        let mut m : BTreeMap<String, usize> = self.defs.iter().map(|d| (d.clone(), 0)).collect();
+        for (i, s) in self.species.iter().enumerate() {
+            *m.entry(s.def.clone()).or_insert(0) += counts[i];
+        }
+        m
+    }
+    pub fn mean(&self, x : &[f64]) -> BTreeMap<String, f64> {
+        let mut m : BTreeMap<String, f64> = self.defs.iter().map(|d| (d.clone(), 0.0)).collect();
+        for (i, s) in self.species.iter().enumerate() {
+            *m.entry(s.def.clone()).or_insert(0.0) += x[i];
+        }
+        m
+    }
+}
+
+pub fn sid(s : &str) -> String {
+    let mut id : String = s.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    while id.ends_with('_') {
+        id.pop();
+    }
+    match id.chars().next() {
+        Some (c) if c.is_ascii_alphabetic() || c == '_' => id,
+        _ => format!("_{}", id)
+    }
+}
+
+pub fn unique(base : String, used : &mut BTreeSet<String>) -> String {
+    let mut id = base.clone();
+    let mut n = 1;
+    while used.contains(&id) {
+        id = format!("{}_{}", base, n);
+        n += 1;
+    }
+    used.insert(id.clone());
+    id
+}
+
+impl fmt::Display for Crn {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        fn side(crn : &Crn, v : &[(usize, usize)]) -> String {
+            if v.is_empty() {
+                return "0".to_string();
+            }
+            let terms : Vec<String> = v.iter().map(|(s, n)| {
+                if *n == 1 { crn.species[*s].name.clone() } else { format!("{} {}", n, crn.species[*s].name) }
+            }).collect();
+            terms.join(" + ")
+        }
+        writeln!(f, "species")?;
+        for (i, s) in self.species.iter().enumerate() {
+            writeln!(f, "  {} = {}", s.name, self.initial[i])?;
+        }
+        writeln!(f, "reactions")?;
+        for r in self.reactions.iter() {
+            writeln!(f, "  {} -> {} @ {} [{}]", side(self, &r.reactants), side(self, &r.products), r.rate, r.chan)?;
+        }
+        Ok (())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // n molecules that switch from A to B at rate 2 and back at rate 1, through a single
+    // catalyst E so that each switch is a reaction of its own; a molecule that starts as A
+    // is a B at time t with probability 2/3 (1 - exp(-3t))
+    pub fn two_state(n : usize) -> Crn {
+        let prog = crate::parse(&format!("new a@2.0\n\
+            new b@1.0\n\
+            let A () = !a; B()\n\
+            let B () = !b; A()\n\
+            let E () = do ?a; E() or ?b; E()\n\
+            run ({} of A() | E())", n)).unwrap();
+        Crn::from_program(&prog, 100).unwrap()
+    }
+
+    pub fn switched(t : f64) -> f64 {
+        2.0 / 3.0 * (1.0 - (-3.0 * t).exp())
+    }
+
+    #[test]
+    fn definitions_that_end_are_inert_species() {
+        let prog = crate::parse("new bind@1.0\n\
+            let A () = !bind; Done()\n\
+            let B () = ?bind; end\n\
+            let Done () = end\n\
+            run (3 of A() | 2 of B() | Done())").unwrap();
+        let c = Crn::from_program(&prog, 100).unwrap();
+        let names : Vec<&str> = c.species.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["A()", "B()", "Done()"]);
+        assert_eq!(c.initial, vec![3, 2, 1]);
+        assert!(c.species[2].branches.is_empty());
+        assert_eq!(c.reactions.len(), 1);
+        assert_eq!(c.reactions[0].reactants, vec![(0, 1), (1, 1)]);
+        assert_eq!(c.reactions[0].products, vec![(2, 1)]);
+        assert_eq!(c.observe(&[0, 0, 4])["Done"], 4);
+    }
+
+    #[test]
+    fn directives_and_triggers_are_rejected() {
+        for d in &["directive at 1.0 run A()", "directive at 1.0 kill A(_)", "when A() < 1 run A()"] {
+            let prog = crate::parse(&format!("new a@1.0
+let A () = !a; A()
+let B () = ?a; B()
+run (A() | B())
+{}", d)).unwrap();
+            assert_eq!(Crn::from_program(&prog, 100).unwrap_err(), "directives and triggers have no counterpart in a reaction network");
+        }
+    }
+}
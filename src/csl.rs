@@ -0,0 +1,114 @@
+
+use std::fmt;
+
+use super::syntax;
+use super::lambda::*;
+use super::ctmc;
+
+const EPSILON : f64 = 1e-10;
+const MAX_ITER : usize = 1000000;
+
+#[derive(Clone, Debug)]
+pub enum Path {
+    Eventually (Option<f64>, syntax::Predicate)
+}
+
+#[derive(Clone, Debug)]
+pub enum Property {
+    Prob (BinOp, f64, Path),
+    Steady (BinOp, f64, syntax::Predicate)
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Path::Eventually (Some (t), phi) => write!(f, "F<={} {}", float_literal(*t), phi),
+            Path::Eventually (None, phi) => write!(f, "F {}", phi)
+        }
+    }
+}
+
+impl fmt::Display for Property {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Property::Prob (o, p, path) => write!(f, "P{}{} [ {} ]", o, p, path),
+            Property::Steady (o, p, phi) => write!(f, "S{}{} [ {} ]", o, p, phi)
+        }
+    }
+}
+
+fn compare(o : &BinOp, x : f64, bound : f64) -> Result<bool, String> {
+    match o {
+        BinOp::Less => Ok (x < bound),
+        BinOp::Greater => Ok (x > bound),
+        BinOp::LEq => Ok (x <= bound),
+        BinOp::GEq => Ok (x >= bound),
+        _ => Err (format!("{} is not a probability bound; use <, <=, > or >=", o))
+    }
+}
+
+fn satisfying(m : &ctmc::Ctmc, phi : &syntax::Predicate) -> Vec<bool> {
+    m.states.iter().map(|s| {
+        let counts = m.crn.observe(s);
+        phi.eval(&|n| *counts.get(n).unwrap_or(&0) as f64)
+    }).collect()
+}
+
+pub fn probability(m : &ctmc::Ctmc, prop : &Property) -> Result<f64, String> {
+    match prop {
+        Property::Prob (_, _, Path::Eventually (Some (t), phi)) => {
+            let target = satisfying(m, phi);
+            let p = m.transient_until(&m.initial(), *t, EPSILON, &target);
+            Ok (p.iter().zip(target.iter()).filter(|(_, b)| **b).map(|(x, _)| x).sum())
+        },
+        Property::Prob (_, _, Path::Eventually (None, phi)) => {
+            let target = satisfying(m, phi);
+            let states : Vec<usize> = (0..m.len()).filter(|s| target[*s]).collect();
+            m.reach_probability(&states, &target, EPSILON, MAX_ITER)
+        },
+        Property::Steady (_, _, phi) => {
+            let target = satisfying(m, phi);
+            let ss = m.steady_state(EPSILON, MAX_ITER)?;
+            Ok (ss.dist.iter().zip(target.iter()).filter(|(_, b)| **b).map(|(x, _)| x).sum())
+        }
+    }
+}
+
+pub fn check(m : &ctmc::Ctmc, prop : &Property) -> Result<(bool, f64), String> {
+    let x = probability(m, prop)?;
+    let holds = match prop {
+        Property::Prob (o, p, _) | Property::Steady (o, p, _) => compare(o, x, *p)?
+    };
+    Ok ((holds, x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crn::tests::{two_state, switched};
+
+    #[test]
+    fn probabilities_match_the_exact_values() {
+        let m = ctmc::Ctmc::explore(two_state(1), 100).unwrap();
+        // the first switch happens at rate 2, and nothing can undo having reached B
+        let p = crate::property("P>=0.5 [F<=0.25 B() > 0]").unwrap();
+        let exact = 1.0 - (-0.5f64).exp();
+        let (holds, x) = check(&m, &p).unwrap();
+        assert!((x - exact).abs() < 1e-9, "{} against {}", x, exact);
+        assert!(!holds);
+        // with B no longer absorbing, the time bound matters: F<=t is not the chance of being in B at t
+        assert!(x > switched(0.25));
+        let (holds, x) = check(&m, &crate::property("P>0.99 [F B() > 0]").unwrap()).unwrap();
+        assert!(holds && (x - 1.0).abs() < 1e-9);
+        let (holds, x) = check(&m, &crate::property("S<0.7 [B() > 0]").unwrap()).unwrap();
+        assert!(holds && (x - 2.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn malformed_properties_are_rejected() {
+        assert!(crate::property("P>0.5 [F<=1 B() > 0]").is_ok());
+        assert!(crate::property("P>0.5 [F<=1 B() > 0] garbage").is_err());
+        assert!(crate::property("P>0.5 [F<=1 B() > 0] #").is_err());
+        assert!(crate::property("P>0.5 [F<=1 B() > 0").is_err());
+    }
+}
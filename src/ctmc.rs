@@ -0,0 +1,396 @@
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::crn;
+
+const MAX_POISSON_RATE : f64 = 400.0;
+
+fn successors(c : &crn::Crn, stoich : &[Vec<i64>], s : &[usize]) -> Vec<(Vec<usize>, f64)> {
+    let mut out = Vec::new();
+    for (r, d) in c.reactions.iter().zip(stoich.iter()) {
+        let a = c.propensity(r, s);
+        if a <= 0.0 {
+            continue;
+        }
+        let next : Vec<usize> = s.iter().zip(d.iter()).map(|(x, dx)| (*x as i64 + dx) as usize).collect();
+        if next.as_slice() != s {
+            out.push((next, a));
+        }
+    }
+    out
+}
+
+#[derive(Debug)]
+pub struct SteadyState {
+    pub bsccs : Vec<(f64, Vec<usize>)>,
+    pub dist : Vec<f64>
+}
+
+#[derive(Debug)]
+pub struct Ctmc {
+    pub crn : crn::Crn,
+    pub states : Vec<Vec<usize>>,
+    pub index : HashMap<Vec<usize>, usize>,
+    pub rows : Vec<Vec<(usize, f64)>>,
+    pub exit : Vec<f64>
+}
+
+impl Ctmc {
+    pub fn explore(c : crn::Crn, limit : usize) -> Result<Ctmc, String> {
+        let stoich : Vec<Vec<i64>> = c.reactions.iter().map(|r| c.stoichiometry(r)).collect();
+        let mut states = vec![c.initial.clone()];
+        let mut index = HashMap::new();
+        index.insert(c.initial.clone(), 0);
+        let mut rows = Vec::new();
+        let mut exit = Vec::new();
+        let mut i = 0;
+        while i < states.len() {
+            let mut row : BTreeMap<usize, f64> = BTreeMap::new();
+            let mut out = 0.0;
+            for (next, a) in successors(&c, &stoich, &states[i]) {
+                let j = match index.get(&next) {
+                    Some (j) => *j,
+                    None => {
+                        if states.len() >= limit {
+                            return Err (format!("the model has more than {} reachable states", limit));
+                        }
+                        states.push(next.clone());
+                        index.insert(next, states.len() - 1);
+                        states.len() - 1
+                    }
+                };
+                *row.entry(j).or_insert(0.0) += a;
+                out += a;
+            }
+            rows.push(row.into_iter().collect());
+            exit.push(out);
+            i += 1;
+        }
+        Ok (Ctmc { crn : c, states : states, index : index, rows : rows, exit : exit })
+    }
+    pub fn project(c : crn::Crn, states : Vec<Vec<usize>>) -> Ctmc {
+        let stoich : Vec<Vec<i64>> = c.reactions.iter().map(|r| c.stoichiometry(r)).collect();
+        let index : HashMap<Vec<usize>, usize> = states.iter().cloned().enumerate().map(|(i, s)| (s, i)).collect();
+        let mut rows = Vec::new();
+        let mut exit = Vec::new();
+        for s in states.iter() {
+            let mut row : BTreeMap<usize, f64> = BTreeMap::new();
+            let mut out = 0.0;
+            for (next, a) in successors(&c, &stoich, s) {
+                if let Some (j) = index.get(&next) {
+                    *row.entry(*j).or_insert(0.0) += a;
+                }
+                out += a;
+            }
+            rows.push(row.into_iter().collect());
+            exit.push(out);
+        }
+        Ctmc { crn : c, states : states, index : index, rows : rows, exit : exit }
+    }
+    pub fn frontier(&self) -> Vec<Vec<usize>> {
+        let stoich : Vec<Vec<i64>> = self.crn.reactions.iter().map(|r| self.crn.stoichiometry(r)).collect();
+        let mut seen = HashSet::new();
+        let mut out = Vec::new();
+        for s in self.states.iter() {
+            for (next, _) in successors(&self.crn, &stoich, s) {
+                if !self.index.contains_key(&next) && seen.insert(next.clone()) {
+                    out.push(next);
+                }
+            }
+        }
+        out
+    }
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+    pub fn initial(&self) -> Vec<f64> {
+        let mut p = vec![0.0; self.len()];
+        p[0] = 1.0;
+        p
+    }
+    fn step(&self, v : &[f64], q : f64, stop : &[bool]) -> Vec<f64> {
+        let mut w = vec![0.0; v.len()];
+        for (i, x) in v.iter().enumerate() {
+            if *x == 0.0 {
+                continue;
+            }
+            if stop[i] {
+                w[i] += x;
+                continue;
+            }
+            w[i] += x * (1.0 - self.exit[i] / q);
+            for (j, a) in self.rows[i].iter() {
+                w[*j] += x * a / q;
+            }
+        }
+        w
+    }
+    pub fn transient(&self, p : &[f64], t : f64, eps : f64) -> Vec<f64> {
+        self.transient_until(p, t, eps, &vec![false; self.len()])
+    }
+    pub fn transient_until(&self, p : &[f64], t : f64, eps : f64, stop : &[bool]) -> Vec<f64> {
+        let q = self.exit.iter().cloned().fold(0.0, f64::max) * 1.02;
+        if q == 0.0 || t <= 0.0 {
+            return p.to_vec();
+        }
+        let chunks = ((q * t) / MAX_POISSON_RATE).ceil().max(1.0) as usize;
+        let lambda = q * t / chunks as f64;
+        let mut cur = p.to_vec();
+        for _ in 0..chunks {
+            let mut w = (-lambda).exp();
+            let mut acc = w;
+            let mut v = cur.clone();
+            let mut res : Vec<f64> = v.iter().map(|x| x * w).collect();
+            let mut n = 0;
+            while acc < 1.0 - eps {
+                n += 1;
+                v = self.step(&v, q, stop);
+                w *= lambda / n as f64;
+                acc += w;
+                for (r, x) in res.iter_mut().zip(v.iter()) {
+                    *r += x * w;
+                }
+                if w == 0.0 && n as f64 > lambda {
+                    break;
+                }
+            }
+            cur = res;
+        }
+        cur
+    }
+    pub fn sccs(&self) -> Vec<Vec<usize>> {
+        let n = self.len();
+        let mut index = vec![usize::max_value(); n];
+        let mut low = vec![0; n];
+        let mut on_stack = vec![false; n];
+        let mut stack = Vec::new();
+        let mut sccs = Vec::new();
+        let mut counter = 0;
+        for root in 0..n {
+            if index[root] != usize::max_value() {
+                continue;
+            }
+            let mut work : Vec<(usize, usize)> = vec![(root, 0)];
+            index[root] = counter;
+            low[root] = counter;
+            counter += 1;
+            stack.push(root);
+            on_stack[root] = true;
+            while let Some ((v, k)) = work.pop() {
+                if k < self.rows[v].len() {
+                    work.push((v, k + 1));
+                    let w = self.rows[v][k].0;
+                    if index[w] == usize::max_value() {
+                        index[w] = counter;
+                        low[w] = counter;
+                        counter += 1;
+                        stack.push(w);
+                        on_stack[w] = true;
+                        work.push((w, 0));
+                    }
+                    else if on_stack[w] {
+                        low[v] = low[v].min(index[w]);
+                    }
+                    continue;
+                }
+                if low[v] == index[v] {
+                    let mut scc = Vec::new();
+                    loop {
+                        let w = stack.pop().unwrap();
+                        on_stack[w] = false;
+                        scc.push(w);
+                        if w == v {
+                            break;
+                        }
+                    }
+                    sccs.push(scc);
+                }
+                if let Some ((u, _)) = work.last() {
+                    low[*u] = low[*u].min(low[v]);
+                }
+            }
+        }
+        sccs
+    }
+    pub fn bsccs(&self) -> Vec<Vec<usize>> {
+        let sccs = self.sccs();
+        let mut comp = vec![0; self.len()];
+        for (c, scc) in sccs.iter().enumerate() {
+            for s in scc.iter() {
+                comp[*s] = c;
+            }
+        }
+        sccs.into_iter().enumerate()
+            .filter(|(c, scc)| scc.iter().all(|s| self.rows[*s].iter().all(|(t, _)| comp[*t] == *c)))
+            .map(|(_, mut scc)| {
+                scc.sort();
+                scc
+            })
+            .collect()
+    }
+    fn incoming(&self) -> Vec<Vec<(usize, f64)>> {
+        let mut inc = vec![Vec::new(); self.len()];
+        for (i, row) in self.rows.iter().enumerate() {
+            for (j, a) in row.iter() {
+                inc[*j].push((i, *a));
+            }
+        }
+        inc
+    }
+    fn local_steady_state(&self, b : &[usize], inc : &[Vec<(usize, f64)>], tol : f64, max_iter : usize) -> Result<Vec<f64>, String> {
+        let mut pi = vec![0.0; self.len()];
+        for s in b.iter() {
+            pi[*s] = 1.0 / b.len() as f64;
+        }
+        if b.len() == 1 {
+            return Ok (pi);
+        }
+        for _ in 0..max_iter {
+            let mut delta : f64 = 0.0;
+            for s in b.iter() {
+                let x = inc[*s].iter().map(|(j, a)| pi[*j] * a).sum::<f64>() / self.exit[*s];
+                delta = delta.max((x - pi[*s]).abs());
+                pi[*s] = x;
+            }
+            let total : f64 = b.iter().map(|s| pi[*s]).sum();
+            for s in b.iter() {
+                pi[*s] /= total;
+            }
+            if delta < tol {
+                return Ok (pi);
+            }
+        }
+        Err (format!("the steady state did not converge within {} iterations", max_iter))
+    }
+    pub fn reach_probability(&self, target : &[usize], absorbing : &[bool], tol : f64, max_iter : usize) -> Result<f64, String> {
+        let mut x = vec![0.0; self.len()];
+        for s in target.iter() {
+            x[*s] = 1.0;
+        }
+        for _ in 0..max_iter {
+            let mut delta : f64 = 0.0;
+            for i in 0..self.len() {
+                if absorbing[i] || self.exit[i] == 0.0 {
+                    continue;
+                }
+                let y = self.rows[i].iter().map(|(j, a)| x[*j] * a).sum::<f64>() / self.exit[i];
+                delta = delta.max((y - x[i]).abs());
+                x[i] = y;
+            }
+            if delta < tol {
+                return Ok (x[0]);
+            }
+        }
+        Err (format!("the reachability probabilities did not converge within {} iterations", max_iter))
+    }
+    pub fn steady_state(&self, tol : f64, max_iter : usize) -> Result<SteadyState, String> {
+        let bsccs = self.bsccs();
+        let inc = self.incoming();
+        let mut absorbing = vec![false; self.len()];
+        for b in bsccs.iter() {
+            for s in b.iter() {
+                absorbing[*s] = true;
+            }
+        }
+        let mut dist = vec![0.0; self.len()];
+        let mut out = Vec::new();
+        for b in bsccs.into_iter() {
+            let w = if absorbing[0] {
+                if b.contains(&0) { 1.0 } else { 0.0 }
+            }
+            else {
+                self.reach_probability(&b, &absorbing, tol, max_iter)?
+            };
+            if w > 0.0 {
+                let pi = self.local_steady_state(&b, &inc, tol, max_iter)?;
+                for s in b.iter() {
+                    dist[*s] += w * pi[*s];
+                }
+            }
+            out.push((w, b));
+        }
+        Ok (SteadyState { bsccs : out, dist : dist })
+    }
+    pub fn expectations(&self, p : &[f64]) -> BTreeMap<String, f64> {
+        let mut m : BTreeMap<String, f64> = self.crn.observables().into_iter().map(|o| (o, 0.0)).collect();
+        for (s, x) in self.states.iter().zip(p.iter()) {
+            for (o, k) in self.crn.observe(s) {
+                *m.entry(o).or_insert(0.0) += x * k as f64;
+            }
+        }
+        m
+    }
+    pub fn marginals(&self, p : &[f64]) -> BTreeMap<String, BTreeMap<usize, f64>> {
+        let mut m : BTreeMap<String, BTreeMap<usize, f64>> = BTreeMap::new();
+        for o in self.crn.observables() {
+            m.insert(o, BTreeMap::new());
+        }
+        for (s, x) in self.states.iter().zip(p.iter()) {
+            for (o, k) in self.crn.observe(s) {
+                *m.entry(o).or_insert_with(BTreeMap::new).entry(k).or_insert(0.0) += x;
+            }
+        }
+        m
+    }
+}
+
+pub fn write_distributions<W : std::io::Write>(wtr : &mut csv::Writer<W>, t : f64, m : &BTreeMap<String, BTreeMap<usize, f64>>) {
+    for (o, dist) in m.iter() {
+        for (k, x) in dist.iter() {
+            wtr.write_record(&[t.to_string(), o.clone(), k.to_string(), x.to_string()]).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crn::tests::{two_state, switched};
+
+    #[test]
+    fn transient_distribution_by_uniformisation() {
+        let m = Ctmc::explore(two_state(3), 100).unwrap();
+        assert_eq!(m.len(), 4);
+        for t in &[0.1, 0.5, 2.0] {
+            let p = m.transient(&m.initial(), *t, 1e-12);
+            let q = switched(*t);
+            assert!((p.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+            assert!((m.expectations(&p)["B"] - 3.0 * q).abs() < 1e-9, "at time {}", t);
+            // each molecule switches on its own, so the number of B is binomial
+            let b = &m.marginals(&p)["B"];
+            for (k, choose) in [1.0, 3.0, 3.0, 1.0].iter().enumerate() {
+                let exact = choose * q.powi(k as i32) * (1.0 - q).powi(3 - k as i32);
+                assert!((b[&k] - exact).abs() < 1e-9, "P(B = {}) at time {}", k, t);
+            }
+        }
+    }
+
+    #[test]
+    fn steady_state_of_an_irreducible_chain() {
+        let m = Ctmc::explore(two_state(3), 100).unwrap();
+        let ss = m.steady_state(1e-12, 100000).unwrap();
+        assert_eq!(ss.bsccs.len(), 1);
+        assert_eq!(ss.bsccs[0].0, 1.0);
+        assert!((ss.dist.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!((m.expectations(&ss.dist)["B"] - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn steady_state_weighs_each_absorbing_component() {
+        // A() becomes B() or C() for good, at rates 3 and 1
+        let prog = crate::parse("new b@3.0\n\
+            new c@1.0\n\
+            let A () = do !b; B() or !c; C()\n\
+            let B () = end\n\
+            let C () = end\n\
+            let E () = do ?b; E() or ?c; E()\n\
+            run (A() | E())").unwrap();
+        let m = Ctmc::explore(crn::Crn::from_program(&prog, 100).unwrap(), 100).unwrap();
+        let ss = m.steady_state(1e-12, 100000).unwrap();
+        let mut weights : Vec<f64> = ss.bsccs.iter().map(|(w, _)| *w).collect();
+        weights.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(weights.len(), 2);
+        assert!((weights[0] - 0.25).abs() < 1e-9 && (weights[1] - 0.75).abs() < 1e-9, "{:?}", weights);
+        assert!((m.expectations(&ss.dist)["B"] - 0.75).abs() < 1e-9);
+    }
+}
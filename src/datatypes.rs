@@ -0,0 +1,268 @@
+
+use std::collections::BTreeMap;
+use std::borrow::Borrow;
+use std::iter;
+use std::rc::Rc;
+
+use super::syntax;
+use super::parser;
+use super::values::*;
+use super::lambda::*;
+
+const BASE_TYPES : [&str; 4] = ["int", "float", "bool", "list"];
+const WILD : &Pattern = &Pattern::Wildcard;
+
+pub struct Datatypes {
+    // each constructor with the type it builds and the number of fields it carries
+    ctors : BTreeMap<String, (String, usize)>,
+    types : BTreeMap<String, Vec<String>>
+}
+
+impl Datatypes {
+    pub fn new(decs : &[Rc<syntax::Declaration>]) -> Result<Datatypes, String> {
+        let mut d = Datatypes { ctors : BTreeMap::new(), types : BTreeMap::new() };
+        for dec in decs.iter() {
+            if let syntax::Declaration::Type (n, cs) = (*dec).borrow() {
+                if BASE_TYPES.contains(&n.as_str()) || d.types.contains_key(n) {
+                    return Err (format!("type {} is declared twice", n));
+                }
+                for (c, fields) in cs.iter() {
+                    if !is_constructor(c) {
+                        return Err (format!("constructor {} of type {} must start with a capital letter", c, n));
+                    }
+                    if let Some ((t, _)) = d.ctors.insert(c.clone(), (n.clone(), fields.len())) {
+                        return Err (format!("constructor {} belongs to both {} and {}", c, t, n));
+                    }
+                }
+                d.types.insert(n.clone(), cs.iter().map(|(c, _)| c.clone()).collect());
+            }
+        }
+        for dec in decs.iter() {
+            if let syntax::Declaration::Type (n, cs) = (*dec).borrow() {
+                for f in cs.iter().flat_map(|(_, fields)| fields.iter()) {
+                    if !BASE_TYPES.contains(&f.as_str()) && !d.types.contains_key(f) {
+                        return Err (format!("type {} refers to unknown type {}", n, f));
+                    }
+                }
+            }
+        }
+        Ok (d)
+    }
+    fn constructor(&self, c : &str, n : usize) -> Result<&str, String> {
+        match self.ctors.get(c) {
+            Some ((t, k)) if *k == n => Ok (t),
+            Some ((_, k)) => Err (format!("constructor {} takes {} fields but is given {}", c, k, n)),
+            None => Err (format!("unknown constructor {}", c))
+        }
+    }
+    // a bare capitalised name is a constructor only if some type declares it, and a variable otherwise
+    fn pattern(&self, p : &Pattern) -> Result<Pattern, String> {
+        match p {
+            Pattern::Wildcard | Pattern::Name (_) => Ok (p.clone()),
+            Pattern::Tuple (pl) => Ok (Pattern::Tuple (self.patterns(pl)?)),
+            Pattern::Constructor (c, pl) if pl.is_empty() && !self.ctors.contains_key(c) => Ok (Pattern::Name (c.clone())),
+            Pattern::Constructor (c, pl) => {
+                self.constructor(c, pl.len())?;
+                Ok (Pattern::Constructor (c.clone(), self.patterns(pl)?))
+            }
+        }
+    }
+    fn patterns(&self, pl : &[Pattern]) -> Result<Vec<Pattern>, String> {
+        pl.iter().map(|p| self.pattern(p)).collect()
+    }
+    // a value no row accepts, one entry per column, or None when every value is accepted
+    fn missing(&self, rows : Vec<Vec<&Pattern>>, width : usize) -> Option<Vec<String>> {
+        if width == 0 {
+            return if rows.is_empty() { Some (Vec::new()) } else { None };
+        }
+        let ty = rows.iter().find_map(|r| match r[0] {
+            Pattern::Constructor (c, _) => self.ctors.get(c).map(|(t, _)| t),
+            _ => None
+        });
+        let tuple = rows.iter().find_map(|r| match r[0] {
+            Pattern::Tuple (pl) => Some (pl.len()),
+            _ => None
+        });
+        if let Some (ty) = ty {
+            for c in self.types[ty].iter() {
+                let k = self.ctors[c].1;
+                let spec = rows.iter().filter_map(|r| match r[0] {
+                    Pattern::Constructor (d, pl) if d == c => Some (pl.iter().chain(r[1..].iter().cloned()).collect()),
+                    Pattern::Constructor (_, _) => None,
+                    _ => Some (iter::repeat(WILD).take(k).chain(r[1..].iter().cloned()).collect())
+                }).collect();
+                if let Some (mut w) = self.missing(spec, k + width - 1) {
+                    let rest = w.split_off(k);
+                    let head = if k == 0 { c.clone() } else { format!("{}({})", c, w.join(", ")) };
+                    return Some (iter::once(head).chain(rest).collect());
+                }
+            }
+            None
+        }
+        else if let Some (k) = tuple {
+            let spec = rows.iter().filter_map(|r| match r[0] {
+                Pattern::Tuple (pl) if pl.len() == k => Some (pl.iter().chain(r[1..].iter().cloned()).collect()),
+                Pattern::Tuple (_) => None,
+                _ => Some (iter::repeat(WILD).take(k).chain(r[1..].iter().cloned()).collect())
+            }).collect();
+            self.missing(spec, k + width - 1).map(|mut w| {
+                let rest = w.split_off(k);
+                iter::once(format!("({})", w.join(", "))).chain(rest).collect()
+            })
+        }
+        else {
+            self.missing(rows.iter().map(|r| r[1..].to_vec()).collect(), width - 1)
+                .map(|w| iter::once("_".to_string()).chain(w).collect())
+        }
+    }
+    fn cases<'a, I : Iterator<Item = &'a Pattern>>(&self, pats : I) -> Result<(), String> {
+        let pats : Vec<&Pattern> = pats.collect();
+        let mut built : Option<&str> = None;
+        for p in pats.iter() {
+            if let Pattern::Constructor (c, pl) = p {
+                let t = self.constructor(c, pl.len())?;
+                match built {
+                    Some (u) if u != t => return Err (format!("the cases of a match mix types {} and {}", u, t)),
+                    _ => built = Some (t)
+                }
+            }
+        }
+        match self.missing(pats.into_iter().map(|p| vec![p]).collect(), 1) {
+            Some (w) => Err (format!("the match is not exhaustive: {} is not covered", w[0])),
+            None => Ok (())
+        }
+    }
+    // a val has no other case to fall back to, so its pattern must accept every value
+    fn irrefutable(&self, p : Pattern) -> Result<Pattern, String> {
+        match self.missing(vec![vec![&p]], 1) {
+            Some (w) => Err (format!("the pattern {} of a val does not cover {}", p, w[0])),
+            None => Ok (p)
+        }
+    }
+    fn lambda(&self, l : &Lambda) -> Result<Lambda, String> {
+        let sub = |e : &Rc<Lambda>| self.lambda(e).map(Rc::new);
+        Ok (match l {
+            Lambda::IntLiteral { i : _, t : _ } | Lambda::FloatLiteral { f : _, t : _ } | Lambda::StrLiteral { s : _, t : _ } => l.clone(),
+            Lambda::True { t : _ } | Lambda::False { t : _ } | Lambda::Var { v : _, t : _ } => l.clone(),
+            Lambda::Tuple { tup, t } => Lambda::Tuple { tup : self.lambdas(tup)?, t : t.clone() },
+            Lambda::Call { f, args, t } => Lambda::Call { f : f.clone(), args : self.lambdas(args)?, t : t.clone() },
+            Lambda::List { elems, t } => Lambda::List { elems : self.lambdas(elems)?, t : t.clone() },
+            Lambda::Index { i, e, t } => Lambda::Index { i : *i, e : sub(e)?, t : t.clone() },
+            Lambda::Abs { x, e, t } => Lambda::Abs { x : x.clone(), e : sub(e)?, t : t.clone() },
+            Lambda::Closure { f, x, e, env, t } =>
+                Lambda::Closure { f : f.clone(), x : x.clone(), e : sub(e)?, env : env.clone(), t : t.clone() },
+            Lambda::App { lhs, rhs, t } => Lambda::App { lhs : sub(lhs)?, rhs : sub(rhs)?, t : t.clone() },
+            Lambda::IfExpr { c, e1, e2, t } => Lambda::IfExpr { c : sub(c)?, e1 : sub(e1)?, e2 : sub(e2)?, t : t.clone() },
+            Lambda::BinExpr { b, l, r, t } => Lambda::BinExpr { b : *b, l : sub(l)?, r : sub(r)?, t : t.clone() },
+            Lambda::LetRec { f, x, e1, e2, t } =>
+                Lambda::LetRec { f : f.clone(), x : x.clone(), e1 : sub(e1)?, e2 : sub(e2)?, t : t.clone() },
+            Lambda::Construct { c, args, t } if args.is_empty() && !self.ctors.contains_key(c) => Lambda::Var { v : c.clone(), t : t.clone() },
+            Lambda::Construct { c, args, t } if !self.ctors.contains_key(c) => {
+                // the parser reads a capitalised name applied to arguments as a constructor;
+                // an undeclared one is a call, built the way the parser builds calls
+                parser::application(Lambda::Var { v : c.clone(), t : t.clone() }, self.lambdas(args)?)
+            },
+            Lambda::Construct { c, args, t } => {
+                self.constructor(c, args.len())?;
+                Lambda::Construct { c : c.clone(), args : self.lambdas(args)?, t : t.clone() }
+            },
+            Lambda::Match { e, arms, t } => {
+                let arms = arms.iter().map(|(p, x)| Ok ((self.pattern(p)?, self.lambda(x)?))).collect::<Result<Vec<_>, String>>()?;
+                self.cases(arms.iter().map(|(p, _)| p))?;
+                Lambda::Match { e : sub(e)?, arms : arms, t : t.clone() }
+            }
+        })
+    }
+    fn lambdas(&self, xs : &[Lambda]) -> Result<Vec<Lambda>, String> {
+        xs.iter().map(|x| self.lambda(x)).collect()
+    }
+    fn process(&self, p : &syntax::Process) -> Result<Rc<syntax::Process>, String> {
+        let sub = |q : &Rc<syntax::Process>| self.process(q);
+        Ok (Rc::new(match p {
+            syntax::Process::Restriction (c, r, q) => syntax::Process::Restriction (c.clone(), *r, sub(q)?),
+            syntax::Process::Action (a, q) => syntax::Process::Action (a.clone(), sub(q)?),
+            syntax::Process::Repetition (n, q) => syntax::Process::Repetition (*n, sub(q)?),
+            syntax::Process::Replication (a, q) => syntax::Process::Replication (a.clone(), sub(q)?),
+            syntax::Process::LetVal (pat, l, q) => syntax::Process::LetVal (self.irrefutable(self.pattern(pat)?)?, self.lambda(l)?, sub(q)?),
+            syntax::Process::Parallel (ps) =>
+                syntax::Process::Parallel (Rc::new(ps.iter().map(sub).collect::<Result<_, _>>()?)),
+            syntax::Process::Choice (bs) =>
+                syntax::Process::Choice (Rc::new(bs.iter().map(|(a, q)| Ok ((a.clone(), sub(q)?))).collect::<Result<_, String>>()?)),
+            syntax::Process::Instance (n, params) => syntax::Process::Instance (n.clone(), self.lambdas(params)?),
+            syntax::Process::Match (l, arms) => {
+                let arms = arms.iter().map(|(p, q)| Ok ((self.pattern(p)?, sub(q)?))).collect::<Result<Vec<_>, String>>()?;
+                self.cases(arms.iter().map(|(p, _)| p))?;
+                syntax::Process::Match (self.lambda(l)?, arms)
+            },
+            syntax::Process::Termination => syntax::Process::Termination
+        }))
+    }
+    fn directive(&self, d : &syntax::Directive) -> Result<syntax::Directive, String> {
+        match d {
+            syntax::Directive::Run (p) => Ok (syntax::Directive::Run (self.process(p)?)),
+            syntax::Directive::Kill (_) => Ok (d.clone())
+        }
+    }
+}
+
+// every constructor is declared and applied to the right number of fields, and every
+// match has a case for each value its constructors can build; the program comes back
+// with each capitalised name that no type declares read as a variable
+pub fn check(prog : &syntax::Program) -> Result<syntax::Program, String> {
+    let decs = match prog {
+        syntax::Program::Prog (decs) => decs
+    };
+    let d = Datatypes::new(decs)?;
+    let mut out = Vec::new();
+    for dec in decs.iter() {
+        out.push(Rc::new(match (*dec).borrow() {
+            syntax::Declaration::Def (n, pats, p) => {
+                d.patterns(pats)
+                    .and_then(|pats| Ok (syntax::Declaration::Def (n.clone(), pats, d.process(p)?)))
+                    .map_err(|e| format!("in {}: {}", n, e))?
+            },
+            syntax::Declaration::Run (p) => syntax::Declaration::Run (d.process(p)?),
+            syntax::Declaration::Directive (t, a) => syntax::Declaration::Directive (*t, d.directive(a)?),
+            syntax::Declaration::Trigger (t) => syntax::Declaration::Trigger (syntax::Trigger { action : d.directive(&t.action)?, ..t.clone() }),
+            syntax::Declaration::Val (pat, l) => syntax::Declaration::Val (d.irrefutable(d.pattern(pat)?)?, d.lambda(l)?),
+            dec => dec.clone()
+        }));
+    }
+    Ok (syntax::Program::Prog (Rc::new(out)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim;
+
+    #[test]
+    fn undeclared_capitalised_names_are_variables() {
+        let prog = crate::parse("type opt = None | Some of int\n\
+            new a@1.0\n\
+            let P (N) = val F = (fun X => X * 2) in val M = F(N + 1) in \
+            match Some(M) with None => end | Some(K) => !a; P(K)\n\
+            let Q () = ?a; Q()\n\
+            run (P(1) | Q())").unwrap();
+        let prog = check(&prog).unwrap();
+        let mut sim = sim::Simulator::new();
+        sim.load(&prog).unwrap();
+        for _ in 0..5 {
+            sim.reduce().unwrap();
+        }
+        let prog = crate::parse("new a@1.0\nlet P (x) = match x with Foo(y) => !a; P(y) | _ => end\nrun P(1)").unwrap();
+        assert_eq!(check(&prog).unwrap_err(), "in P: unknown constructor Foo");
+    }
+
+    #[test]
+    fn val_patterns_must_accept_every_value() {
+        let model = |pat : &str| crate::parse(&format!("type bound = Bound of int | Unbounded
+            new a@1.0
+            let P (b) = val {} = b in !a; P(b)
+            run P(Bound(1))", pat)).unwrap();
+        assert_eq!(check(&model("Bound(n)")).unwrap_err(), "in P: the pattern Bound(n) of a val does not cover Unbounded");
+        assert_eq!(check(&model("(Bound(n), _)")).unwrap_err(), "in P: the pattern (Bound(n), _) of a val does not cover (Unbounded, _)");
+        assert!(check(&model("x")).is_ok());
+        assert!(check(&model("_")).is_ok());
+    }
+}
@@ -0,0 +1,288 @@
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::borrow::Borrow;
+
+use super::syntax;
+use super::lambda::*;
+
+fn quote(s : &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn chan_of(a : &syntax::Act) -> &str {
+    match a {
+        syntax::Act::Input (c) | syntax::Act::Output (c) => c
+    }
+}
+
+struct Automaton<'a> {
+    out : String,
+    // edges into other definitions, written once every cluster is declared
+    links : String,
+    rates : BTreeMap<String, f64>,
+    defs : &'a BTreeSet<String>,
+    def : String,
+    fresh : usize
+}
+
+impl<'a> Automaton<'a> {
+    fn node(&mut self, label : &str, shape : &str) -> String {
+        self.fresh += 1;
+        let id = format!("{}.{}", self.def, self.fresh);
+        writeln!(self.out, "    {} [label={}, shape={}];", quote(&id), quote(label), shape).unwrap();
+        id
+    }
+    fn edge(&mut self, from : &str, to : &str, label : &str, style : &str) {
+        let (out, indent) = if to != self.def && self.defs.contains(to) { (&mut self.links, "  ") } else { (&mut self.out, "    ") };
+        writeln!(out, "{}{} -> {} [label={}{}];", indent, quote(from), quote(to), quote(label), style).unwrap();
+    }
+    fn act(&self, a : &syntax::Act) -> String {
+        let prefix = match a {
+            syntax::Act::Input (c) => format!("?{}", c),
+            syntax::Act::Output (c) => format!("!{}", c)
+        };
+        match self.rates.get(chan_of(a)) {
+            Some (r) => format!("{}@{}", prefix, float_literal(*r)),
+            None => prefix
+        }
+    }
+    fn prefix(&mut self, from : &str, a : &syntax::Act, p : &syntax::Process) {
+        let to = self.target(p);
+        let label = self.act(a);
+        self.edge(from, &to, &label, "");
+    }
+    // the state a process is in once control reaches it
+    fn target(&mut self, p : &syntax::Process) -> String {
+        match p {
+            syntax::Process::Instance (n, _) if self.defs.contains(n) => n.clone(),
+            syntax::Process::Instance (n, _) => self.node(&format!("{} (undefined)", n), "octagon"),
+            syntax::Process::Termination => self.node("end", "point"),
+            syntax::Process::Action (a, q) => {
+                let id = self.node("", "circle");
+                self.prefix(&id, a, q);
+                id
+            },
+            syntax::Process::Choice (bs) => {
+                let id = self.node("", "circle");
+                for (a, q) in bs.iter() {
+                    self.prefix(&id, a, q);
+                }
+                id
+            },
+            syntax::Process::Parallel (ps) => {
+                let id = self.node("|", "diamond");
+                for q in ps.iter() {
+                    let to = self.target(q);
+                    self.edge(&id, &to, "", ", style=dashed");
+                }
+                id
+            },
+            syntax::Process::Repetition (n, q) => {
+                let id = self.node(&format!("{} of", n), "diamond");
+                let to = self.target(q);
+                self.edge(&id, &to, "", ", style=dashed");
+                id
+            },
+            syntax::Process::Replication (a, q) => {
+                let id = self.node("replicate", "doublecircle");
+                let to = self.target(q);
+                let label = self.act(a);
+                self.edge(&id, &id, &label, "");
+                self.edge(&id, &to, "", ", style=dashed");
+                id
+            },
+            syntax::Process::Restriction (c, r, q) => {
+                let id = self.node(&format!("new {}@{}", c, float_literal(*r)), "box");
+                let saved = self.rates.insert(c.clone(), *r);
+                let to = self.target(q);
+                match saved {
+                    Some (r) => self.rates.insert(c.clone(), r),
+                    None => self.rates.remove(c)
+                };
+                self.edge(&id, &to, "", ", style=dotted");
+                id
+            },
+            syntax::Process::LetVal (pat, l, q) => {
+                let id = self.node(&format!("val {} = {}", pat, l), "box");
+                let to = self.target(q);
+                self.edge(&id, &to, "", ", style=dotted");
+                id
+            },
+            syntax::Process::Match (l, arms) => {
+                let id = self.node(&format!("match {}", l), "diamond");
+                for (pat, q) in arms.iter() {
+                    let to = self.target(q);
+                    self.edge(&id, &to, &pat.to_string(), ", style=dotted");
+                }
+                id
+            }
+        }
+    }
+    fn definition(&mut self, name : &str, params : &[String], body : &syntax::Process) {
+        self.def = name.to_string();
+        self.fresh = 0;
+        writeln!(self.out, "  subgraph {} {{", quote(&format!("cluster_{}", name))).unwrap();
+        writeln!(self.out, "    label={};", quote(&format!("{}({})", name, params.join(", ")))).unwrap();
+        writeln!(self.out, "    {} [shape=box, style=rounded];", quote(name)).unwrap();
+        match body {
+            syntax::Process::Action (a, q) => self.prefix(name, a, q),
+            syntax::Process::Choice (bs) => {
+                for (a, q) in bs.iter() {
+                    self.prefix(name, a, q);
+                }
+            },
+            p => {
+                let to = self.target(p);
+                self.edge(name, &to, "", ", style=dotted");
+            }
+        }
+        writeln!(self.out, "  }}").unwrap();
+    }
+}
+
+fn declarations(prog : &syntax::Program) -> &[std::rc::Rc<syntax::Declaration>] {
+    match prog {
+        syntax::Program::Prog (decs) => decs
+    }
+}
+
+pub fn automata(prog : &syntax::Program) -> String {
+    let decs = declarations(prog);
+    let defs : BTreeSet<String> = decs.iter().filter_map(|d| match (*d).borrow() {
+        syntax::Declaration::Def (n, _, _) => Some (n.clone()),
+        _ => None
+    }).collect();
+    let mut a = Automaton { out : String::new(), links : String::new(), rates : BTreeMap::new(), defs : &defs, def : String::new(), fresh : 0 };
+    writeln!(a.out, "digraph automata {{").unwrap();
+    for d in decs.iter() {
+        match (*d).borrow() {
+            syntax::Declaration::NewChannel (c, r) => {
+                a.rates.insert(c.clone(), *r);
+            },
+            syntax::Declaration::Def (n, pats, body) => {
+                let params : Vec<String> = pats.iter().map(|p| p.to_string()).collect();
+                a.definition(n, &params, body);
+            },
+            _ => ()
+        }
+    }
+    let links = std::mem::replace(&mut a.links, String::new());
+    a.out.push_str(&links);
+    writeln!(a.out, "}}").unwrap();
+    a.out
+}
+
+struct Interactions {
+    chans : BTreeMap<String, Option<f64>>,
+    edges : BTreeSet<(String, String, bool)>
+}
+
+fn interactions_of(p : &syntax::Process, scope : &[(String, String)], owner : &str, g : &mut Interactions) {
+    let act = |a : &syntax::Act, g : &mut Interactions| {
+        let c = chan_of(a);
+        let node = match scope.iter().rev().find(|(n, _)| n == c) {
+            Some ((_, local)) => local.clone(),
+            None => c.to_string()
+        };
+        g.edges.insert((owner.to_string(), node, match a { syntax::Act::Input (_) => true, syntax::Act::Output (_) => false }));
+    };
+    match p {
+        syntax::Process::Action (a, q) | syntax::Process::Replication (a, q) => {
+            act(a, g);
+            interactions_of(q, scope, owner, g);
+        },
+        syntax::Process::Choice (bs) => {
+            for (a, q) in bs.iter() {
+                act(a, g);
+                interactions_of(q, scope, owner, g);
+            }
+        },
+        syntax::Process::Parallel (ps) => {
+            for q in ps.iter() {
+                interactions_of(q, scope, owner, g);
+            }
+        },
+        syntax::Process::Restriction (c, r, q) => {
+            let local = format!("{} (new in {})", c, owner);
+            g.chans.insert(local.clone(), Some (*r));
+            let mut inner = scope.to_vec();
+            inner.push((c.clone(), local));
+            interactions_of(q, &inner, owner, g);
+        },
+        syntax::Process::LetVal (_, _, q) | syntax::Process::Repetition (_, q) => interactions_of(q, scope, owner, g),
+        syntax::Process::Match (_, arms) => {
+            for (_, q) in arms.iter() {
+                interactions_of(q, scope, owner, g);
+            }
+        },
+        syntax::Process::Instance (_, _) | syntax::Process::Termination => ()
+    }
+}
+
+pub fn interactions(prog : &syntax::Program) -> String {
+    let mut g = Interactions { chans : BTreeMap::new(), edges : BTreeSet::new() };
+    let mut owners : Vec<String> = Vec::new();
+    for d in declarations(prog).iter() {
+        match (*d).borrow() {
+            syntax::Declaration::NewChannel (c, r) => {
+                g.chans.insert(c.clone(), Some (*r));
+            },
+            syntax::Declaration::Def (n, _, body) => {
+                owners.push(n.clone());
+                interactions_of(body, &[], n, &mut g);
+            },
+            syntax::Declaration::Run (p) => {
+                let before = g.edges.len();
+                interactions_of(p, &[], "run", &mut g);
+                if g.edges.len() > before && !owners.iter().any(|o| o == "run") {
+                    owners.push("run".to_string());
+                }
+            },
+            _ => ()
+        }
+    }
+    for (_, c, _) in g.edges.iter() {
+        g.chans.entry(c.clone()).or_insert(None);
+    }
+    let mut out = String::new();
+    writeln!(out, "digraph interactions {{").unwrap();
+    for o in owners.iter() {
+        let shape = if o == "run" { "plaintext" } else { "box" };
+        writeln!(out, "  {} [shape={}];", quote(o), shape).unwrap();
+    }
+    for (c, r) in g.chans.iter() {
+        let label = match r {
+            Some (r) => format!("{}@{}", c, float_literal(*r)),
+            None => c.clone()
+        };
+        writeln!(out, "  {} [shape=ellipse, label={}];", quote(&format!("chan {}", c)), quote(&label)).unwrap();
+    }
+    for (o, c, input) in g.edges.iter() {
+        let chan = format!("chan {}", c);
+        if *input {
+            writeln!(out, "  {} -> {} [label=\"?\"];", quote(&chan), quote(o)).unwrap();
+        }
+        else {
+            writeln!(out, "  {} -> {} [label=\"!\"];", quote(o), quote(&chan)).unwrap();
+        }
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cross_definition_edges_follow_every_cluster() {
+        let prog = crate::parse("new ionize@100.0\nlet Na () = !ionize; Naplus()\nlet Naplus () = ?ionize; Na()\nrun (Na())").unwrap();
+        let out = automata(&prog);
+        let last_cluster = out.rfind("subgraph").unwrap();
+        let closed = last_cluster + out[last_cluster..].find("\n  }\n").unwrap();
+        for edge in &["\"Na\" -> \"Naplus\"", "\"Naplus\" -> \"Na\""] {
+            assert!(out.find(edge).unwrap() > closed, "{} is declared inside a cluster:\n{}", edge, out);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+
+use super::crn;
+use super::ctmc;
+
+const EPSILON : f64 = 1e-12;
+
+pub struct Fsp {
+    pub m : ctmc::Ctmc,
+    pub p : Vec<f64>,
+    pub time : f64,
+    tol : f64,
+    horizon : f64,
+    limit : usize
+}
+
+impl Fsp {
+    pub fn new(c : crn::Crn, tol : f64, horizon : f64, limit : usize) -> Fsp {
+        let init = c.initial.clone();
+        Fsp { m : ctmc::Ctmc::project(c, vec![init]), p : vec![1.0], time : 0.0, tol : tol, horizon : horizon, limit : limit }
+    }
+    pub fn leak(&self) -> f64 {
+        (1.0 - self.p.iter().sum::<f64>()).max(0.0)
+    }
+    fn expand(&mut self, layers : usize) -> Result<bool, String> {
+        let mut states = self.m.states.clone();
+        let mut m = None;
+        for _ in 0..layers {
+            let frontier = m.as_ref().unwrap_or(&self.m).frontier();
+            if frontier.is_empty() {
+                break;
+            }
+            states.extend(frontier);
+            if states.len() > self.limit {
+                return Err (format!("the projection needs more than {} states to stay within tolerance {}", self.limit, self.tol));
+            }
+            m = Some (ctmc::Ctmc::project(self.m.crn.clone(), states.clone()));
+        }
+        match m {
+            Some (m) => {
+                self.p.resize(m.len(), 0.0);
+                self.m = m;
+                Ok (true)
+            },
+            None => Ok (false)
+        }
+    }
+    pub fn advance(&mut self, t : f64) -> Result<(), String> {
+        let budget = self.tol * (t / self.horizon).min(1.0);
+        let mut layers = 1;
+        loop {
+            let p = self.m.transient(&self.p, t - self.time, EPSILON);
+            let leak = 1.0 - p.iter().sum::<f64>();
+            // with no frontier left the projection is the whole reachable space
+            if leak <= budget || !self.expand(layers)? {
+                self.p = p;
+                self.time = t;
+                return Ok (());
+            }
+            layers *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crn::tests::{two_state, switched};
+
+    #[test]
+    fn projection_grows_to_the_exact_distribution() {
+        let mut f = Fsp::new(two_state(4), 1e-8, 2.0, 1000);
+        assert_eq!(f.m.len(), 1);
+        for t in &[0.5, 2.0] {
+            f.advance(*t).unwrap();
+            assert!(f.leak() <= 1e-8);
+            let q = switched(*t);
+            let b = &f.m.marginals(&f.p)["B"];
+            for (k, choose) in [1.0, 4.0, 6.0, 4.0, 1.0].iter().enumerate() {
+                let exact = choose * q.powi(k as i32) * (1.0 - q).powi(4 - k as i32);
+                assert!((b[&k] - exact).abs() < 1e-8, "P(B = {}) at time {}", k, t);
+            }
+        }
+        assert_eq!(f.m.len(), 5);
+    }
+
+    #[test]
+    fn projection_too_large_for_the_limit_is_an_error() {
+        let mut f = Fsp::new(two_state(20), 1e-12, 1.0, 5);
+        assert!(f.advance(1.0).unwrap_err().contains("more than 5 states"));
+    }
+}
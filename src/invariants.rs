@@ -0,0 +1,185 @@
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::crn;
+
+fn gcd(a : i64, b : i64) -> i64 {
+    if b == 0 { a.abs() } else { gcd(b, a % b) }
+}
+
+fn normalise(v : &mut [i64]) {
+    let g = v.iter().fold(0, |g, x| gcd(g, *x));
+    if g > 1 {
+        for x in v.iter_mut() {
+            *x /= g;
+        }
+    }
+    if v.iter().find(|x| **x != 0).map_or(false, |x| *x < 0) {
+        for x in v.iter_mut() {
+            *x = -*x;
+        }
+    }
+}
+
+const OVERFLOW : &str = "the stoichiometry is too large for exact integer elimination";
+
+// integer basis of { x | m x = 0 }, by fraction-free elimination; every row is reduced by its
+// gcd as it goes, and what still does not fit in an i64 is an error rather than a wrong basis
+pub fn null_space(m : &[Vec<i64>], cols : usize) -> Result<Vec<Vec<i64>>, String> {
+    let mut rows : Vec<Vec<i64>> = m.to_vec();
+    let mut pivots : Vec<usize> = Vec::new();
+    let mut r = 0;
+    for c in 0..cols {
+        let p = match (r..rows.len()).find(|i| rows[*i][c] != 0) {
+            Some (p) => p,
+            None => continue
+        };
+        rows.swap(r, p);
+        for i in 0..rows.len() {
+            if i == r || rows[i][c] == 0 {
+                continue;
+            }
+            // only the ratio of the two pivots matters, so take it in lowest terms
+            let g = gcd(rows[r][c], rows[i][c]);
+            let (a, b) = (rows[r][c] / g, rows[i][c] / g);
+            for k in 0..cols {
+                rows[i][k] = rows[i][k].checked_mul(a)
+                    .and_then(|x| rows[r][k].checked_mul(b).and_then(|y| x.checked_sub(y)))
+                    .ok_or(OVERFLOW)?;
+            }
+            normalise(&mut rows[i]);
+        }
+        pivots.push(c);
+        r += 1;
+    }
+    let mut basis = Vec::new();
+    for f in (0..cols).filter(|c| !pivots.contains(c)) {
+        let scale = pivots.iter().enumerate().try_fold(1i64, |l, (i, pc)| {
+            let a = rows[i][*pc].abs();
+            (l / gcd(l, a)).checked_mul(a).ok_or(OVERFLOW)
+        })?;
+        let mut v = vec![0; cols];
+        v[f] = scale;
+        for (i, pc) in pivots.iter().enumerate() {
+            v[*pc] = -rows[i][f].checked_mul(scale / rows[i][*pc]).ok_or(OVERFLOW)?;
+        }
+        normalise(&mut v);
+        basis.push(v);
+    }
+    Ok (basis)
+}
+
+pub struct Invariants {
+    pub p : Vec<Vec<i64>>,
+    pub t : Vec<Vec<i64>>
+}
+
+impl Invariants {
+    pub fn new(c : &crn::Crn) -> Result<Invariants, String> {
+        let n = c.species.len();
+        let stoich : Vec<Vec<i64>> = c.reactions.iter().map(|r| c.stoichiometry(r)).collect();
+        let by_species : Vec<Vec<i64>> = (0..n).map(|s| stoich.iter().map(|d| d[s]).collect()).collect();
+        Ok (Invariants { p : null_space(&stoich, n)?, t : null_space(&by_species, stoich.len())? })
+    }
+    pub fn total(c : &crn::Crn, y : &[i64]) -> i64 {
+        y.iter().zip(c.initial.iter()).map(|(a, n)| a * *n as i64).sum()
+    }
+    // a P-invariant that gives every species of a definition the same weight can be
+    // checked against the instance counts the simulator keeps per definition
+    pub fn by_definition(c : &crn::Crn, y : &[i64]) -> Option<BTreeMap<String, i64>> {
+        let mut m : BTreeMap<String, i64> = BTreeMap::new();
+        for (s, a) in c.species.iter().zip(y.iter()) {
+            if *m.entry(s.def.clone()).or_insert(*a) != *a {
+                return None;
+            }
+        }
+        m.retain(|_, a| *a != 0);
+        Some (m)
+    }
+}
+
+fn sum(terms : Vec<(i64, String)>) -> String {
+    let mut out = String::new();
+    for (a, name) in terms.into_iter().filter(|(a, _)| *a != 0) {
+        if out.is_empty() {
+            out.push_str(if a < 0 { "-" } else { "" });
+        }
+        else {
+            out.push_str(if a < 0 { " - " } else { " + " });
+        }
+        if a.abs() != 1 {
+            write!(out, "{} ", a.abs()).unwrap();
+        }
+        out.push_str(&name);
+    }
+    out
+}
+
+fn reaction_names(c : &crn::Crn) -> Vec<String> {
+    let mut seen : BTreeMap<&str, usize> = BTreeMap::new();
+    for r in c.reactions.iter() {
+        *seen.entry(&r.chan).or_insert(0) += 1;
+    }
+    let mut next : BTreeMap<&str, usize> = BTreeMap::new();
+    c.reactions.iter().map(|r| {
+        if seen[r.chan.as_str()] == 1 {
+            return r.chan.clone();
+        }
+        let k = next.entry(&r.chan).or_insert(0);
+        *k += 1;
+        format!("{}.{}", r.chan, k)
+    }).collect()
+}
+
+pub fn report(c : &crn::Crn) -> Result<String, String> {
+    let inv = Invariants::new(c)?;
+    let mut out = String::new();
+    writeln!(out, "{} species, {} reactions", c.species.len(), c.reactions.len()).unwrap();
+    writeln!(out, "conservation laws").unwrap();
+    for y in inv.p.iter() {
+        let terms = c.species.iter().zip(y.iter()).map(|(s, a)| (*a, s.name.clone())).collect();
+        writeln!(out, "  {} = {}", sum(terms), Invariants::total(c, y)).unwrap();
+    }
+    writeln!(out, "reaction cycles").unwrap();
+    let names = reaction_names(c);
+    for x in inv.t.iter() {
+        let terms = names.iter().zip(x.iter()).map(|(n, a)| (*a, n.clone())).collect();
+        writeln!(out, "  {}", sum(terms)).unwrap();
+    }
+    Ok (out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crn::tests::two_state;
+
+    #[test]
+    fn two_state_invariants() {
+        let c = two_state(5);
+        let inv = Invariants::new(&c).unwrap();
+        let laws : Vec<BTreeMap<String, i64>> = inv.p.iter().filter_map(|y| Invariants::by_definition(&c, y)).collect();
+        let totals : Vec<i64> = inv.p.iter().map(|y| Invariants::total(&c, y)).collect();
+        assert_eq!(laws.len(), 2);
+        let ab : BTreeMap<String, i64> = vec![("A".to_string(), 1), ("B".to_string(), 1)].into_iter().collect();
+        let e : BTreeMap<String, i64> = vec![("E".to_string(), 1)].into_iter().collect();
+        assert!(laws.contains(&ab) && laws.contains(&e), "{:?}", laws);
+        assert!(totals.contains(&5) && totals.contains(&1));
+        // switching there and back is the one cycle
+        assert_eq!(inv.t, vec![vec![1, 1]]);
+    }
+
+    #[test]
+    fn null_space_of_a_chain() {
+        assert_eq!(null_space(&[vec![1, -1, 0], vec![0, 2, -2]], 3).unwrap(), vec![vec![1, 1, 1]]);
+        assert_eq!(null_space(&[vec![2, -3]], 2).unwrap(), vec![vec![3, 2]]);
+        assert_eq!(null_space(&[vec![6, 4, 0], vec![9, 6, 0]], 3).unwrap(), vec![vec![2, -3, 0], vec![0, 0, 1]]);
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        let big = i64::max_value() / 2;
+        assert_eq!(null_space(&[vec![big, 3, 1], vec![5, big - 1, 1]], 3).unwrap_err(), OVERFLOW);
+    }
+}
@@ -1,7 +1,9 @@
 use std::rc::Rc;
+use std::cell::Cell;
+use std::fmt;
 use std::convert::From;
 use std::convert::Into;
-use std::cmp::{PartialEq, PartialOrd, Ordering};
+use std::cmp::Ordering;
 
 use diff_enum::common_fields;
 
@@ -22,6 +24,64 @@ pub enum BinOp {
     NotEqual
 }
 
+// the values of the variables in scope, innermost first; binding a variable shares the rest
+#[derive(Clone, Debug, Default)]
+pub struct Env (Option<Rc<(String, Lambda, Env)>>);
+
+impl Env {
+    pub fn new() -> Env {
+        Env (None)
+    }
+    pub fn bind(&self, x : &str, v : Lambda) -> Env {
+        Env (Some (Rc::new((x.to_string(), v, self.clone()))))
+    }
+    pub fn extend(&self, bs : Vec<(String, Lambda)>) -> Env {
+        bs.into_iter().fold(self.clone(), |env, (x, v)| env.bind(&x, v))
+    }
+    pub fn lookup(&self, x : &str) -> Option<&Lambda> {
+        let mut env = self;
+        while let Some (b) = &env.0 {
+            if b.0 == x {
+                return Some (&b.1);
+            }
+            env = &b.2;
+        }
+        None
+    }
+    // a channel made by new is bound to its fresh name under a key no variable can have,
+    // so the body it scopes over is shared rather than rewritten
+    pub fn bind_channel(&self, c : &str, fresh : &str) -> Env {
+        self.bind(&format!("new:{}", c), Lambda::Var { v : fresh.to_string(), t : Type::Channel (None) })
+    }
+    // the name a channel goes by once every enclosing new has been applied
+    pub fn channel<'a>(&'a self, c : &'a str) -> &'a str {
+        match self.lookup(&format!("new:{}", c)) {
+            Some (Lambda::Var { v, t : _ }) => v,
+            _ => c
+        }
+    }
+    // the summations an instance's body breaks into carry its id, so that the instance
+    // can be counted while any of them is left and removed with them
+    pub fn bind_owner(&self, id : &str) -> Env {
+        self.bind("instance:", Lambda::Var { v : id.to_string(), t : Type::TVar })
+    }
+    pub fn owner(&self) -> Option<&str> {
+        match self.lookup("instance:") {
+            Some (Lambda::Var { v, t : _ }) => Some (v),
+            _ => None
+        }
+    }
+    pub fn bindings(&self) -> Vec<(&str, &Lambda)> {
+        let mut bs = Vec::new();
+        let mut env = self;
+        while let Some (b) = &env.0 {
+            bs.push((b.0.as_str(), &b.1));
+            env = &b.2;
+        }
+        bs
+    }
+}
+
 #[common_fields {
     t : Type
 }]
@@ -29,6 +89,7 @@ pub enum BinOp {
 pub enum Lambda {
     IntLiteral { i : i64 },
     FloatLiteral { f : f64 },
+    StrLiteral { s : String },
     True,
     False,
     Var { v : String },
@@ -37,7 +98,13 @@ pub enum Lambda {
     Abs { x : String, e : Rc<Lambda> },
     App { lhs : Rc<Lambda>, rhs : Rc<Lambda> },
     IfExpr { c : Rc<Lambda>, e1 : Rc<Lambda>, e2 : Rc<Lambda> },
-    BinExpr { b : BinOp, l : Rc<Lambda>, r : Rc<Lambda> }
+    BinExpr { b : BinOp, l : Rc<Lambda>, r : Rc<Lambda> },
+    Call { f : String, args : Vec<Lambda> },
+    List { elems : Vec<Lambda> },
+    LetRec { f : String, x : String, e1 : Rc<Lambda>, e2 : Rc<Lambda> },
+    Closure { f : Option<String>, x : String, e : Rc<Lambda>, env : Env },
+    Construct { c : String, args : Vec<Lambda> },
+    Match { e : Rc<Lambda>, arms : Vec<(Pattern, Lambda)> }
 }
 
 impl From<tokenizer::Token> for BinOp {
@@ -47,56 +114,329 @@ impl From<tokenizer::Token> for BinOp {
             tokenizer::Token::Dash => BinOp::Sub,
             tokenizer::Token::Star => BinOp::Times,
             tokenizer::Token::Slash => BinOp::Div,
+            tokenizer::Token::Equals => BinOp::Equal,
+            tokenizer::Token::Less => BinOp::Less,
+            tokenizer::Token::Greater => BinOp::Greater,
+            tokenizer::Token::LEq => BinOp::LEq,
+            tokenizer::Token::GEq => BinOp::GEq,
+            tokenizer::Token::NotEqual => BinOp::NotEqual,
             _ => panic!()
         }
     }
 }
 
-impl BinOp {
-    pub fn eval(self, t : Type, l : Lambda, r : Lambda) -> Lambda {
-        match t {
-            Type::Integer | Type::Float => match self {
-                    BinOp::Plus => l + r,
-                    BinOp::Sub => l - r,
-                    BinOp::Times => l * r,
-                    BinOp::Div => l / r,
-                    BinOp::Equal => Lambda::from(l == r),
-                    BinOp::Less => Lambda::from(l < r),
-                    BinOp::Greater => Lambda::from(l > r),
-                    BinOp::LEq => Lambda::from(l <= r),
-                    BinOp::GEq => Lambda::from(l >= r),
-                    BinOp::NotEqual => Lambda::from(l != r),
-                },
-            _ => panic!()
+// a number as arithmetic sees it; an integer only becomes a float when it meets one
+#[derive(Clone, Copy, Debug)]
+enum Num {
+    Int (i64),
+    Float (f64)
+}
+
+impl Num {
+    fn of(l : &Lambda) -> Result<Num, String> {
+        match l {
+            Lambda::IntLiteral { i, t : _ } => Ok (Num::Int (*i)),
+            Lambda::FloatLiteral { f, t : _ } => Ok (Num::Float (*f)),
+            _ => Err (format!("{} is not a number", l))
+        }
+    }
+    fn float(self) -> f64 {
+        match self {
+            Num::Int (i) => i as f64,
+            Num::Float (f) => f
+        }
+    }
+    fn is_zero(self) -> bool {
+        self.float() == 0.0
+    }
+}
+
+impl From<Num> for Lambda {
+    fn from(n : Num) -> Lambda {
+        match n {
+            Num::Int (i) => Lambda::IntLiteral { i : i, t : Type::Integer },
+            Num::Float (f) => Lambda::FloatLiteral { f : f, t : Type::Float }
         }
     }
 }
 
-impl Substitutable<Lambda> for Lambda {
-    fn substitute (&self, src : &str, dest : Lambda) -> Lambda {
+fn equal(l : &Lambda, r : &Lambda) -> Option<bool> {
+    match (l, r) {
+        (Lambda::True { t : _ }, _) | (Lambda::False { t : _ }, _) => {
+            let x : bool = l.into();
+            match r {
+                Lambda::True { t : _ } | Lambda::False { t : _ } => Some (x == r.into()),
+                _ => None
+            }
+        },
+        (Lambda::StrLiteral { s : a, t : _ }, Lambda::StrLiteral { s : b, t : _ }) => Some (a == b),
+        (Lambda::Tuple { tup : a, t : _ }, Lambda::Tuple { tup : b, t : _ }) if a.len() == b.len() =>
+            a.iter().zip(b.iter()).try_fold(true, |eq, (x, y)| equal(x, y).map(|e| eq && e)),
+        (Lambda::Construct { c : c1, args : a, t : _ }, Lambda::Construct { c : c2, args : b, t : _ }) =>
+            if c1 != c2 { Some (false) } else { a.iter().zip(b.iter()).try_fold(true, |eq, (x, y)| equal(x, y).map(|e| eq && e)) },
+        (Lambda::List { elems : a, t : _ }, Lambda::List { elems : b, t : _ }) =>
+            a.iter().zip(b.iter()).try_fold(a.len() == b.len(), |eq, (x, y)| equal(x, y).map(|e| eq && e)),
+        _ => match (Num::of(l).ok()?, Num::of(r).ok()?) {
+            (Num::Int (x), Num::Int (y)) => Some (x == y),
+            (x, y) => Some (x.float() == y.float())
+        }
+    }
+}
+
+impl BinOp {
+    pub fn eval(self, l : Lambda, r : Lambda) -> Result<Lambda, String> {
+        let fail = |why : &str| format!("{} in {} {} {}", why, l.atom(), self, r.atom());
         match self {
-            Lambda::IntLiteral { i : _, t : _ } => self.clone(),
-            Lambda::FloatLiteral { f : _, t : _ } => self.clone(),
-            Lambda::True { t : _ } => self.clone(),
-            Lambda::False { t : _ } => self.clone(),
-            Lambda::Var { v, t : _ } => 
-                if v == src { dest.clone() } else { self.clone() },
-            Lambda::Tuple { tup, t } => 
-                Lambda::Tuple { tup : tup.iter().map(|x| x.substitute(src, dest.clone())).collect(), t : t.clone() },
-            Lambda::Index { i, e, t } => 
-                Lambda::Index { i : *i, e : Rc::new(e.substitute(src, dest.clone())), t : t.clone() },
-            Lambda::Abs { x, e, t } => 
-                Lambda::Abs { x : x.clone(), e : Rc::new(e.substitute(src, dest.clone())), t : t.clone() },
-            Lambda::App { lhs, rhs, t } => 
-                Lambda::App { lhs : Rc::new(lhs.substitute(src, dest.clone())), rhs : Rc::new(rhs.substitute(src, dest.clone())), t : t.clone() },
-            Lambda::IfExpr { c, e1, e2, t } =>
-                Lambda::IfExpr { c : Rc::new(c.substitute(src, dest.clone())), 
-                    e1 : Rc::new(e1.substitute(src, dest.clone())), 
-                    e2 : Rc::new(e2.substitute(src, dest.clone())),
-                    t : t.clone() },
-            Lambda::BinExpr { b, l, r, t } => 
-                Lambda::BinExpr { b : *b, l : Rc::new(l.eval()), r : Rc::new(r.eval()), t : t.clone() }
+            BinOp::Equal => return equal(&l, &r).map(Lambda::from).ok_or_else(|| fail("mismatched operands")),
+            BinOp::NotEqual => return equal(&l, &r).map(|eq| Lambda::from(!eq)).ok_or_else(|| fail("mismatched operands")),
+            _ => ()
+        }
+        // strings join with + and compare in lexicographic order
+        if let (Lambda::StrLiteral { s : a, t : _ }, Lambda::StrLiteral { s : b, t : _ }) = (&l, &r) {
+            return match self {
+                BinOp::Plus => Ok (Lambda::StrLiteral { s : format!("{}{}", a, b), t : Type::Str }),
+                BinOp::Less => Ok (Lambda::from(a < b)),
+                BinOp::Greater => Ok (Lambda::from(a > b)),
+                BinOp::LEq => Ok (Lambda::from(a <= b)),
+                BinOp::GEq => Ok (Lambda::from(a >= b)),
+                _ => Err (fail("non-numeric operand"))
+            };
         }
+        let (x, y) = match (Num::of(&l), Num::of(&r)) {
+            (Ok (x), Ok (y)) => (x, y),
+            _ => return Err (fail("non-numeric operand"))
+        };
+        let ord = match (x, y) {
+            (Num::Int (a), Num::Int (b)) => Some (a.cmp(&b)),
+            (a, b) => a.float().partial_cmp(&b.float())
+        };
+        let n = match self {
+            BinOp::Less => return Ok (Lambda::from(ord == Some (Ordering::Less))),
+            BinOp::Greater => return Ok (Lambda::from(ord == Some (Ordering::Greater))),
+            BinOp::LEq => return Ok (Lambda::from(ord.map_or(false, |o| o != Ordering::Greater))),
+            BinOp::GEq => return Ok (Lambda::from(ord.map_or(false, |o| o != Ordering::Less))),
+            BinOp::Div if y.is_zero() => return Err (fail("division by zero")),
+            _ => match (x, y) {
+                (Num::Int (a), Num::Int (b)) => Num::Int (match self {
+                    BinOp::Plus => a.checked_add(b),
+                    BinOp::Sub => a.checked_sub(b),
+                    BinOp::Times => a.checked_mul(b),
+                    _ => a.checked_div(b)
+                }.ok_or_else(|| fail("integer overflow"))?),
+                (a, b) => Num::Float (match self {
+                    BinOp::Plus => a.float() + b.float(),
+                    BinOp::Sub => a.float() - b.float(),
+                    BinOp::Times => a.float() * b.float(),
+                    _ => a.float() / b.float()
+                })
+            }
+        };
+        if !n.float().is_finite() {
+            return Err (fail("overflow"));
+        }
+        Ok (Lambda::from(n))
+    }
+}
+
+enum Builtin {
+    Numeric (fn(&[Num]) -> Result<Num, String>),
+    Values (fn(&[Lambda]) -> Result<Lambda, String>)
+}
+
+fn abs(a : &[Num]) -> Result<Num, String> {
+    match a[0] {
+        Num::Int (i) => i.checked_abs().map(Num::Int).ok_or_else(|| "integer overflow".to_string()),
+        Num::Float (f) => Ok (Num::Float (f.abs()))
+    }
+}
+
+fn exp(a : &[Num]) -> Result<Num, String> {
+    Ok (Num::Float (a[0].float().exp()))
+}
+
+fn log(a : &[Num]) -> Result<Num, String> {
+    Ok (Num::Float (a[0].float().ln()))
+}
+
+fn sqrt(a : &[Num]) -> Result<Num, String> {
+    Ok (Num::Float (a[0].float().sqrt()))
+}
+
+fn pow(a : &[Num]) -> Result<Num, String> {
+    match (a[0], a[1]) {
+        (Num::Int (x), Num::Int (n)) if n >= 0 => std::convert::TryFrom::try_from(n).ok()
+            .and_then(|n| x.checked_pow(n))
+            .map(Num::Int)
+            .ok_or_else(|| "integer overflow".to_string()),
+        (x, y) => Ok (Num::Float (x.float().powf(y.float())))
+    }
+}
+
+fn min(a : &[Num]) -> Result<Num, String> {
+    match (a[0], a[1]) {
+        (Num::Int (x), Num::Int (y)) => Ok (Num::Int (x.min(y))),
+        (x, y) => Ok (Num::Float (x.float().min(y.float())))
+    }
+}
+
+fn max(a : &[Num]) -> Result<Num, String> {
+    match (a[0], a[1]) {
+        (Num::Int (x), Num::Int (y)) => Ok (Num::Int (x.max(y))),
+        (x, y) => Ok (Num::Float (x.float().max(y.float())))
+    }
+}
+
+fn modulo(a : &[Num]) -> Result<Num, String> {
+    match (a[0], a[1]) {
+        (Num::Int (_), Num::Int (0)) => Err ("division by zero".to_string()),
+        (Num::Int (x), Num::Int (y)) => x.checked_rem_euclid(y).map(Num::Int).ok_or_else(|| "integer overflow".to_string()),
+        _ => Err ("mod expects integers".to_string())
+    }
+}
+
+fn list(elems : Vec<Lambda>) -> Lambda {
+    Lambda::List { elems : elems, t : Type::List }
+}
+
+fn elems(l : &Lambda) -> Result<&[Lambda], String> {
+    match l {
+        Lambda::List { elems, t : _ } => Ok (elems),
+        _ => Err (format!("{} is not a list", l))
+    }
+}
+
+fn cons(a : &[Lambda]) -> Result<Lambda, String> {
+    let mut v = vec![a[0].clone()];
+    v.extend_from_slice(elems(&a[1])?);
+    Ok (list(v))
+}
+
+fn head(a : &[Lambda]) -> Result<Lambda, String> {
+    elems(&a[0])?.first().cloned().ok_or_else(|| "head of an empty list".to_string())
+}
+
+fn tail(a : &[Lambda]) -> Result<Lambda, String> {
+    match elems(&a[0])?.split_first() {
+        Some ((_, rest)) => Ok (list(rest.to_vec())),
+        None => Err ("tail of an empty list".to_string())
+    }
+}
+
+fn length(a : &[Lambda]) -> Result<Lambda, String> {
+    Ok (Lambda::IntLiteral { i : elems(&a[0])?.len() as i64, t : Type::Integer })
+}
+
+fn map(a : &[Lambda]) -> Result<Lambda, String> {
+    Ok (list(elems(&a[1])?.iter().map(|x| apply(&a[0], x.clone())).collect::<Result<_, _>>()?))
+}
+
+fn fold(a : &[Lambda]) -> Result<Lambda, String> {
+    elems(&a[2])?.iter().try_fold(a[1].clone(), |acc, x| apply(&apply(&a[0], acc)?, x.clone()))
+}
+
+// a longer list is taken for a mistake rather than built until memory runs out
+const MAX_RANGE : i64 = 1 << 20;
+
+fn range(a : &[Lambda]) -> Result<Lambda, String> {
+    match (Num::of(&a[0])?, Num::of(&a[1])?) {
+        (Num::Int (lo), Num::Int (hi)) if hi.checked_sub(lo).map_or(true, |n| n > MAX_RANGE) =>
+            Err (format!("a range may have at most {} elements", MAX_RANGE)),
+        (Num::Int (lo), Num::Int (hi)) => Ok (list((lo..hi).map(|i| Lambda::IntLiteral { i : i, t : Type::Integer }).collect())),
+        _ => Err ("range expects integers".to_string())
+    }
+}
+
+// the functions every expression can call, with their arities
+const BUILTINS : [(&str, usize, Builtin); 15] = [
+    ("abs", 1, Builtin::Numeric (abs)),
+    ("exp", 1, Builtin::Numeric (exp)),
+    ("log", 1, Builtin::Numeric (log)),
+    ("sqrt", 1, Builtin::Numeric (sqrt)),
+    ("pow", 2, Builtin::Numeric (pow)),
+    ("min", 2, Builtin::Numeric (min)),
+    ("max", 2, Builtin::Numeric (max)),
+    ("mod", 2, Builtin::Numeric (modulo)),
+    ("cons", 2, Builtin::Values (cons)),
+    ("head", 1, Builtin::Values (head)),
+    ("tail", 1, Builtin::Values (tail)),
+    ("length", 1, Builtin::Values (length)),
+    ("map", 2, Builtin::Values (map)),
+    ("fold", 3, Builtin::Values (fold)),
+    ("range", 2, Builtin::Values (range))
+];
+
+pub fn is_builtin(f : &str) -> bool {
+    BUILTINS.iter().any(|(n, _, _)| *n == f)
+}
+
+fn call(f : &str, args : &[Lambda]) -> Result<Lambda, String> {
+    // the arguments are only shown once a call has failed
+    let fail = |why : &str| {
+        let shown : Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        format!("{} in {}({})", why, f, shown.join(", "))
+    };
+    let (_, arity, fun) = BUILTINS.iter().find(|(n, _, _)| *n == f)
+        .ok_or_else(|| format!("unknown function {}", f))?;
+    if args.len() != *arity {
+        return Err (fail(&format!("{} expects {} arguments but was given {}", f, arity, args.len())));
+    }
+    match fun {
+        Builtin::Numeric (fun) => {
+            let nums = args.iter().map(Num::of).collect::<Result<Vec<Num>, String>>().map_err(|e| fail(&e))?;
+            let n = fun(&nums).map_err(|e| fail(&e))?;
+            if !n.float().is_finite() {
+                return Err (fail("no finite result"));
+            }
+            Ok (Lambda::from(n))
+        },
+        // a function applied by the builtin can recurse through it, and the depth limit
+        // would otherwise be reported once for every level
+        Builtin::Values (fun) => fun(args).map_err(|e| if e == TOO_DEEP { e } else { fail(&e) })
+    }
+}
+
+// the variables a pattern binds when it accepts a value
+pub fn bindings(p : &Pattern, v : &Lambda) -> Option<Vec<(String, Lambda)>> {
+    match (p, v) {
+        (Pattern::Wildcard, _) => Some (Vec::new()),
+        (Pattern::Name (n), v) => Some (vec![(n.clone(), v.clone())]),
+        (Pattern::Tuple (pl), Lambda::Tuple { tup : vl, t : _ }) | (Pattern::Constructor (_, pl), Lambda::Construct { c : _, args : vl, t : _ })
+            if pl.len() == vl.len() && p.constructor() == v.constructor() => {
+            let mut bs = Vec::new();
+            for (p, v) in pl.iter().zip(vl.iter()) {
+                bs.extend(bindings(p, v)?);
+            }
+            Some (bs)
+        },
+        _ => None
+    }
+}
+
+// the bindings of a pattern that must accept its value, as in a val or a definition's
+// parameters, with the innermost part of the value it fails on
+pub fn destructure(p : &Pattern, v : &Lambda) -> Result<Vec<(String, Lambda)>, String> {
+    match (p, v) {
+        (Pattern::Wildcard, _) | (Pattern::Name (_), _) => Ok (bindings(p, v).unwrap_or_default()),
+        (Pattern::Tuple (pl), Lambda::Tuple { tup : vl, t : _ }) | (Pattern::Constructor (_, pl), Lambda::Construct { c : _, args : vl, t : _ })
+            if p.constructor() == v.constructor() => {
+            if pl.len() != vl.len() {
+                return Err (format!("pattern {} has {} fields but {} has {}", p, pl.len(), v, vl.len()));
+            }
+            let mut bs = Vec::new();
+            for (p, v) in pl.iter().zip(vl.iter()) {
+                bs.extend(destructure(p, v)?);
+            }
+            Ok (bs)
+        },
+        _ => Err (format!("pattern {} does not accept {}", p, v))
+    }
+}
+
+fn apply(f : &Lambda, v : Lambda) -> Result<Lambda, String> {
+    match f {
+        Lambda::Closure { f : None, x, e, env, t : _ } => e.eval_in(&env.bind(x, v)),
+        Lambda::Closure { f : Some (name), x, e, env, t : _ } => e.eval_in(&env.bind(name, f.clone()).bind(x, v)),
+        _ => Err (format!("{} is not a function", f))
     }
 }
 
@@ -165,168 +505,356 @@ impl From<bool> for Lambda {
     }
 }
 
-impl std::ops::Add for Lambda {
-    type Output = Lambda;
+// how deeply evaluation may nest before a model's recursion is cut off; each level
+// costs native stack, and running out of it aborts the process
+const MAX_DEPTH : usize = 2000;
+const TOO_DEEP : &str = "recursion too deep";
 
-    fn add(self, rhs : Lambda) -> Lambda {
-        match self.t() {
-            Type::Integer => {
-                let tt = self.t().clone();
-                let x : i64 = self.into();
-                let y : i64 = rhs.into();
-                Lambda::IntLiteral { i : x + y, t : tt }
-            },
-            Type::Float => {
-                let tt = self.t().clone();
-                let x : f64 = self.into();
-                let y : f64 = rhs.into();
-                Lambda::FloatLiteral { f : x + y, t : tt }
+thread_local! {
+    static DEPTH : Cell<usize> = Cell::new(0);
+}
+
+fn all(xs : &[Lambda], env : &Env) -> Result<Vec<Lambda>, String> {
+    xs.iter().map(|x| x.eval_in(env)).collect()
+}
+
+impl Lambda {
+    pub fn eval(&self) -> Result<Lambda, String> {
+        self.eval_in(&Env::new())
+    }
+    pub fn eval_in(&self, env : &Env) -> Result<Lambda, String> {
+        let depth = DEPTH.with(|d| d.get());
+        if depth >= MAX_DEPTH {
+            return Err (TOO_DEEP.to_string());
+        }
+        DEPTH.with(|d| d.set(depth + 1));
+        let v = self.step(env);
+        DEPTH.with(|d| d.set(depth));
+        v
+    }
+    // every arm with temporaries of its own is a separate function, so that the frame
+    // of each level of a recursion stays small
+    fn step(&self, env : &Env) -> Result<Lambda, String> {
+        match self {
+            Lambda::IntLiteral { i : _, t : _ } | Lambda::FloatLiteral { f : _, t : _ } | Lambda::StrLiteral { s : _, t : _ } |
+            Lambda::True { t : _ } | Lambda::False { t : _ } | Lambda::Closure { f : _, x : _, e : _, env : _, t : _ } => Ok (self.clone()),
+            Lambda::Var { v, t : _ } => lookup(v, env),
+            Lambda::Tuple { tup : _, t : _ } | Lambda::List { elems : _, t : _ } | Lambda::Construct { c : _, args : _, t : _ } =>
+                self.rebuild(env),
+            Lambda::Index { i, e, t : _ } => index(*i, e, env),
+            Lambda::Abs { x : _, e : _, t : _ } | Lambda::LetRec { f : _, x : _, e1 : _, e2 : _, t : _ } => self.close(env),
+            Lambda::App { lhs, rhs, t : _ } => application(lhs, rhs, env),
+            Lambda::IfExpr { c, e1, e2, t : _ } => conditional(c, e1, e2, env),
+            Lambda::BinExpr { b, l, r, t : _ } => binary(*b, l, r, env),
+            Lambda::Call { f, args, t : _ } => builtin(f, args, env),
+            Lambda::Match { e, arms, t : _ } => matching(e, arms, env)
+        }
+    }
+    // a tuple, list or constructor with its parts evaluated
+    fn rebuild(&self, env : &Env) -> Result<Lambda, String> {
+        match self {
+            Lambda::Tuple { tup, t } => Ok (Lambda::Tuple { tup : all(tup, env)?, t : t.clone() }),
+            Lambda::List { elems, t } => Ok (Lambda::List { elems : all(elems, env)?, t : t.clone() }),
+            Lambda::Construct { c, args, t } => Ok (Lambda::Construct { c : c.clone(), args : all(args, env)?, t : t.clone() }),
+            _ => unreachable!()
+        }
+    }
+    // a function closed over the environment it is defined in; a let rec evaluates its
+    // body with the function bound to itself
+    fn close(&self, env : &Env) -> Result<Lambda, String> {
+        match self {
+            Lambda::Abs { x, e, t } => Ok (Lambda::Closure { f : None, x : x.clone(), e : e.clone(), env : env.clone(), t : t.clone() }),
+            Lambda::LetRec { f, x, e1, e2, t } => {
+                let rec = Lambda::Closure { f : Some (f.clone()), x : x.clone(), e : e1.clone(), env : env.clone(), t : t.clone() };
+                e2.eval_in(&env.bind(f, rec))
             },
-            _ => panic!()
+            _ => unreachable!()
         }
     }
 }
 
-impl std::ops::Sub for Lambda {
-    type Output = Lambda;
+fn lookup(v : &str, env : &Env) -> Result<Lambda, String> {
+    env.lookup(v).cloned().ok_or_else(|| format!("{} is not bound", v))
+}
 
-    fn sub(self, rhs : Lambda) -> Lambda {
-        match self.t() {
-            Type::Integer => {
-                let tt = self.t().clone();
-                let x : i64 = self.into();
-                let y : i64 = rhs.into();
-                Lambda::IntLiteral { i : x - y, t : tt }
-            },
-            Type::Float => {
-                let tt = self.t().clone();
-                let x : f64 = self.into();
-                let y : f64 = rhs.into();
-                Lambda::FloatLiteral { f : x - y, t : tt }
-            },
-            _ => panic!()
-        }
+fn application(lhs : &Lambda, rhs : &Lambda, env : &Env) -> Result<Lambda, String> {
+    let f = lhs.eval_in(env)?;
+    apply(&f, rhs.eval_in(env)?)
+}
+
+fn binary(b : BinOp, l : &Lambda, r : &Lambda, env : &Env) -> Result<Lambda, String> {
+    let x = l.eval_in(env)?;
+    b.eval(x, r.eval_in(env)?)
+}
+
+fn builtin(f : &str, args : &[Lambda], env : &Env) -> Result<Lambda, String> {
+    call(f, &all(args, env)?)
+}
+
+fn conditional(c : &Lambda, e1 : &Lambda, e2 : &Lambda, env : &Env) -> Result<Lambda, String> {
+    branch(c, e1, e2, env)?.eval_in(env)
+}
+
+fn matching(e : &Lambda, arms : &[(Pattern, Lambda)], env : &Env) -> Result<Lambda, String> {
+    let (x, env) = select(e, arms, env)?;
+    x.eval_in(&env)
+}
+
+fn index(i : i64, e : &Lambda, env : &Env) -> Result<Lambda, String> {
+    match e.eval_in(env)? {
+        Lambda::Tuple { tup, t : _ } => match tup.get(i as usize) {
+            Some (x) => Ok (x.clone()),
+            None => Err (format!("index {} is out of range for a tuple of {}", i, tup.len()))
+        },
+        x => Err (format!("{} is not a tuple", x))
     }
 }
 
-impl std::ops::Mul for Lambda {
-    type Output = Lambda;
+// the branch of a conditional its condition selects
+fn branch<'a>(c : &Lambda, e1 : &'a Lambda, e2 : &'a Lambda, env : &Env) -> Result<&'a Lambda, String> {
+    match c.eval_in(env)? {
+        Lambda::True { t : _ } => Ok (e1),
+        Lambda::False { t : _ } => Ok (e2),
+        x => Err (format!("{} is not a boolean", x))
+    }
+}
 
-    fn mul(self, rhs : Lambda) -> Lambda {
-        match self.t() {
-            Type::Integer => {
-                let tt = self.t().clone();
-                let x : i64 = self.into();
-                let y : i64 = rhs.into();
-                Lambda::IntLiteral { i : x * y, t : tt }
-            },
-            Type::Float => {
-                let tt = self.t().clone();
-                let x : f64 = self.into();
-                let y : f64 = rhs.into();
-                Lambda::FloatLiteral { f : x * y, t : tt }
-            },
-            _ => panic!()
+// the arm of a match that accepts the value of its scrutinee, with the arm's bindings in scope
+fn select<'a>(e : &Lambda, arms : &'a [(Pattern, Lambda)], env : &Env) -> Result<(&'a Lambda, Env), String> {
+    let v = e.eval_in(env)?;
+    for (p, x) in arms.iter() {
+        if let Some (bs) = bindings(p, &v) {
+            return Ok ((x, env.extend(bs)));
         }
     }
+    Err (format!("no case of the match accepts {}", v))
 }
 
-impl std::ops::Div for Lambda {
-    type Output = Lambda;
 
-    fn div(self, rhs : Lambda) -> Lambda {
-        match self.t() {
-            Type::Integer => {
-                let tt = self.t().clone();
-                let x : i64 = self.into();
-                let y : i64 = rhs.into();
-                Lambda::IntLiteral { i : x / y, t : tt }
-            },
-            Type::Float => {
-                let tt = self.t().clone();
-                let x : f64 = self.into();
-                let y : f64 = rhs.into();
-                Lambda::FloatLiteral { f : x / y, t : tt }
-            },
-            _ => panic!()
+pub fn float_literal(x : f64) -> String {
+    if x.fract() == 0.0 && x.is_finite() {
+        format!("{:.1}", x)
+    }
+    else {
+        format!("{}", x)
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            BinOp::Plus => "+",
+            BinOp::Sub => "-",
+            BinOp::Times => "*",
+            BinOp::Div => "/",
+            BinOp::Equal => "=",
+            BinOp::Less => "<",
+            BinOp::Greater => ">",
+            BinOp::LEq => "<=",
+            BinOp::GEq => ">=",
+            BinOp::NotEqual => "<>"
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl BinOp {
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinOp::Times | BinOp::Div => 7,
+            BinOp::Plus | BinOp::Sub => 6,
+            _ => 5
         }
     }
 }
 
-impl PartialEq for Lambda {
-    fn eq(&self, other: &Lambda) -> bool {
-        match self.t() {
-            Type::Integer => {
-                let x : i64 = self.into();
-                let y : i64 = other.into();
-                x == y
-            },
-            Type::Float => {
-                let x : f64 = self.into();
-                let y : f64 = other.into();
-                x == y
-            },
-            _ => panic!()
+impl Pattern {
+    fn constructor(&self) -> Option<&str> {
+        match self {
+            Pattern::Constructor (c, _) => Some (c),
+            _ => None
         }
     }
 }
 
-impl PartialOrd for Lambda {
-    fn partial_cmp(&self, other: &Lambda) -> Option<Ordering> {
-        match self.t() {
-            Type::Integer => {
-                let x : i64 = self.into();
-                let y : i64 = other.into();
-                if x < y { Some (Ordering::Less) }
-                else if x > y { Some (Ordering::Greater) }
-                else { Some (Ordering::Equal) }
-            },
-            Type::Float => {
-                let x : f64 = self.into();
-                let y : f64 = other.into();
-                if x < y { Some (Ordering::Less) }
-                else if x > y { Some (Ordering::Greater) }
-                else { Some (Ordering::Equal) }
-            },
-            _ => panic!()
+impl Lambda {
+    fn constructor(&self) -> Option<&str> {
+        match self {
+            Lambda::Construct { c, args : _, t : _ } => Some (c),
+            _ => None
+        }
+    }
+    pub fn is_atomic(&self) -> bool {
+        match self {
+            Lambda::IntLiteral { i : _, t : _ } | Lambda::FloatLiteral { f : _, t : _ } | Lambda::StrLiteral { s : _, t : _ } => true,
+            Lambda::True { t : _ } | Lambda::False { t : _ } | Lambda::Var { v : _, t : _ } | Lambda::Tuple { tup : _, t : _ } => true,
+            Lambda::Call { f : _, args : _, t : _ } | Lambda::App { lhs : _, rhs : _, t : _ } => true,
+            Lambda::List { elems : _, t : _ } | Lambda::Construct { c : _, args : _, t : _ } => true,
+            _ => false
+        }
+    }
+    // the expression as it must appear where the grammar only accepts an atom
+    pub fn atom(&self) -> String {
+        if self.is_atomic() { self.to_string() } else { format!("({})", self) }
+    }
+    fn operand(&self, prec : u8, right : bool) -> String {
+        match self {
+            Lambda::BinExpr { b, l : _, r : _, t : _ } if b.precedence() > prec || (b.precedence() == prec && !right) => self.to_string(),
+            Lambda::Index { i : _, e : _, t : _ } => self.to_string(),
+            _ => self.atom()
         }
     }
 }
 
+// a string as a literal reads it back: quotes and backslashes are escaped, as are the
+// newlines and tabs the tokenizer has escapes for
+fn escape(s : &str) -> String {
+    s.chars().map(|c| match c {
+        '"' => "\\\"".to_string(),
+        '\\' => "\\\\".to_string(),
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        c => c.to_string()
+    }).collect()
+}
 
-impl Lambda {
-    pub fn eval(&self) -> Lambda {
+impl fmt::Display for Lambda {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Lambda::IntLiteral { i : _, t : _ } => self.clone(),
-            Lambda::FloatLiteral { f : _, t : _ } => self.clone(),
-            Lambda::True { t : _ } => self.clone(),
-            Lambda::False { t : _ } => self.clone(),
-            Lambda::Var { v : _, t : _ } => self.clone(),
-            Lambda::Tuple { tup, t } => 
-                Lambda::Tuple { tup : tup.iter().map(|x| x.eval()).collect(), t : t.clone() },
-            Lambda::Index { i, e, t : _ } => {
-                match e.eval() {
-                    Lambda::Tuple { tup, t : _ } => tup[*i as usize].eval(),
-                    _ => panic!()
-                }
+            Lambda::IntLiteral { i, t : _ } => write!(f, "{}", i),
+            Lambda::FloatLiteral { f : x, t : _ } => write!(f, "{}", float_literal(*x)),
+            Lambda::StrLiteral { s, t : _ } => write!(f, "\"{}\"", escape(s)),
+            Lambda::True { t : _ } => write!(f, "true"),
+            Lambda::False { t : _ } => write!(f, "false"),
+            Lambda::Var { v, t : _ } => write!(f, "{}", v),
+            Lambda::Tuple { tup, t : _ } => {
+                let ts : Vec<String> = tup.iter().map(|x| x.to_string()).collect();
+                write!(f, "({})", ts.join(", "))
             },
-            Lambda::Abs { x : _, e : _, t : _ } => self.clone(),
-            Lambda::App { lhs, rhs, t : _ } => {
-                match lhs.eval() {
-                    Lambda::Abs { x, e, t : _ } => e.substitute(&x, rhs.eval()),
-                    _ => panic!()
+            Lambda::Index { i, e, t : _ } => write!(f, "{}.{}", e.atom(), i),
+            Lambda::Abs { x, e, t : _ } => write!(f, "fun {} => {}", x, e),
+            Lambda::App { lhs : _, rhs : _, t : _ } => {
+                // curried applications print as a single argument list
+                let mut head = self;
+                let mut xs : Vec<String> = Vec::new();
+                while let Lambda::App { lhs, rhs, t : _ } = head {
+                    xs.insert(0, rhs.to_string());
+                    head = lhs;
                 }
-            },
-            Lambda::IfExpr { c, e1, e2, t : _ } => {
-                match c.eval() {
-                    Lambda::True { t : _ } => e1.eval(),
-                    Lambda::False { t : _ } => e2.eval(),
-                    _ => panic!()
+                // a function of no arguments is applied to the empty tuple
+                if xs == ["()"] {
+                    xs.clear();
                 }
+                write!(f, "{}({})", head.atom(), xs.join(", "))
+            },
+            Lambda::IfExpr { c, e1, e2, t : _ } => write!(f, "if {} then {} else {}", c, e1, e2),
+            Lambda::BinExpr { b, l, r, t : _ } => write!(f, "{} {} {}", l.operand(b.precedence(), false), b, r.operand(b.precedence(), true)),
+            Lambda::Call { f : name, args, t : _ } => {
+                let xs : Vec<String> = args.iter().map(|x| x.to_string()).collect();
+                write!(f, "{}({})", name, xs.join(", "))
+            },
+            Lambda::List { elems, t : _ } => {
+                let xs : Vec<String> = elems.iter().map(|x| x.to_string()).collect();
+                write!(f, "[{}]", xs.join(", "))
             },
-            Lambda::BinExpr { b, l, r, t } => 
-                b.eval(t.clone(), l.eval(), r.eval())
+            Lambda::LetRec { f : name, x, e1, e2, t : _ } => write!(f, "let rec {} {} = {} in {}", name, x, e1, e2),
+            Lambda::Closure { f : Some (name), x, e, env : _, t : _ } => write!(f, "let rec {} {} = {} in {}", name, x, e, name),
+            Lambda::Closure { f : None, x, e, env : _, t : _ } => write!(f, "fun {} => {}", x, e),
+            Lambda::Construct { c, args, t : _ } if args.is_empty() => write!(f, "{}", c),
+            Lambda::Construct { c, args, t : _ } => {
+                let xs : Vec<String> = args.iter().map(|x| x.to_string()).collect();
+                write!(f, "{}({})", c, xs.join(", "))
+            },
+            Lambda::Match { e, arms, t : _ } => {
+                // a nested match would take over the cases that follow it
+                let cs : Vec<String> = arms.iter().map(|(p, x)| match x {
+                    Lambda::Match { e : _, arms : _, t : _ } => format!("{} => ({})", p, x),
+                    _ => format!("{} => {}", p, x)
+                }).collect();
+                write!(f, "match {} with {}", e, cs.join(" | "))
+            }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax;
+
+    fn expression(src : &str) -> Lambda {
+        let prog = crate::parse(&format!("let P () = val y = ({}) in end\nrun P()", src)).unwrap();
+        let syntax::Program::Prog (ref decs) = prog;
+        match &*decs[0] {
+            syntax::Declaration::Def (_, _, p) => match &**p {
+                syntax::Process::LetVal (_, l, _) => l.clone(),
+                p => panic!("unexpected process {:?}", p)
+            },
+            d => panic!("unexpected declaration {:?}", d)
+        }
+    }
+
+    #[test]
+    fn deep_recursion_is_an_error() {
+        // the simulator evaluates on the main thread, so give the test a stack of that size
+        let t = std::thread::Builder::new().stack_size(8 << 20).spawn(|| {
+            let sum = |n : i64| expression(&format!("let rec f x = if x = 0 then 0 else x + f(x - 1) in f({})", n)).eval();
+            assert_eq!(sum(100).unwrap().to_string(), "5050");
+            assert_eq!(sum(100000).unwrap_err(), "recursion too deep");
+            let through_builtins = expression("let rec f x = if x = 0 then 0 else fold(fun a => fun b => f(b), 0, [x - 1]) in f(100000)");
+            assert_eq!(through_builtins.eval().unwrap_err(), "recursion too deep");
+            assert_eq!(sum(100).unwrap().to_string(), "5050");
+        }).unwrap();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn strings_join_and_compare() {
+        let value = |src : &str| expression(src).eval().unwrap().to_string();
+        assert_eq!(value(r#""ab" + "c" + """#), r#""abc""#);
+        assert_eq!(value(r#""a\"b" + "\n""#), r#""a\"b\n""#);
+        assert_eq!(value(r#"("ab" < "b", "b" >= "ab", "x" = "x", "x" <> "y")"#), "(true, true, true, true)");
+        assert_eq!(value(r#"match ("a", 1) with (s, _) => s + "!""#), r#""a!""#);
+        assert_eq!(expression(r#""a" - "b""#).eval().unwrap_err(), r#"non-numeric operand in "a" - "b""#);
+        assert!(expression(r#""a" + 1"#).eval().is_err());
+    }
+
+    #[test]
+    fn ranges_are_bounded() {
+        assert_eq!(expression("range(2, 5)").eval().unwrap().to_string(), "[2, 3, 4]");
+        assert_eq!(expression("range(5, 2)").eval().unwrap().to_string(), "[]");
+        assert_eq!(expression("length(range(-5, 1048571))").eval().unwrap().to_string(), "1048576");
+        for r in &["range(0, 1048577)", "range(-9223372036854775807, 9223372036854775807)"] {
+            let e = expression(r).eval().unwrap_err();
+            assert!(e.starts_with("a range may have at most 1048576 elements"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn nested_patterns_bind_every_name() {
+        let name = |n : &str| Pattern::Name (n.to_string());
+        let pat = Pattern::Tuple (vec![name("x"), Pattern::Tuple (vec![Pattern::Wildcard, name("y")]), Pattern::Wildcard]);
+        let bound = |v : &str| destructure(&pat, &expression(v).eval().unwrap())
+            .map(|bs| bs.iter().map(|(n, v)| format!("{} = {}", n, v)).collect::<Vec<String>>());
+        assert_eq!(bound("(1, (2, 3), (4, 5))").unwrap(), vec!["x = 1", "y = 3"]);
+        assert_eq!(bound("((1, 2), (true, [3]), 4)").unwrap(), vec!["x = (1, 2)", "y = [3]"]);
+        assert_eq!(bound("(1, (2, 3))").unwrap_err(), "pattern (x, (_, y), _) has 3 fields but (1, (2, 3)) has 2");
+        assert_eq!(bound("(1, (2, 3, 4), 5)").unwrap_err(), "pattern (_, y) has 2 fields but (2, 3, 4) has 3");
+        assert_eq!(bound("(1, 2, 3)").unwrap_err(), "pattern (_, y) does not accept 2");
+        assert_eq!(destructure(&Pattern::Wildcard, &Lambda::from(true)).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn parameters_are_destructured_when_a_definition_is_instantiated() {
+        let prog = crate::parse("new a@1.0\n\
+            let P ((x, _), y) = val (u, (v, _)) = (x, y) in !a; P((v, u), y)\n\
+            run P((1, 2), (3, 4))").unwrap();
+        let mut sim = crate::sim::Simulator::new();
+        sim.load(&prog).unwrap();
+        let prog = crate::parse("new a@1.0\n\
+            let P ((x, _), y) = !a; P(x, y)\n\
+            run P((1, 2, 3), 4)").unwrap();
+        let e = crate::sim::Simulator::new().load(&prog).unwrap_err();
+        assert_eq!(e, "runtime error: in P: pattern (x, _) has 2 fields but (1, 2, 3) has 3");
+    }
+}
@@ -0,0 +1,141 @@
+
+use std::collections::BTreeMap;
+
+use super::crn;
+
+struct Term {
+    rate : f64,
+    factors : Vec<(usize, f64)>,
+    change : Vec<(usize, f64)>
+}
+
+impl Term {
+    fn product(&self, x : &[f64], skip : &[usize]) -> f64 {
+        self.factors.iter().enumerate()
+            .filter(|(m, _)| !skip.contains(m))
+            .fold(self.rate, |a, (_, (s, c))| a * (x[*s] - c))
+    }
+    fn value(&self, x : &[f64]) -> f64 {
+        self.product(x, &[])
+    }
+    fn gradient(&self, x : &[f64], n : usize) -> Vec<f64> {
+        let mut g = vec![0.0; n];
+        for (m, (s, _)) in self.factors.iter().enumerate() {
+            g[*s] += self.product(x, &[m]);
+        }
+        g
+    }
+    fn curvature(&self, x : &[f64], cov : &[f64], n : usize) -> f64 {
+        let mut h = 0.0;
+        for (m, (s, _)) in self.factors.iter().enumerate() {
+            for (l, (t, _)) in self.factors.iter().enumerate() {
+                if m != l {
+                    h += self.product(x, &[m, l]) * cov[s * n + t];
+                }
+            }
+        }
+        h
+    }
+}
+
+pub struct Lna {
+    n : usize,
+    terms : Vec<Term>,
+    closure : bool
+}
+
+impl Lna {
+    pub fn new(c : &crn::Crn, closure : bool) -> Lna {
+        let terms = c.reactions.iter().map(|r| Term {
+            rate : r.rate,
+            factors : r.reactants.iter().flat_map(|(s, k)| (0..*k).map(move |i| (*s, i as f64))).collect(),
+            change : c.stoichiometry(r).into_iter().enumerate().filter(|(_, d)| *d != 0).map(|(s, d)| (s, d as f64)).collect()
+        }).collect();
+        Lna { n : c.species.len(), terms : terms, closure : closure }
+    }
+    pub fn initial(&self, c : &crn::Crn) -> Vec<f64> {
+        let mut y : Vec<f64> = c.initial.iter().map(|k| *k as f64).collect();
+        y.extend(vec![0.0; self.n * self.n]);
+        y
+    }
+    pub fn derivative(&self, y : &[f64]) -> Vec<f64> {
+        let n = self.n;
+        let (x, cov) = y.split_at(n);
+        let mut dy = vec![0.0; y.len()];
+        let mut jac = vec![0.0; n * n];
+        for t in self.terms.iter() {
+            let mut a = t.value(x);
+            if self.closure {
+                a += 0.5 * t.curvature(x, cov, n);
+            }
+            let g = t.gradient(x, n);
+            for (i, di) in t.change.iter() {
+                dy[*i] += di * a;
+                for (j, dj) in t.change.iter() {
+                    dy[n + i * n + j] += di * dj * a;
+                }
+                for (k, gk) in g.iter().enumerate() {
+                    jac[i * n + k] += di * gk;
+                }
+            }
+        }
+        for i in 0..n {
+            for j in 0..n {
+                let mut d = 0.0;
+                for k in 0..n {
+                    d += jac[i * n + k] * cov[k * n + j] + cov[i * n + k] * jac[j * n + k];
+                }
+                dy[n + i * n + j] += d;
+            }
+        }
+        dy
+    }
+    pub fn moments(&self, c : &crn::Crn, y : &[f64]) -> BTreeMap<String, f64> {
+        let n = self.n;
+        let mut m = c.mean(&y[..n]);
+        let mut var : BTreeMap<String, f64> = c.defs.iter().map(|d| (d.clone(), 0.0)).collect();
+        for i in 0..n {
+            for j in 0..n {
+                if c.species[i].def == c.species[j].def {
+                    *var.entry(c.species[i].def.clone()).or_insert(0.0) += y[n + i * n + j];
+                }
+            }
+        }
+        for (d, v) in var {
+            m.insert(format!("{}_sd", d), v.max(0.0).sqrt());
+        }
+        m
+    }
+}
+
+pub fn schema(c : &crn::Crn) -> Vec<String> {
+    c.observables().into_iter().flat_map(|o| vec![o.clone(), format!("{}_sd", o)]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ode;
+    use crate::crn::tests::{two_state, switched};
+
+    #[test]
+    fn linear_noise_matches_the_binomial_moments() {
+        // every molecule switches independently, so B is binomial and, the network being
+        // first order, both the linear noise approximation and the closure are exact
+        let c = two_state(50);
+        for closure in &[false, true] {
+            let l = Lna::new(&c, *closure);
+            let mut solver = ode::Solver::new(|y : &[f64]| l.derivative(y), l.initial(&c));
+            for t in &[0.2, 1.0, 3.0] {
+                solver.advance(*t).unwrap();
+                let m = l.moments(&c, &solver.y);
+                let q = switched(*t);
+                assert!((m["B"] - 50.0 * q).abs() < 1e-4, "mean {} at time {}", m["B"], t);
+                assert!((m["B_sd"] - (50.0 * q * (1.0 - q)).sqrt()).abs() < 1e-4, "sd {} at time {}", m["B_sd"], t);
+                assert!((m["A_sd"] - m["B_sd"]).abs() < 1e-4);
+                assert!(m["E_sd"].abs() < 1e-9);
+            }
+        }
+        assert_eq!(schema(&c), vec!["A", "A_sd", "B", "B_sd", "E", "E_sd"]);
+    }
+}
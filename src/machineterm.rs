@@ -1,9 +1,10 @@
 use std::rc::Rc;
 use std::collections::BTreeMap;
 use super::ast;
+use super::lambda::*;
 
 #[derive(Debug)]
-pub struct Summ (pub Option<String>, pub Rc<Vec<(ast::Act, Rc<ast::Process>)>>);
+pub struct Summ (pub Option<(String, Vec<Lambda>)>, pub Rc<Vec<(ast::Act, Rc<ast::Process>)>>, pub Env);
 
 #[derive(Debug)]
 pub enum MachineTerm {
@@ -11,7 +12,18 @@ pub enum MachineTerm {
     SummList (Vec<Rc<Summ>>)
 }
 
+pub fn label(name : &str, params : &[Lambda]) -> String {
+    let ps : Vec<String> = params.iter().map(|p| p.to_string()).collect();
+    format!("{}({})", name, ps.join(",")).split_whitespace().collect()
+}
+
 impl Summ {
+    pub fn label(&self) -> String {
+        match self.0 {
+            Some ((ref name, ref params)) => label(name, params),
+            None => "_".to_string()
+        }
+    }
     pub fn index(&self, i : usize) -> (ast::Act, Rc<ast::Process>) {
         self.1[i].clone()
     }
@@ -20,10 +32,10 @@ impl Summ {
         for (a, _p) in self.1.iter() {
             match a {
                 ast::Act::Input (c) => {
-                    counts.entry(c).or_insert((0, 0, 0)).0 += 1;
+                    counts.entry(self.2.channel(c)).or_insert((0, 0, 0)).0 += 1;
                 },
                 ast::Act::Output (c) => {
-                    counts.entry(c).or_insert((0, 0, 0)).1 += 1;
+                    counts.entry(self.2.channel(c)).or_insert((0, 0, 0)).1 += 1;
                 },
             }
         }
@@ -60,7 +72,7 @@ impl MachineTerm {
                         match (sl[i].1[j].0.clone(), elem.clone()) {
                             (ast::Act::Input (n1), ast::Act::Input (n2)) |
                             (ast::Act::Output (n1), ast::Act::Output (n2)) => {
-                                if n1 == n2 {
+                                if sl[i].2.channel(&n1) == n2 {
                                     if c == 0 {
                                         // println!("located");
                                         return (i, j);
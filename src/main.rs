@@ -9,7 +9,9 @@ extern crate diff_enum;
 extern crate lazy_static;
 
 use std::fs;
+use std::collections::BTreeMap;
 use combine::Parser;
+use combine::stream::state::State;
 use structopt::StructOpt;
 
 mod symgen;
@@ -22,47 +24,381 @@ mod parser;
 mod machineterm;
 mod store;
 mod sim;
+mod output;
+mod trace;
+mod rng;
+mod checkpoint;
+mod crn;
+mod xml;
+mod sbml;
+mod prism;
+mod ctmc;
+mod csl;
+mod ode;
+mod lna;
+mod fsp;
+mod invariants;
+mod dot;
+mod datatypes;
+
+#[derive(StructOpt)]
+enum Command {
+    /// Checks CSL properties on the explicit CTMC of a model
+    #[structopt(name = "check-property")]
+    CheckProperty {
+        #[structopt(parse(from_os_str))]
+        inpath: std::path::PathBuf,
+        #[structopt(raw(required = "true"))]
+        properties: Vec<String>,
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        outpath: std::path::PathBuf,
+        #[structopt(long = "max-species", default_value = "10000")]
+        max_species: usize,
+        #[structopt(long = "max-states", default_value = "100000")]
+        max_states: usize,
+    },
+    /// Reports the conservation laws and T-invariants of a model's reaction network
+    #[structopt(name = "analyze")]
+    Analyze {
+        #[structopt(parse(from_os_str))]
+        inpath: std::path::PathBuf,
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        outpath: std::path::PathBuf,
+        #[structopt(long = "max-species", default_value = "10000")]
+        max_species: usize,
+    },
+    /// Rewrites a model in canonical layout, or prints it instead with --stdout
+    #[structopt(name = "fmt")]
+    Fmt {
+        #[structopt(parse(from_os_str))]
+        inpath: std::path::PathBuf,
+        #[structopt(long = "stdout")]
+        stdout: bool,
+    },
+}
 
 #[derive(StructOpt)]
 struct Cli {
+    #[structopt(subcommand)]
+    command: Option<Command>,
     #[structopt(parse(from_os_str))]
-    inpath: std::path::PathBuf,
+    inpath: Option<std::path::PathBuf>,
     #[structopt(short = "o", long = "output", parse(from_os_str))]
-    outpath: std::path::PathBuf,
+    outpath: Option<std::path::PathBuf>,
+    #[structopt(long = "trace", parse(from_os_str))]
+    trace: Option<std::path::PathBuf>,
+    #[structopt(long = "replay", parse(from_os_str))]
+    replay: Option<std::path::PathBuf>,
+    #[structopt(long = "steps", default_value = "1000000")]
+    steps: usize,
+    #[structopt(long = "checkpoint", parse(from_os_str))]
+    checkpoint: Option<std::path::PathBuf>,
+    #[structopt(long = "resume", parse(from_os_str))]
+    resume: Option<std::path::PathBuf>,
+    #[structopt(long = "export")]
+    export: Option<String>,
+    #[structopt(long = "max-species", default_value = "10000")]
+    max_species: usize,
+    #[structopt(long = "import")]
+    import: Option<String>,
+    #[structopt(long = "transient")]
+    transient: Option<String>,
+    #[structopt(long = "max-states", default_value = "100000")]
+    max_states: usize,
+    #[structopt(long = "steady-state")]
+    steady_state: bool,
+    #[structopt(long = "ode")]
+    ode: bool,
+    #[structopt(long = "fsp")]
+    fsp: Option<String>,
+    #[structopt(long = "tolerance", default_value = "0.0001")]
+    tolerance: f64,
+    #[structopt(long = "definitions")]
+    definitions: Option<String>,
+    #[structopt(long = "check-invariants")]
+    check_invariants: bool,
+    #[structopt(long = "lna")]
+    lna: bool,
+    #[structopt(long = "moment-closure")]
+    moment_closure: bool,
+    #[structopt(long = "until", default_value = "10.0")]
+    until: f64,
+    #[structopt(long = "interval", default_value = "0.1")]
+    interval: f64,
+}
+
+impl Cli {
+    fn output(&self) -> Result<&std::path::PathBuf, String> {
+        match self.outpath {
+            Some (ref p) => Ok (p),
+            None => Err ("an output path (-o) is required".to_string())
+        }
+    }
+}
+
+fn parse(src : &str) -> Result<syntax::Program, String> {
+    let (spanned, rest) = match tokenizer::tokenize_spanned().easy_parse(State::new(src)) {
+        Ok (r) => r,
+        Err (e) => return Err (e.to_string())
+    };
+    if !rest.input.is_empty() {
+        return Err (format!("line {}, column {}: unexpected character {:?}",
+            rest.positioner.line, rest.positioner.column, rest.input.chars().next().unwrap()));
+    }
+    let (spans, toks) : (Vec<_>, Vec<_>) = spanned.into_iter().unzip();
+    let locate = |i : usize| match spans.get(i) {
+        Some (p) => format!("line {}, column {}: unexpected {:?}", p.line, p.column, toks[i]),
+        None => "unexpected end of input".to_string()
+    };
+    match parser::program().easy_parse(toks.as_slice()) {
+        Ok ((p, rest)) if rest.is_empty() => Ok (p),
+        Ok ((_, rest)) => Err (locate(toks.len() - rest.len())),
+        Err (e) => Err (locate(e.position.translate_position(toks.as_slice()) / std::mem::size_of::<tokenizer::Token>()))
+    }
+}
+
+fn times(s : &str) -> Result<Vec<f64>, String> {
+    let mut ts = s.split(',')
+        .map(|t| t.trim().parse::<f64>().map_err(|_| format!("malformed time {}", t)))
+        .collect::<Result<Vec<f64>, String>>()?;
+    ts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    Ok (ts)
+}
+
+fn grid(args : &Cli) -> Vec<f64> {
+    let n = (args.until / args.interval).ceil() as usize;
+    (1..=n).map(|i| (i as f64 * args.interval).min(args.until)).collect()
+}
+
+fn explicit(prog : &syntax::Program, max_species : usize, max_states : usize) -> Result<ctmc::Ctmc, String> {
+    crn::Crn::from_program(prog, max_species).and_then(|c| ctmc::Ctmc::explore(c, max_states))
+}
+
+fn property(s : &str) -> Result<csl::Property, String> {
+    let toks = match tokenizer::tokenize().parse(combine::easy::Stream(s)) {
+        Ok ((t, rest)) if rest.0.is_empty() => t,
+        Ok ((_, rest)) => return Err (format!("malformed property {}: unexpected character {:?}", s, rest.0.chars().next().unwrap())),
+        Err (e) => return Err (format!("malformed property {}: {:?}", s, e))
+    };
+    match parser::property().parse(toks.as_slice()) {
+        Ok ((p, rest)) if rest.is_empty() => Ok (p),
+        Ok ((_, rest)) => Err (format!("malformed property {}: unexpected {:?}", s, rest[0])),
+        Err (e) => Err (format!("malformed property {}: {:?}", s, e))
+    }
+}
+
+fn conservation_laws(prog : &syntax::Program, args : &Cli) -> Result<Vec<(BTreeMap<String, i64>, i64)>, String> {
+    let c = crn::Crn::from_program(prog, args.max_species)?;
+    Ok (invariants::Invariants::new(&c)?.p.iter()
+        .filter_map(|y| invariants::Invariants::by_definition(&c, y).map(|law| (law, invariants::Invariants::total(&c, y))))
+        .collect())
+}
+
+fn read(filename : &std::path::Path) -> Result<String, String> {
+    fs::read_to_string(filename).map_err(|e| format!("could not read {}: {}", filename.display(), e))
+}
+
+fn write(filename : &std::path::Path, text : String) -> Result<(), String> {
+    fs::write(filename, text).map_err(|e| format!("could not write {}: {}", filename.display(), e))
+}
+
+fn load(filename : &std::path::Path) -> Result<syntax::Program, String> {
+    check(filename, &read(filename)?)
+}
+
+fn check(filename : &std::path::Path, text : &str) -> Result<syntax::Program, String> {
+    let prog = parse(text).map_err(|e| format!("could not parse {}: {}", filename.display(), e))?;
+    datatypes::check(&prog).map_err(|e| format!("{} is not well formed: {}", filename.display(), e))
+}
+
+fn check_property(filename : &std::path::Path, props : &[String], outpath : &std::path::Path, max_species : usize, max_states : usize) -> Result<(), String> {
+    let props = props.iter().map(|s| property(s)).collect::<Result<Vec<csl::Property>, String>>()?;
+    let m = explicit(&load(filename)?, max_species, max_states)?;
+    let mut wtr = csv::Writer::from_path(outpath).unwrap();
+    wtr.write_record(&["Property", "Probability", "Holds"]).unwrap();
+    for p in props.iter() {
+        let (holds, x) = csl::check(&m, p).map_err(|e| format!("{}: {}", p, e))?;
+        println!("{}: {} (probability {})", p, holds, x);
+        wtr.write_record(&[p.to_string(), x.to_string(), holds.to_string()]).unwrap();
+    }
+    Ok (())
+}
+
+fn analyze(filename : &std::path::Path, outpath : &std::path::Path, max_species : usize) -> Result<(), String> {
+    let c = crn::Crn::from_program(&load(filename)?, max_species)?;
+    write(outpath, invariants::report(&c)?)
 }
 
+fn format(filename : &std::path::Path, stdout : bool) -> Result<(), String> {
+    let f = read(filename)?;
+    let text = check(filename, &f)?.to_string();
+    match check(filename, &text) {
+        Ok (p) if p.to_string() == text => (),
+        _ => return Err (format!("the formatted program does not read back the same; {} was left unchanged", filename.display()))
+    }
+    let comment = tokenizer::first_comment(&f);
+    if stdout {
+        if let Some ((line, column)) = comment {
+            eprintln!("warning: the comment at line {}, column {} of {} is not kept", line, column, filename.display());
+        }
+        print!("{}", text);
+        Ok (())
+    }
+    else if let Some ((line, column)) = comment {
+        Err (format!("{} has a comment at line {}, column {}, which fmt cannot keep; the file was left unchanged",
+            filename.display(), line, column))
+    }
+    else {
+        write(filename, text)
+    }
+}
+
+// errors in the model or its inputs are reported without a backtrace; they come back
+// through run, so every output file it opened has been flushed by the time they are shown
 fn main() {
-    let args = Cli::from_args();
-    let filename = args.inpath; // "test.spi";
-    let f = fs::read_to_string(filename)
-        .expect("Something went wrong reading the file");
-    let g : &str = &f;
-    let mut s = combine::easy::Stream(g);
-    let p = tokenizer::tokenize().parse_stream(&mut s);
-    println!("{:?}", p);
-    let z = parser::program().parse_stream(&mut (p.unwrap().0.as_slice()));
-    println!("{:?}", z);
-    let prog = match z {
-        Ok ((p, c)) => {
-            println!("{:?} {:?}", p, c);
-            p
-        },
-        Err (e) => panic!("{:?}", e)
+    if let Err (e) = run(Cli::from_args()) {
+        eprintln!("error: {}", e);
+        std::process::exit(1)
+    }
+}
+
+fn run(args : Cli) -> Result<(), String> {
+    match args.command {
+        Some (Command::CheckProperty { ref inpath, ref properties, ref outpath, max_species, max_states }) =>
+            return check_property(inpath, properties, outpath, max_species, max_states),
+        Some (Command::Analyze { ref inpath, ref outpath, max_species }) =>
+            return analyze(inpath, outpath, max_species),
+        Some (Command::Fmt { ref inpath, stdout }) =>
+            return format(inpath, stdout),
+        None => ()
+    }
+    let filename = match args.inpath {
+        Some (ref p) => p,
+        None => return Err ("an input file or a subcommand is required".to_string())
     };
+    if let Some (ref format) = args.import {
+        let f = read(filename)?;
+        let prog = match format.as_str() {
+            "sbml" => xml::parse(&f).and_then(|d| sbml::import(&d))?,
+            _ => return Err (format!("unknown import format {}", format))
+        };
+        return write(args.output()?, prog.to_string());
+    }
+    let prog = load(filename)?;
+    if let Some (ref format) = args.export {
+        let out = match format.as_str() {
+            "crn" => crn::Crn::from_program(&prog, args.max_species).map(|c| c.to_string()),
+            "sbml" => crn::Crn::from_program(&prog, args.max_species).map(|c| sbml::export(&c).to_string()),
+            "prism" => crn::Crn::from_program(&prog, args.max_species).and_then(|c| prism::export(&c)),
+            "dot-automata" => Ok (dot::automata(&prog)),
+            "dot-interactions" => Ok (dot::interactions(&prog)),
+            _ => Err (format!("unknown export format {}", format))
+        };
+        return write(args.output()?, out?);
+    }
+    if let Some (ref ts) = args.transient {
+        let m = explicit(&prog, args.max_species, args.max_states)?;
+        let mut wtr = csv::Writer::from_path(args.output()?).unwrap();
+        wtr.write_record(&["Time", "Observable", "Count", "Probability"]).unwrap();
+        let mut p = m.initial();
+        let mut now = 0.0;
+        for t in times(ts)? {
+            p = m.transient(&p, t - now, 1e-10);
+            now = t;
+            ctmc::write_distributions(&mut wtr, t, &m.marginals(&p));
+        }
+        return Ok (());
+    }
+    if let Some (ref ts) = args.fsp {
+        let c = crn::Crn::from_program(&prog, args.max_species)?;
+        let ts = times(ts)?;
+        let selected : Option<Vec<&str>> = args.definitions.as_ref().map(|d| d.split(',').map(|s| s.trim()).collect());
+        let mut f = fsp::Fsp::new(c, args.tolerance, *ts.last().unwrap_or(&1.0), args.max_states);
+        let mut wtr = csv::Writer::from_path(args.output()?).unwrap();
+        wtr.write_record(&["Time", "Observable", "Count", "Probability"]).unwrap();
+        for t in ts {
+            f.advance(t)?;
+            println!("t = {}: {} states, error bound {}", t, f.m.len(), f.leak());
+            let mut m = f.m.marginals(&f.p);
+            if let Some (ref sel) = selected {
+                m.retain(|o, _| sel.contains(&o.as_str()));
+            }
+            ctmc::write_distributions(&mut wtr, t, &m);
+        }
+        return Ok (());
+    }
+    if args.steady_state {
+        let m = explicit(&prog, args.max_species, args.max_states)?;
+        let ss = m.steady_state(1e-12, 1000000)?;
+        println!("{} reachable states, {} bottom strongly connected components", m.len(), ss.bsccs.len());
+        for (w, b) in ss.bsccs.iter() {
+            println!("  {} states reached with probability {}", b.len(), w);
+        }
+        let mut wtr = csv::Writer::from_path(args.output()?).unwrap();
+        wtr.write_record(&["Observable", "Expected"]).unwrap();
+        for (o, e) in m.expectations(&ss.dist) {
+            wtr.write_record(&[o, e.to_string()]).unwrap();
+        }
+        return Ok (());
+    }
+    if args.ode {
+        let c = crn::Crn::from_program(&prog, args.max_species)?;
+        let mut wtr = output::Recorder::new(args.output()?, c.observables());
+        let mut solver = ode::Solver::new(ode::mean_field(&c), c.initial.iter().map(|n| *n as f64).collect());
+        wtr.record(solver.time, &c.mean(&solver.y));
+        for t in grid(&args) {
+            solver.advance(t)?;
+            wtr.record(solver.time, &c.mean(&solver.y));
+        }
+        return Ok (());
+    }
+    if args.lna || args.moment_closure {
+        let c = crn::Crn::from_program(&prog, args.max_species)?;
+        let l = lna::Lna::new(&c, args.moment_closure);
+        let mut wtr = output::Recorder::new(args.output()?, lna::schema(&c));
+        let mut solver = ode::Solver::new(|y : &[f64]| l.derivative(y), l.initial(&c));
+        wtr.record(solver.time, &l.moments(&c, &solver.y));
+        for t in grid(&args) {
+            solver.advance(t)?;
+            wtr.record(solver.time, &l.moments(&c, &solver.y));
+        }
+        return Ok (());
+    }
+    let laws = if args.check_invariants { conservation_laws(&prog, &args)? } else { Vec::new() };
     let mut sim = sim::Simulator::new();
-    sim.load(&prog);
-    let mut wtr = csv::Writer::from_path(args.outpath).unwrap();
-    let mut headers : Vec<String> = sim.s.instance_counts.iter().map(|(k, _v)| k.to_string()).collect();
-    headers.insert(0, "Time".to_string());
-    wtr.write_record(headers).unwrap();
-    let mut c : Vec<String> = sim.s.instance_counts.iter().map(|(_k, v)| v.to_string()).collect();
-    c.insert(0, sim.time.to_string());
-    wtr.write_record(c).unwrap();
-    for _i in 0..1000000 {
-        sim.reduce();
-        let mut c1 : Vec<String> = sim.s.instance_counts.iter().map(|(_k, v)| v.to_string()).collect();
-        c1.insert(0, sim.time.to_string());
-        wtr.write_record(c1).unwrap();
+    sim.load(&prog)?;
+    if let Some (ref path) = args.resume {
+        sim.resume(path).map_err(|e| format!("could not resume from {}: {}", path.display(), e))?;
+    }
+    let mut wtr = output::Recorder::new(args.output()?, sim.s.observables());
+    wtr.record(sim.time, &sim.s.instance_counts);
+    if let Some (ref path) = args.replay {
+        let events = trace::read(path)?;
+        for (n, e) in events.iter().enumerate() {
+            sim.replay(e).map_err(|msg| format!("replay diverged at event {}: {}", n + 1, msg))?;
+            wtr.record(sim.time, &sim.s.instance_counts);
+        }
+        return Ok (());
     }
+    let mut log = args.trace.as_ref().map(|p| trace::Log::new(p));
+    for _i in 0..args.steps {
+        if sim.is_stuck() {
+            break;
+        }
+        let e = sim.reduce().map_err(|e| format!("at time {}: {}", sim.time, e))?;
+        if let (Some (ref mut l), Some (ref e)) = (&mut log, e) {
+            l.write(e);
+        }
+        wtr.record(sim.time, &sim.s.instance_counts);
+        for (law, total) in laws.iter() {
+            let n : i64 = law.iter().map(|(d, a)| a * *sim.s.instance_counts.get(d).unwrap_or(&0) as i64).sum();
+            if n != *total {
+                return Err (format!("conservation law {:?} = {} is violated at time {}: the instance counts give {}", law, total, sim.time, n));
+            }
+        }
+    }
+    if let Some (ref path) = args.checkpoint {
+        sim.save(path).map_err(|e| format!("could not write checkpoint {}: {}", path.display(), e))?;
+    }
+    Ok (())
 }
-
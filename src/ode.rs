@@ -0,0 +1,135 @@
+
+use super::crn;
+
+const RTOL : f64 = 1e-6;
+const ATOL : f64 = 1e-9;
+const MAX_STEPS : usize = 10000000;
+
+// Dormand-Prince 5(4) tableau; the systems solved here do not depend on time, so the stage
+// times are not needed
+const A : [[f64; 6]; 7] = [
+    [0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+    [1.0 / 5.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+    [3.0 / 40.0, 9.0 / 40.0, 0.0, 0.0, 0.0, 0.0],
+    [44.0 / 45.0, -56.0 / 15.0, 32.0 / 9.0, 0.0, 0.0, 0.0],
+    [19372.0 / 6561.0, -25360.0 / 2187.0, 64448.0 / 6561.0, -212.0 / 729.0, 0.0, 0.0],
+    [9017.0 / 3168.0, -355.0 / 33.0, 46732.0 / 5247.0, 49.0 / 176.0, -5103.0 / 18656.0, 0.0],
+    [35.0 / 384.0, 0.0, 500.0 / 1113.0, 125.0 / 192.0, -2187.0 / 6784.0, 11.0 / 84.0]
+];
+const B5 : [f64; 7] = [35.0 / 384.0, 0.0, 500.0 / 1113.0, 125.0 / 192.0, -2187.0 / 6784.0, 11.0 / 84.0, 0.0];
+const B4 : [f64; 7] = [5179.0 / 57600.0, 0.0, 7571.0 / 16695.0, 393.0 / 640.0, -92097.0 / 339200.0, 187.0 / 2100.0, 1.0 / 40.0];
+
+pub struct Solver<F : Fn(&[f64]) -> Vec<f64>> {
+    pub f : F,
+    pub y : Vec<f64>,
+    pub time : f64,
+    h : f64
+}
+
+impl<F : Fn(&[f64]) -> Vec<f64>> Solver<F> {
+    pub fn new(f : F, y : Vec<f64>) -> Solver<F> {
+        Solver { f : f, y : y, time : 0.0, h : 1e-3 }
+    }
+    fn stage(&self, k : &[Vec<f64>], i : usize, h : f64) -> Vec<f64> {
+        let mut y = self.y.clone();
+        for (j, kj) in k.iter().enumerate().take(i) {
+            if A[i][j] != 0.0 {
+                for (yy, d) in y.iter_mut().zip(kj.iter()) {
+                    *yy += h * A[i][j] * d;
+                }
+            }
+        }
+        y
+    }
+    pub fn advance(&mut self, t : f64) -> Result<(), String> {
+        let mut steps = 0;
+        while self.time < t {
+            steps += 1;
+            if steps > MAX_STEPS {
+                return Err (format!("the solver took more than {} steps before time {}", MAX_STEPS, t));
+            }
+            let h = self.h.min(t - self.time);
+            let mut k : Vec<Vec<f64>> = Vec::with_capacity(7);
+            for i in 0..7 {
+                let y = self.stage(&k, i, h);
+                k.push((self.f)(&y));
+            }
+            let mut err : f64 = 0.0;
+            let mut next = self.y.clone();
+            for n in 0..self.y.len() {
+                let mut hi = self.y[n];
+                let mut lo = self.y[n];
+                for i in 0..7 {
+                    hi += h * B5[i] * k[i][n];
+                    lo += h * B4[i] * k[i][n];
+                }
+                let scale = ATOL + RTOL * self.y[n].abs().max(hi.abs());
+                err = err.max(((hi - lo) / scale).abs());
+                next[n] = hi;
+            }
+            if !err.is_finite() {
+                return Err (format!("the solution diverged at time {}", self.time));
+            }
+            let factor = if err == 0.0 { 5.0 } else { (0.9 * err.powf(-0.2)).max(0.2).min(5.0) };
+            if err <= 1.0 {
+                self.time += h;
+                self.y = next;
+                if h < self.h && self.time >= t {
+                    // a step clipped to the output time says nothing about the step size
+                    break;
+                }
+            }
+            self.h = h * factor;
+            if self.h < 1e-14 * self.time.max(1.0) {
+                return Err (format!("the step size underflowed at time {}", self.time));
+            }
+        }
+        self.time = t;
+        Ok (())
+    }
+}
+
+pub fn mean_field(c : &crn::Crn) -> impl Fn(&[f64]) -> Vec<f64> + '_ {
+    let stoich : Vec<Vec<i64>> = c.reactions.iter().map(|r| c.stoichiometry(r)).collect();
+    move |x : &[f64]| {
+        let mut dx = vec![0.0; x.len()];
+        for (r, d) in c.reactions.iter().zip(stoich.iter()) {
+            let a = c.flux(r, x);
+            if a == 0.0 {
+                continue;
+            }
+            for (s, n) in d.iter().enumerate() {
+                dx[s] += *n as f64 * a;
+            }
+        }
+        dx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crn::tests::{two_state, switched};
+
+    #[test]
+    fn mean_field_follows_the_exact_mean() {
+        // the switches are first order, so the mean-field equations hold for the mean exactly
+        let c = two_state(100);
+        let mut solver = Solver::new(mean_field(&c), c.initial.iter().map(|n| *n as f64).collect());
+        for t in &[0.1, 0.5, 1.0, 5.0] {
+            solver.advance(*t).unwrap();
+            assert_eq!(solver.time, *t);
+            let m = c.mean(&solver.y);
+            assert!((m["B"] - 100.0 * switched(*t)).abs() < 1e-4, "{} at time {}", m["B"], t);
+            assert!((m["A"] + m["B"] - 100.0).abs() < 1e-6);
+            assert!((m["E"] - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn exponential_decay() {
+        let mut solver = Solver::new(|y : &[f64]| vec![-y[0]], vec![1.0]);
+        solver.advance(3.0).unwrap();
+        assert!((solver.y[0] - (-3.0f64).exp()).abs() < 1e-6);
+    }
+}
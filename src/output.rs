@@ -0,0 +1,60 @@
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+pub struct Recorder {
+    wtr : csv::Writer<File>,
+    schema : Vec<String>
+}
+
+impl Recorder {
+    pub fn new<P : AsRef<Path>>(path : P, schema : Vec<String>) -> Recorder {
+        let mut wtr = csv::Writer::from_path(path).unwrap();
+        let mut headers = schema.clone();
+        headers.insert(0, "Time".to_string());
+        wtr.write_record(headers).unwrap();
+        Recorder { wtr : wtr, schema : schema }
+    }
+    pub fn record<T : ToString>(&mut self, time : f64, values : &BTreeMap<String, T>) {
+        let mut row : Vec<String> = self.schema.iter().map(|k| {
+            match values.get(k) {
+                Some (v) => v.to_string(),
+                None => "0".to_string()
+            }
+        }).collect();
+        row.insert(0, time.to_string());
+        self.wtr.write_record(row).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+    use crate::sim;
+
+    #[test]
+    fn columns_stay_fixed_when_definitions_first_appear_mid_run() {
+        let prog = crate::parse("new a@1.0\n\
+            let A () = !a; B()\n\
+            let B () = end\n\
+            let C () = ?a; C()\n\
+            run (A() | C())").unwrap();
+        let path = std::env::temp_dir().join(format!("spi-recorder-{}.csv", std::process::id()));
+        let mut sim = sim::Simulator::new();
+        sim.load(&prog).unwrap();
+        let mut wtr = Recorder::new(&path, sim.s.observables());
+        wtr.record(sim.time, &sim.s.instance_counts);
+        sim.reduce().unwrap();
+        wtr.record(sim.time, &sim.s.instance_counts);
+        drop(wtr);
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let rows : Vec<Vec<&str>> = text.lines().map(|l| l.split(',').collect()).collect();
+        assert_eq!(rows[0], vec!["Time", "A", "B", "C"]);
+        assert_eq!(rows[1], vec!["0", "1", "0", "1"]);
+        assert_eq!(rows[2][1..], ["0", "1", "1"]);
+        assert!(rows[2][0].parse::<f64>().unwrap() > 0.0);
+    }
+}
@@ -1,11 +1,12 @@
 use std::rc::Rc;
-use combine::{Stream, Parser, parser, many1, between, sep_by};
+use combine::{Stream, Parser, parser, many1, between, sep_by, optional, chainl1, many, sep_by1};
+use combine::parser::item::satisfy_map;
 use combine::error::{ParseError};
-use combine_language;
 
 use super::tokenizer;
 use super::tokenizer::{Token, Keyword};
 use super::syntax;
+use super::csl;
 use super::values::*;
 use super::lambda::*;
 
@@ -16,9 +17,16 @@ where I: Stream<Item = Token>,
       I: combine::RangeStreamOnce
 {
     let wildcard = tokenizer::underscore().map(|_| Pattern::Wildcard);
-    let name = tokenizer::ident().map(|n| Pattern::Name (n));
+    let name = tokenizer::ident()
+        .and(optional(between(tokenizer::lpar(), tokenizer::rpar(), sep_by(pattern(), tokenizer::comma()))))
+        .map(|(n, args) : (String, Option<Vec<Pattern>>)| match args {
+            Some (pl) => Pattern::Constructor (n, pl),
+            None if is_constructor(&n) => Pattern::Constructor (n, Vec::new()),
+            None => Pattern::Name (n)
+        });
+    // as with expressions, parentheses around a single pattern only group it
     let tuple = between(tokenizer::lpar(), tokenizer::rpar(), sep_by(pattern(), tokenizer::comma()))
-        .map(|v : Vec<Pattern>| Pattern::Tuple (v));
+        .map(|mut v : Vec<Pattern>| if v.len() == 1 { v.remove(0) } else { Pattern::Tuple (v) });
 
     wildcard.or(name).or(tuple)
 }
@@ -33,6 +41,16 @@ where [I: Stream<Item = Token>,
 }
 }
 
+// a name from the built-in table calls the built-in, anything else applies one argument at a time
+pub fn application(f : Lambda, args : Vec<Lambda>) -> Lambda {
+    match f {
+        Lambda::Construct { c, args : none, t } if none.is_empty() => Lambda::Construct { c : c, args : args, t : t },
+        Lambda::Var { v, t : _ } if is_builtin(&v) => Lambda::Call { f : v, args : args, t : Type::TVar },
+        f if args.is_empty() => Lambda::App { lhs : Rc::new(f), rhs : Rc::new(Lambda::Tuple { tup : Vec::new(), t : Type::Tuple }), t : Type::TVar },
+        f => args.into_iter().fold(f, |g, x| Lambda::App { lhs : Rc::new(g), rhs : Rc::new(x), t : Type::TVar })
+    }
+}
+
 fn lambda_<I>() -> impl Parser<Input = I, Output = Lambda>
 where I: Stream<Item = Token>,
       I::Error: ParseError<I::Item, I::Range, I::Position>,
@@ -41,9 +59,18 @@ where I: Stream<Item = Token>,
 {
     let int = tokenizer::integer().map(|i| Lambda::IntLiteral { i : i , t : Type::Integer });
     let flt = tokenizer::float().map(|f| Lambda::FloatLiteral { f : f, t : Type::Float });
+    let st = tokenizer::strlit().map(|s| Lambda::StrLiteral { s : s, t : Type::Str });
     let bt = tokenizer::keyword(Keyword::True).map(|_| Lambda::True {t : Type::Bool } );
     let bf = tokenizer::keyword(Keyword::False).map(|_| Lambda::False {t : Type::Bool });
-    let var = tokenizer::ident().map(|n| Lambda::Var { v : n, t : Type::TVar });
+    let var = tokenizer::ident().map(|n| if is_constructor(&n) {
+        Lambda::Construct { c : n, args : Vec::new(), t : Type::TVar }
+    }
+    else {
+        Lambda::Var { v : n, t : Type::TVar }
+    });
+    let neg = satisfy_map(|t : Token| if t == Token::Dash { Some (()) } else { None })
+        .with(tokenizer::integer().map(|i| Lambda::IntLiteral { i : -i, t : Type::Integer })
+            .or(tokenizer::float().map(|f| Lambda::FloatLiteral { f : -f, t : Type::Float })));
 
     let fun = tokenizer::keyword(Keyword::Fun)
         .with(tokenizer::ident())
@@ -58,9 +85,32 @@ where I: Stream<Item = Token>,
         .skip(tokenizer::keyword(Keyword::Else))
         .and(expr())
         .map(|((e1, e2), e3)| Lambda::IfExpr { c : Rc::new(e1), e1 : Rc::new(e2), e2 : Rc::new(e3), t : Type::TVar });
-    let paren = between(tokenizer::lpar(), tokenizer::rpar(), expr());
+    let letrec = tokenizer::keyword(Keyword::Let)
+        .skip(tokenizer::keyword(Keyword::Rec))
+        .with(tokenizer::ident())
+        .and(many1(tokenizer::ident()))
+        .skip(tokenizer::equals())
+        .and(expr())
+        .skip(tokenizer::keyword(Keyword::In))
+        .and(expr())
+        .map(|(((f, xs), e1), e2) : (((String, Vec<String>), Lambda), Lambda)| {
+            let body = xs[1..].iter().rev().fold(e1, |e, x| Lambda::Abs { x : x.clone(), e : Rc::new(e), t : Type::TVar });
+            Lambda::LetRec { f : f, x : xs[0].clone(), e1 : Rc::new(body), e2 : Rc::new(e2), t : Type::TVar }
+        });
+    let matchexpr = tokenizer::keyword(Keyword::Match)
+        .with(expr())
+        .skip(tokenizer::keyword(Keyword::With))
+        .skip(optional(tokenizer::pipe()))
+        .and(sep_by1(pattern().skip(tokenizer::rightarrow()).and(expr()), tokenizer::pipe()))
+        .map(|(e, arms)| Lambda::Match { e : Rc::new(e), arms : arms, t : Type::TVar });
+    let list = between(tokenizer::lbracket(), tokenizer::rbracket(), sep_by(expr(), tokenizer::comma()))
+        .map(|elems| Lambda::List { elems : elems, t : Type::List });
+    let paren = between(tokenizer::lpar(), tokenizer::rpar(), sep_by(expr(), tokenizer::comma()))
+        .map(|mut tup : Vec<Lambda>| if tup.len() == 1 { tup.remove(0) } else { Lambda::Tuple { tup : tup, t : Type::Tuple } });
 
-    int.or(flt).or(bt).or(bf).or(var).or(fun).or(ifexpr).or(paren)
+    int.or(flt).or(st).or(neg).or(bt).or(bf).or(var).or(fun).or(ifexpr).or(letrec).or(matchexpr).or(list).or(paren)
+        .and(many(between(tokenizer::lpar(), tokenizer::rpar(), sep_by(expr(), tokenizer::comma()))))
+        .map(|(f, calls) : (Lambda, Vec<Vec<Lambda>>)| calls.into_iter().fold(f, application))
 }
 
 parser!{
@@ -73,43 +123,22 @@ where [I: Stream<Item = Token>,
 }
 }
 
-fn op(l : Lambda, o : tokenizer::Token, r : Lambda) -> Lambda {
-    Lambda::BinExpr { b : o.into(), l : Rc::new(l), r : Rc::new(r), t : Type::TVar }
-}
-
-parser!{
-fn op_parser[I]()(I) -> (tokenizer::Token, combine_language::Assoc)
-where [I: Stream<Item = Token>,
-      I: combine::RangeStreamOnce,
-      <I as combine::StreamOnce>::Range: combine::stream::Range]
+fn expr_<I>() -> impl Parser<Input = I, Output = Lambda>
+where I: Stream<Item = Token>,
+      I::Error: ParseError<I::Item, I::Range, I::Position>,
+      <I as combine::StreamOnce>::Range: combine::stream::Range,
+      I: combine::RangeStreamOnce
 {
-    tokenizer::binop()
-        .map(|op| {
-            match op {
-                tokenizer::Token::Equals => 
-                    (op, combine_language::Assoc { precedence : 5, fixity : combine_language::Fixity::Left }),
-                tokenizer::Token::Less => 
-                    (op, combine_language::Assoc { precedence : 5, fixity : combine_language::Fixity::Left }),
-                tokenizer::Token::Greater => 
-                    (op, combine_language::Assoc { precedence : 5, fixity : combine_language::Fixity::Left }),
-                tokenizer::Token::LEq => 
-                    (op, combine_language::Assoc { precedence : 5, fixity : combine_language::Fixity::Left }),
-                tokenizer::Token::GEq => 
-                    (op, combine_language::Assoc { precedence : 5, fixity : combine_language::Fixity::Left }),
-                tokenizer::Token::NotEqual => 
-                    (op, combine_language::Assoc { precedence : 5, fixity : combine_language::Fixity::Left }),
-                tokenizer::Token::Plus => 
-                    (op, combine_language::Assoc { precedence : 6, fixity : combine_language::Fixity::Left }),
-                tokenizer::Token::Dash => 
-                    (op, combine_language::Assoc { precedence : 6, fixity : combine_language::Fixity::Left }),
-                tokenizer::Token::Star => 
-                    (op, combine_language::Assoc { precedence : 7, fixity : combine_language::Fixity::Left }),
-                tokenizer::Token::Slash => 
-                    (op, combine_language::Assoc { precedence : 7, fixity : combine_language::Fixity::Left }),
-                _ => panic!()
-            }
-        })
-}
+    fn level<I>(ops : &'static [Token]) -> impl Parser<Input = I, Output = impl Fn(Lambda, Lambda) -> Lambda>
+    where I: Stream<Item = Token>,
+          I::Error: ParseError<I::Item, I::Range, I::Position>
+    {
+        satisfy_map(move |t : Token| if ops.contains(&t) { Some (BinOp::from(t)) } else { None })
+            .map(|b| move |l, r| Lambda::BinExpr { b : b, l : Rc::new(l), r : Rc::new(r), t : Type::TVar })
+    }
+    let product = chainl1(lambda(), level(&[Token::Star, Token::Slash]));
+    let sum = chainl1(product, level(&[Token::Plus, Token::Dash]));
+    chainl1(sum, level(&[Token::Equals, Token::Less, Token::Greater, Token::LEq, Token::GEq, Token::NotEqual]))
 }
 
 parser!{
@@ -118,7 +147,7 @@ where [I: Stream<Item = Token>,
       I: combine::RangeStreamOnce,
       <I as combine::StreamOnce>::Range: combine::stream::Range]
 {
-    combine_language::expression_parser(lambda(), op_parser(), op)
+    expr_()
 }
 }
 
@@ -170,8 +199,17 @@ fn process_<I>() -> impl Parser<Input = I, Output = syntax::Process>
         .skip(tokenizer::keyword(Keyword::In))
         .and(process())
         .map(|((pat, lam), p) : ((Pattern, Lambda), syntax::Process)| syntax::Process::LetVal (pat, lam, Rc::new(p)));
-    let parallel = between(tokenizer::lpar(), tokenizer::rpar(), process().and(many1(tokenizer::pipe().with(process().map(|p| Rc::new(p))))))
-        .map(|(p1, plist)| syntax::Process::Parallel (Rc::new(prepend(Rc::new(p1), plist))));
+    let parallel = between(tokenizer::lpar(), tokenizer::rpar(), process().and(many(tokenizer::pipe().with(process().map(|p| Rc::new(p))))))
+        .map(|(p1, plist) : (syntax::Process, Vec<Rc<syntax::Process>>)| match plist.len() {
+            0 => p1,
+            _ => syntax::Process::Parallel (Rc::new(prepend(Rc::new(p1), plist)))
+        });
+    let matchproc = tokenizer::keyword(Keyword::Match)
+        .with(expr())
+        .skip(tokenizer::keyword(Keyword::With))
+        .skip(optional(tokenizer::pipe()))
+        .and(sep_by1(pattern().skip(tokenizer::rightarrow()).and(process().map(|p| Rc::new(p))), tokenizer::pipe()))
+        .map(|(l, arms)| syntax::Process::Match (l, arms));
     let actionproc = ap().map(|(a, p)| syntax::Process::Action (a, Rc::new(p)));
     let choose = tokenizer::keyword(Keyword::Do)
         .with(ap().and(many1(tokenizer::keyword(Keyword::Or).with(ap()).map(|(a, p)| (a, Rc::new(p))))))
@@ -191,6 +229,7 @@ fn process_<I>() -> impl Parser<Input = I, Output = syntax::Process>
 
     restrict
         .or(val)
+        .or(matchproc)
         .or(parallel)
         .or(terminate)
         .or(choose)
@@ -211,6 +250,101 @@ where [I: Stream<Item = Token>,
 }
 }
 
+fn observable_<I>() -> impl Parser<Input = I, Output = syntax::Observable>
+where I: Stream<Item = Token>,
+      I::Error: ParseError<I::Item, I::Range, I::Position>,
+      <I as combine::StreamOnce>::Range: combine::stream::Range,
+      I: combine::RangeStreamOnce
+{
+    fn arith<I>(ops : &'static [Token]) -> impl Parser<Input = I, Output = impl Fn(syntax::Observable, syntax::Observable) -> syntax::Observable>
+    where I: Stream<Item = Token>,
+          I::Error: ParseError<I::Item, I::Range, I::Position>
+    {
+        satisfy_map(move |t : Token| if ops.contains(&t) { Some (BinOp::from(t)) } else { None })
+            .map(|b| move |l, r| syntax::Observable::BinExpr (b, Rc::new(l), Rc::new(r)))
+    }
+    let count = tokenizer::ident()
+        .skip(between(tokenizer::lpar(), tokenizer::rpar(), optional(tokenizer::underscore())))
+        .map(|n| syntax::Observable::Count (n));
+    let int = tokenizer::integer().map(|i| syntax::Observable::Const (i as f64));
+    let flt = tokenizer::float().map(|f| syntax::Observable::Const (f));
+    let neg = satisfy_map(|t : Token| if t == Token::Dash { Some (()) } else { None })
+        .with(tokenizer::integer().map(|i| i as f64).or(tokenizer::float()))
+        .map(|x| syntax::Observable::Const (-x));
+    let paren = between(tokenizer::lpar(), tokenizer::rpar(), observable());
+    let atom = count.or(int).or(flt).or(neg).or(paren);
+
+    chainl1(chainl1(atom, arith(&[Token::Star, Token::Slash])), arith(&[Token::Plus, Token::Dash]))
+}
+
+parser!{
+fn observable[I]()(I) -> syntax::Observable
+where [I: Stream<Item = Token>,
+      I: combine::RangeStreamOnce,
+      <I as combine::StreamOnce>::Range: combine::stream::Range]
+{
+    observable_()
+}
+}
+
+fn predicate_<I>() -> impl Parser<Input = I, Output = syntax::Predicate>
+where I: Stream<Item = Token>,
+      I::Error: ParseError<I::Item, I::Range, I::Position>,
+      <I as combine::StreamOnce>::Range: combine::stream::Range,
+      I: combine::RangeStreamOnce
+{
+    let compare = (observable(), tokenizer::cmpop(), observable())
+        .map(|(l, o, r)| syntax::Predicate::Compare (o.into(), l, r));
+    let conj = tokenizer::keyword(Keyword::And).map(|_| true)
+        .or(tokenizer::keyword(Keyword::Or).map(|_| false))
+        .map(|and| move |l, r| {
+            if and {
+                syntax::Predicate::And (Rc::new(l), Rc::new(r))
+            }
+            else {
+                syntax::Predicate::Or (Rc::new(l), Rc::new(r))
+            }
+        });
+
+    chainl1(compare, conj)
+}
+
+parser!{
+fn predicate[I]()(I) -> syntax::Predicate
+where [I: Stream<Item = Token>,
+      I: combine::RangeStreamOnce,
+      <I as combine::StreamOnce>::Range: combine::stream::Range]
+{
+    predicate_()
+}
+}
+
+fn time<I>() -> impl Parser<Input = I, Output = f64>
+where I: Stream<Item = Token>,
+      I::Error: ParseError<I::Item, I::Range, I::Position>,
+      <I as combine::StreamOnce>::Range: combine::stream::Range,
+      I: combine::RangeStreamOnce
+{
+    tokenizer::float().or(tokenizer::integer().map(|i| i as f64))
+}
+
+fn intervention<I>() -> impl Parser<Input = I, Output = syntax::Directive>
+where I: Stream<Item = Token>,
+      I::Error: ParseError<I::Item, I::Range, I::Position>,
+      <I as combine::StreamOnce>::Range: combine::stream::Range,
+      I: combine::RangeStreamOnce
+{
+    let inject = tokenizer::keyword(Keyword::Run)
+        .with(process())
+        .map(|p| syntax::Directive::Run (Rc::new(p)));
+    let kill = tokenizer::keyword(Keyword::Kill)
+        .with(tokenizer::ident())
+        .skip(between(tokenizer::lpar(), tokenizer::rpar(), optional(tokenizer::underscore())))
+        .map(|n| syntax::Directive::Kill (n));
+
+    inject.or(kill)
+}
+
 fn declaration_<I>() -> impl Parser<Input = I, Output = syntax::Declaration>
     where I: Stream<Item = Token>,
           // Necessary due to rust-lang/rust#24159
@@ -238,10 +372,40 @@ fn declaration_<I>() -> impl Parser<Input = I, Output = syntax::Declaration>
         .and(process())
         .map(|((c, pat), p) : ((String, Vec<Pattern>), syntax::Process)| syntax::Declaration::Def (c, pat, Rc::new(p)));
 
+    let variant = tokenizer::ident()
+        .and(optional(tokenizer::keyword(Keyword::Of).with(sep_by1(tokenizer::ident(), tokenizer::star()))))
+        .map(|(c, fields) : (String, Option<Vec<String>>)| (c, fields.unwrap_or_default()));
+    let datatype = tokenizer::keyword(Keyword::Type)
+        .with(tokenizer::ident())
+        .skip(tokenizer::equals())
+        .skip(optional(tokenizer::pipe()))
+        .and(sep_by1(variant, tokenizer::pipe()))
+        .map(|(n, cs)| syntax::Declaration::Type (n, cs));
+
+    let directive = tokenizer::keyword(Keyword::Directive)
+        .skip(tokenizer::keyword(Keyword::At))
+        .with(time())
+        .and(intervention())
+        .map(|(t, d)| syntax::Declaration::Directive (t, d));
+    let trigger = tokenizer::keyword(Keyword::When).map(|_| false)
+        .or(tokenizer::keyword(Keyword::Whenever).map(|_| true))
+        .and(predicate())
+        .and(optional(tokenizer::keyword(Keyword::After).with(time())))
+        .and(intervention())
+        .map(|(((repeat, pred), delay), action)| syntax::Declaration::Trigger (syntax::Trigger {
+            pred : pred,
+            delay : delay.unwrap_or(0.0),
+            repeat : repeat,
+            action : action
+        }));
+
     newchan
         .or(runproc)
         .or(val)
         .or(def)
+        .or(datatype)
+        .or(directive)
+        .or(trigger)
 }
 
 parser!{
@@ -267,3 +431,93 @@ where [I: Stream<Item = Token>,
 }
 }
 
+
+fn property_<I>() -> impl Parser<Input = I, Output = csl::Property>
+where I: Stream<Item = Token>,
+      I::Error: ParseError<I::Item, I::Range, I::Position>,
+      <I as combine::StreamOnce>::Range: combine::stream::Range,
+      I: combine::RangeStreamOnce
+{
+    fn operator<I>(name : &'static str) -> impl Parser<Input = I, Output = String>
+    where I: Stream<Item = Token>,
+          I::Error: ParseError<I::Item, I::Range, I::Position>
+    {
+        satisfy_map(move |t : Token| match t {
+            Token::Identifier (ref i) if i == name => Some (i.clone()),
+            _ => None
+        })
+    }
+    fn bound<I>() -> impl Parser<Input = I, Output = (BinOp, f64)>
+    where I: Stream<Item = Token>,
+          I::Error: ParseError<I::Item, I::Range, I::Position>,
+          <I as combine::StreamOnce>::Range: combine::stream::Range,
+          I: combine::RangeStreamOnce
+    {
+        tokenizer::cmpop().map(BinOp::from).and(time())
+    }
+    let eventually = operator("F")
+        .with(optional(satisfy_map(|t : Token| if t == Token::LEq { Some (()) } else { None }).with(time())))
+        .and(predicate())
+        .map(|(t, p)| csl::Path::Eventually (t, p));
+    let prob = operator("P")
+        .with(bound())
+        .and(between(tokenizer::lbracket(), tokenizer::rbracket(), eventually))
+        .map(|((o, p), path)| csl::Property::Prob (o, p, path));
+    let steady = operator("S")
+        .with(bound())
+        .and(between(tokenizer::lbracket(), tokenizer::rbracket(), predicate()))
+        .map(|((o, p), phi)| csl::Property::Steady (o, p, phi));
+
+    prob.or(steady)
+}
+
+parser! {
+pub fn property[I]()(I) -> csl::Property
+where [I: Stream<Item = Token>,
+      I: combine::RangeStreamOnce,
+      <I as combine::StreamOnce>::Range: combine::stream::Range]
+{
+    property_()
+}
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::syntax;
+    use super::*;
+
+    fn definition(src : &str) -> (Vec<Pattern>, syntax::Process) {
+        let prog = crate::parse(&format!("new a@1.0\n{}\nrun end", src)).unwrap();
+        let syntax::Program::Prog (ref decs) = prog;
+        match &*decs[1] {
+            syntax::Declaration::Def (_, pats, p) => (pats.clone(), (**p).clone()),
+            d => panic!("unexpected declaration {:?}", d)
+        }
+    }
+
+    fn name(n : &str) -> Pattern {
+        Pattern::Name (n.to_string())
+    }
+
+    #[test]
+    fn nested_tuple_patterns() {
+        let (pats, p) = definition("let P ((x, (y, _)), z, ()) = val ((u), (_, v)) = (1, (2, 3)) in end");
+        assert_eq!(format!("{:?}", pats), format!("{:?}", vec![
+            Pattern::Tuple (vec![name("x"), Pattern::Tuple (vec![name("y"), Pattern::Wildcard])]),
+            name("z"),
+            Pattern::Tuple (Vec::new())]));
+        match p {
+            syntax::Process::LetVal (pat, l, _) => {
+                assert_eq!(format!("{:?}", pat), format!("{:?}", Pattern::Tuple (vec![name("u"), Pattern::Tuple (vec![Pattern::Wildcard, name("v")])])));
+                assert_eq!(l.to_string(), "(1, (2, 3))");
+            },
+            p => panic!("unexpected process {:?}", p)
+        }
+    }
+
+    #[test]
+    fn parentheses_only_group_a_single_expression() {
+        let (_, p) = definition("let P () = val x = (((1))) in val y = ((1, 2)) in val z = () in end");
+        assert_eq!(p.to_string(), "val x = 1 in val y = (1, 2) in val z = () in end");
+    }
+}
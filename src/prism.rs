@@ -0,0 +1,91 @@
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use super::crn;
+
+const RESERVED : &[&str] = &[
+    "A", "bool", "C", "ceil", "const", "ctmc", "double", "dtmc", "E", "endinit", "endinvariant",
+    "endmodule", "endrewards", "endsystem", "F", "false", "floor", "formula", "func", "G", "global",
+    "I", "init", "invariant", "label", "log", "max", "mdp", "min", "mod", "module", "N", "P", "pow",
+    "R", "rate", "rewards", "S", "system", "true", "U", "W", "X"
+];
+
+fn ident(base : &str, used : &mut BTreeSet<String>) -> String {
+    let mut id = crn::sid(base);
+    if RESERVED.contains(&id.as_str()) {
+        id.push('_');
+    }
+    crn::unique(id, used)
+}
+
+pub fn conservation_bound(c : &crn::Crn) -> Result<usize, String> {
+    for r in c.reactions.iter() {
+        let consumed : usize = r.reactants.iter().map(|(_, n)| n).sum();
+        let produced : usize = r.products.iter().map(|(_, n)| n).sum();
+        if produced > consumed {
+            let names : Vec<&str> = r.reactants.iter().map(|(s, _)| c.species[*s].name.as_str()).collect();
+            return Err (format!("the interaction of {} on {} increases the population, \
+                so the model has no conservation bound", names.join(" and "), r.chan));
+        }
+    }
+    Ok (c.initial.iter().sum())
+}
+
+pub fn export(c : &crn::Crn) -> Result<String, String> {
+    let bound = conservation_bound(c)?;
+    let mut used : BTreeSet<String> = BTreeSet::new();
+    used.insert("spi".to_string());
+    let vars : Vec<String> = c.species.iter().map(|s| ident(&s.name, &mut used)).collect();
+    let max = ident("MAX", &mut used);
+
+    let mut out = String::new();
+    writeln!(out, "ctmc").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "const int {} = {};", max, bound).unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "module spi").unwrap();
+    for (i, v) in vars.iter().enumerate() {
+        writeln!(out, "  {} : [0..{}] init {};", v, max, c.initial[i]).unwrap();
+    }
+    writeln!(out).unwrap();
+    for r in c.reactions.iter() {
+        let mut delta : BTreeMap<usize, i64> = BTreeMap::new();
+        for (s, n) in r.reactants.iter() {
+            *delta.entry(*s).or_insert(0) -= *n as i64;
+        }
+        for (s, n) in r.products.iter() {
+            *delta.entry(*s).or_insert(0) += *n as i64;
+        }
+        let mut guard : Vec<String> = r.reactants.iter().map(|(s, n)| format!("{}>={}", vars[*s], n)).collect();
+        guard.extend(delta.iter().filter(|(_, d)| **d > 0).map(|(s, d)| format!("{}<={}-{}", vars[*s], max, d)));
+        let mut rate = vec![r.rate.to_string()];
+        for (s, n) in r.reactants.iter() {
+            for k in 0..*n {
+                rate.push(if k == 0 { vars[*s].clone() } else { format!("({}-{})", vars[*s], k) });
+            }
+        }
+        let updates : Vec<String> = delta.iter().filter(|(_, d)| **d != 0).map(|(s, d)| {
+            if *d > 0 { format!("({}'={}+{})", vars[*s], vars[*s], d) } else { format!("({}'={}-{})", vars[*s], vars[*s], -d) }
+        }).collect();
+        let update = if updates.is_empty() { "true".to_string() } else { updates.join(" & ") };
+        writeln!(out, "  [{}] {} -> {} : {};",
+            crn::sid(&r.chan),
+            if guard.is_empty() { "true".to_string() } else { guard.join(" & ") },
+            rate.join("*"),
+            update).unwrap();
+    }
+    writeln!(out, "endmodule").unwrap();
+
+    let mut defs : BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (i, s) in c.species.iter().enumerate() {
+        defs.entry(&s.def).or_insert_with(Vec::new).push(&vars[i]);
+    }
+    for (d, vs) in defs.iter() {
+        writeln!(out).unwrap();
+        writeln!(out, "rewards \"{}\"", d).unwrap();
+        writeln!(out, "  true : {};", vs.join("+")).unwrap();
+        writeln!(out, "endrewards").unwrap();
+    }
+    Ok (out)
+}
@@ -0,0 +1,37 @@
+
+use rand;
+
+#[derive(Clone, Debug)]
+pub struct SplitMix {
+    pub state : u64
+}
+
+impl SplitMix {
+    pub fn new(seed : u64) -> SplitMix {
+        SplitMix { state : seed }
+    }
+}
+
+impl rand::RngCore for SplitMix {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+    fn fill_bytes(&mut self, dest : &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            let n = chunk.len();
+            chunk.copy_from_slice(&bytes[..n]);
+        }
+    }
+    fn try_fill_bytes(&mut self, dest : &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok (())
+    }
+}
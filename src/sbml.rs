@@ -0,0 +1,493 @@
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use super::crn;
+use super::syntax;
+use super::tokenizer::Keyword;
+use super::xml;
+use super::xml::{Element, Document};
+
+pub const SBML_NS : &str = "http://www.sbml.org/sbml/level3/version2/core";
+pub const MATHML_NS : &str = "http://www.w3.org/1998/Math/MathML";
+pub const COMPARTMENT : &str = "cell";
+
+fn ci(id : &str) -> Element {
+    Element::new("ci").text(id)
+}
+
+fn kinetic_law(k : &str, reactants : &[(String, usize)]) -> Element {
+    let mut times = Element::new("apply").child(Element::new("times")).child(ci(k));
+    for (s, n) in reactants.iter() {
+        times = times.child(ci(s));
+        for j in 1..*n {
+            times = times.child(Element::new("apply")
+                .child(Element::new("minus"))
+                .child(ci(s))
+                .child(Element::new("cn").attr("type", "integer").text(j)));
+        }
+    }
+    Element::new("kineticLaw").child(Element::new("math").attr("xmlns", MATHML_NS).child(times))
+}
+
+fn references(tag : &str, ids : &[String], side : &[(usize, usize)]) -> Element {
+    side.iter().fold(Element::new(tag), |e, (s, n)| {
+        e.child(Element::new("speciesReference")
+            .attr("species", &ids[*s])
+            .attr("stoichiometry", n)
+            .attr("constant", "true"))
+    })
+}
+
+pub fn export(c : &crn::Crn) -> Document {
+    let mut used = BTreeSet::new();
+    used.insert(COMPARTMENT.to_string());
+    let ids : Vec<String> = c.species.iter().map(|s| crn::unique(crn::sid(&s.name), &mut used)).collect();
+    let mut chans : Vec<(String, f64)> = Vec::new();
+    for r in c.reactions.iter() {
+        if !chans.iter().any(|(ch, _)| *ch == r.chan) {
+            chans.push((r.chan.clone(), r.rate));
+        }
+    }
+    let params : Vec<String> = chans.iter().map(|(ch, _)| crn::unique(crn::sid(&format!("k_{}", ch)), &mut used)).collect();
+
+    let compartments = Element::new("listOfCompartments")
+        .child(Element::new("compartment")
+            .attr("id", COMPARTMENT)
+            .attr("spatialDimensions", 3)
+            .attr("size", 1)
+            .attr("constant", "true"));
+    let species = c.species.iter().enumerate().fold(Element::new("listOfSpecies"), |e, (i, s)| {
+        e.child(Element::new("species")
+            .attr("id", &ids[i])
+            .attr("name", &s.name)
+            .attr("compartment", COMPARTMENT)
+            .attr("initialAmount", c.initial[i])
+            .attr("hasOnlySubstanceUnits", "true")
+            .attr("boundaryCondition", "false")
+            .attr("constant", "false"))
+    });
+    let parameters = chans.iter().zip(params.iter()).fold(Element::new("listOfParameters"), |e, ((ch, rate), id)| {
+        e.child(Element::new("parameter")
+            .attr("id", id)
+            .attr("name", ch)
+            .attr("value", rate)
+            .attr("constant", "true"))
+    });
+    let reactions = c.reactions.iter().enumerate().fold(Element::new("listOfReactions"), |e, (i, r)| {
+        let k = &params[chans.iter().position(|(ch, _)| *ch == r.chan).unwrap()];
+        let reactants : Vec<(String, usize)> = r.reactants.iter().map(|(s, n)| (ids[*s].clone(), *n)).collect();
+        let mut re = Element::new("reaction")
+            .attr("id", format!("r{}", i))
+            .attr("name", &r.chan)
+            .attr("reversible", "false")
+            .child(references("listOfReactants", &ids, &r.reactants));
+        if !r.products.is_empty() {
+            re = re.child(references("listOfProducts", &ids, &r.products));
+        }
+        e.child(re.child(kinetic_law(k, &reactants)))
+    });
+
+    let model = Element::new("model")
+        .attr("id", "spi")
+        .attr("substanceUnits", "item")
+        .attr("extentUnits", "item")
+        .attr("timeUnits", "second")
+        .child(compartments)
+        .child(species)
+        .child(parameters)
+        .child(reactions);
+    Document (Element::new("sbml")
+        .attr("xmlns", SBML_NS)
+        .attr("level", 3)
+        .attr("version", 2)
+        .child(model))
+}
+
+#[derive(Clone, Debug)]
+enum Math {
+    Ci (String),
+    Cn (f64),
+    Apply (String, Vec<Math>)
+}
+
+fn math(e : &xml::Element) -> Result<Math, String> {
+    match e.name.as_str() {
+        "ci" => Ok (Math::Ci (e.content())),
+        "cn" => {
+            let parts : Vec<String> = e.children.iter().filter_map(|c| match c {
+                xml::Node::Text (t) => Some (t.trim().to_string()),
+                _ => None
+            }).collect();
+            let num = |s : &str| s.parse::<f64>().map_err(|_| format!("malformed number {}", s));
+            match (e.get("type"), parts.as_slice()) {
+                (Some ("e-notation"), [m, x]) => Ok (Math::Cn (num(m)? * 10f64.powf(num(x)?))),
+                (Some ("rational"), [n, d]) => Ok (Math::Cn (num(n)? / num(d)?)),
+                (_, [v]) => Ok (Math::Cn (num(v)?)),
+                _ => Err ("malformed <cn> element".to_string())
+            }
+        },
+        "apply" => {
+            let mut it = e.elements();
+            let op = match it.next() {
+                Some (op) => op.name.clone(),
+                None => return Err ("empty <apply> element".to_string())
+            };
+            Ok (Math::Apply (op, it.map(math).collect::<Result<_, _>>()?))
+        },
+        "math" | "semantics" => match e.elements().next() {
+            Some (m) => math(m),
+            None => Err ("empty <math> element".to_string())
+        },
+        n => Err (format!("unsupported MathML element <{}>", n))
+    }
+}
+
+struct Law<'a> {
+    species : &'a BTreeMap<String, usize>,
+    consts : &'a BTreeMap<String, f64>,
+    k : f64,
+    factors : BTreeMap<String, usize>
+}
+
+impl<'a> Law<'a> {
+    fn constant(&self, m : &Math) -> Option<f64> {
+        match m {
+            Math::Cn (v) => Some (*v),
+            Math::Ci (id) => self.consts.get(id).cloned(),
+            Math::Apply (op, args) if op == "times" =>
+                args.iter().try_fold(1.0, |acc, a| self.constant(a).map(|v| acc * v)),
+            Math::Apply (op, args) if op == "divide" && args.len() == 2 =>
+                self.constant(&args[0]).and_then(|n| self.constant(&args[1]).map(|d| n / d)),
+            _ => None
+        }
+    }
+    fn factor(&mut self, m : &Math) -> Result<(), String> {
+        if let Some (v) = self.constant(m) {
+            self.k *= v;
+            return Ok (());
+        }
+        match m {
+            Math::Ci (id) if self.species.contains_key(id) => {
+                *self.factors.entry(id.clone()).or_insert(0) += 1;
+                Ok (())
+            },
+            Math::Ci (id) => Err (format!("{} is neither a species nor a constant parameter", id)),
+            Math::Apply (op, args) if op == "times" => {
+                for a in args.iter() {
+                    self.factor(a)?;
+                }
+                Ok (())
+            },
+            Math::Apply (op, args) if op == "divide" && args.len() == 2 => {
+                match self.constant(&args[1]) {
+                    Some (d) => {
+                        self.k /= d;
+                        self.factor(&args[0])
+                    },
+                    None => Err ("division by a non-constant expression".to_string())
+                }
+            },
+            Math::Apply (op, args) if op == "power" && args.len() == 2 => {
+                match (&args[0], self.constant(&args[1])) {
+                    (Math::Ci (id), Some (n)) if self.species.contains_key(id) && n >= 1.0 && n.fract() == 0.0 => {
+                        *self.factors.entry(id.clone()).or_insert(0) += n as usize;
+                        Ok (())
+                    },
+                    _ => Err ("only integer powers of species are mass action".to_string())
+                }
+            },
+            Math::Apply (op, args) if op == "minus" && args.len() == 2 => {
+                match (&args[0], self.constant(&args[1])) {
+                    (Math::Ci (id), Some (j)) if self.species.contains_key(id) && j >= 1.0 && j.fract() == 0.0 => {
+                        *self.factors.entry(id.clone()).or_insert(0) += 1;
+                        Ok (())
+                    },
+                    _ => Err ("subtraction is only allowed in falling factorials such as A * (A - 1)".to_string())
+                }
+            },
+            Math::Apply (op, _) => Err (format!("the operator {} is not mass action", op)),
+            Math::Cn (_) => Ok (())
+        }
+    }
+}
+
+fn spi_name(base : &str, used : &mut BTreeSet<String>) -> String {
+    let mut n : String = base.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+    if !n.chars().next().map(|c| c.is_ascii_alphabetic()).unwrap_or(false) {
+        n.insert(0, 'S');
+    }
+    if Keyword::try_from(n.as_str()).is_ok() {
+        n.push('0');
+    }
+    crn::unique(n, used)
+}
+
+fn stoich(e : &xml::Element, list : &str) -> Result<Vec<(String, usize)>, String> {
+    let mut v = Vec::new();
+    if let Some (l) = e.find(list) {
+        for r in l.find_all("speciesReference") {
+            let s = r.get("species").ok_or("speciesReference without a species")?;
+            let n = r.get("stoichiometry").unwrap_or("1").parse::<f64>()
+                .map_err(|_| format!("malformed stoichiometry for {}", s))?;
+            if n < 0.0 || n.fract() != 0.0 {
+                return Err (format!("stoichiometry {} of {} is not a whole number", n, s));
+            }
+            v.push((s.to_string(), n as usize));
+        }
+    }
+    Ok (v)
+}
+
+fn add(m : &mut BTreeMap<String, usize>, v : &[(String, usize)]) {
+    for (s, n) in v.iter() {
+        *m.entry(s.clone()).or_insert(0) += n;
+    }
+}
+
+fn continuation(names : &BTreeMap<String, String>, prods : &BTreeMap<String, usize>) -> syntax::Process {
+    let mut ps : Vec<Rc<syntax::Process>> = prods.iter().filter(|(_, n)| **n > 0).map(|(s, n)| {
+        let inst = syntax::Process::Instance (names[s].clone(), Vec::new());
+        Rc::new(if *n == 1 { inst } else { syntax::Process::Repetition (*n, Rc::new(inst)) })
+    }).collect();
+    match ps.len() {
+        0 => syntax::Process::Termination,
+        1 => (*ps.remove(0)).clone(),
+        _ => syntax::Process::Parallel (Rc::new(ps))
+    }
+}
+
+fn summation(branches : Vec<(syntax::Act, Rc<syntax::Process>)>) -> syntax::Process {
+    match branches.len() {
+        0 => syntax::Process::Termination,
+        1 => {
+            let (a, p) = branches.into_iter().next().unwrap();
+            syntax::Process::Action (a, p)
+        },
+        _ => syntax::Process::Choice (Rc::new(branches))
+    }
+}
+
+pub fn import(root : &xml::Element) -> Result<syntax::Program, String> {
+    if root.name != "sbml" {
+        return Err (format!("expected an <sbml> document, found <{}>", root.name));
+    }
+    let model = root.find("model").ok_or("the document has no <model>")?;
+    let mut consts : BTreeMap<String, f64> = BTreeMap::new();
+    let mut sizes : BTreeMap<String, f64> = BTreeMap::new();
+    if let Some (l) = model.find("listOfCompartments") {
+        for c in l.find_all("compartment") {
+            let id = c.get("id").ok_or("compartment without an id")?;
+            let size = c.get("size").unwrap_or("1").parse::<f64>().map_err(|_| format!("malformed size of {}", id))?;
+            sizes.insert(id.to_string(), size);
+            consts.insert(id.to_string(), size);
+        }
+    }
+    if let Some (l) = model.find("listOfParameters") {
+        for p in l.find_all("parameter") {
+            let id = p.get("id").ok_or("parameter without an id")?;
+            if p.get("constant") == Some ("false") {
+                return Err (format!("parameter {} is not constant", id));
+            }
+            let v = p.get("value").ok_or(format!("parameter {} has no value", id))?;
+            consts.insert(id.to_string(), v.parse::<f64>().map_err(|_| format!("malformed value of {}", id))?);
+        }
+    }
+
+    let mut used = BTreeSet::new();
+    let mut order = Vec::new();
+    let mut index : BTreeMap<String, usize> = BTreeMap::new();
+    let mut names : BTreeMap<String, String> = BTreeMap::new();
+    let mut initial : BTreeMap<String, usize> = BTreeMap::new();
+    let mut boundary : BTreeSet<String> = BTreeSet::new();
+    if let Some (l) = model.find("listOfSpecies") {
+        for s in l.find_all("species") {
+            let id = s.get("id").ok_or("species without an id")?.to_string();
+            let amount = match (s.get("initialAmount"), s.get("initialConcentration")) {
+                (Some (a), _) => a.parse::<f64>().map_err(|_| format!("malformed initial amount of {}", id))?,
+                (None, Some (c)) => {
+                    let v = s.get("compartment").and_then(|c| sizes.get(c)).cloned().unwrap_or(1.0);
+                    c.parse::<f64>().map_err(|_| format!("malformed initial concentration of {}", id))? * v
+                },
+                (None, None) => 0.0
+            };
+            if amount < 0.0 {
+                return Err (format!("species {} has a negative initial amount", id));
+            }
+            if s.get("boundaryCondition") == Some ("true") || s.get("constant") == Some ("true") {
+                boundary.insert(id.clone());
+            }
+            index.insert(id.clone(), order.len());
+            names.insert(id.clone(), spi_name(&id, &mut used));
+            initial.insert(id.clone(), amount.round() as usize);
+            order.push(id);
+        }
+    }
+
+    let clock = spi_name("Clock", &mut used);
+    let source = spi_name("Source", &mut used);
+    let mut chans = Vec::new();
+    let mut roles : BTreeMap<String, Vec<(syntax::Act, Rc<syntax::Process>)>> = BTreeMap::new();
+    let mut ticks = Vec::new();
+    let mut sources = Vec::new();
+    if let Some (l) = model.find("listOfReactions") {
+        for (i, r) in l.find_all("reaction").enumerate() {
+            let rid = r.get("id").map(|s| s.to_string()).unwrap_or(format!("r{}", i));
+            let ctx = |e : String| format!("reaction {}: {}", rid, e);
+            let reactants = stoich(r, "listOfReactants").map_err(ctx)?;
+            let products = stoich(r, "listOfProducts").map_err(ctx)?;
+            for (s, _) in reactants.iter().chain(products.iter()) {
+                if !index.contains_key(s) {
+                    return Err (ctx(format!("unknown species {}", s)));
+                }
+            }
+            let kl = r.find("kineticLaw").ok_or(ctx("no kinetic law".to_string()))?;
+            let mut local = consts.clone();
+            for list in ["listOfLocalParameters", "listOfParameters"].iter() {
+                if let Some (lp) = kl.find(list) {
+                    for p in lp.elements() {
+                        let id = p.get("id").ok_or(ctx("local parameter without an id".to_string()))?;
+                        let v = p.get("value").ok_or(ctx(format!("{} has no value", id)))?;
+                        local.insert(id.to_string(), v.parse::<f64>().map_err(|_| ctx(format!("malformed value of {}", id)))?);
+                    }
+                }
+            }
+            let m = math(kl.find("math").ok_or(ctx("the kinetic law has no math".to_string()))?).map_err(ctx)?;
+            let mut law = Law { species : &index, consts : &local, k : 1.0, factors : BTreeMap::new() };
+            law.factor(&m).map_err(|e| ctx(format!("kinetic law is not mass action: {}", e)))?;
+            let (k, factors) = (law.k, law.factors);
+
+            let mut consumed : BTreeMap<String, usize> = BTreeMap::new();
+            add(&mut consumed, &reactants);
+            for (s, n) in consumed.iter() {
+                if factors.get(s).cloned().unwrap_or(0) != *n {
+                    return Err (ctx(format!("kinetic law is not mass action: expected {} to appear {} times", s, n)));
+                }
+            }
+            let order_ = factors.values().sum::<usize>();
+            if order_ > 2 {
+                return Err (ctx(format!("reactions of order {} cannot be expressed with binary channels", order_)));
+            }
+            let mut prods : BTreeMap<String, usize> = BTreeMap::new();
+            add(&mut prods, &products.iter().filter(|(s, _)| !boundary.contains(s)).cloned().collect::<Vec<_>>());
+            for (s, n) in factors.iter() {
+                let eaten = if boundary.contains(s) { 0 } else { consumed.get(s).cloned().unwrap_or(0) };
+                *prods.entry(s.clone()).or_insert(0) += n - eaten;
+            }
+
+            let chan = spi_name(&rid, &mut used);
+            chans.push((chan.clone(), k));
+            let parts : Vec<String> = factors.iter().flat_map(|(s, n)| std::iter::repeat(s.clone()).take(*n)).collect();
+            let cont = Rc::new(continuation(&names, &prods));
+            match parts.as_slice() {
+                [a, b] => {
+                    roles.entry(a.clone()).or_insert_with(Vec::new).push((syntax::Act::Input (chan.clone()), cont));
+                    roles.entry(b.clone()).or_insert_with(Vec::new).push((syntax::Act::Output (chan.clone()), Rc::new(syntax::Process::Termination)));
+                },
+                [a] => {
+                    roles.entry(a.clone()).or_insert_with(Vec::new).push((syntax::Act::Input (chan.clone()), cont));
+                    ticks.push(chan.clone());
+                },
+                _ => {
+                    let again = syntax::Process::Instance (source.clone(), Vec::new());
+                    let cont = match (*cont).clone() {
+                        syntax::Process::Termination => again,
+                        syntax::Process::Parallel (ps) => {
+                            let mut v = vec![Rc::new(again)];
+                            v.extend(ps.iter().cloned());
+                            syntax::Process::Parallel (Rc::new(v))
+                        },
+                        p => syntax::Process::Parallel (Rc::new(vec![Rc::new(again), Rc::new(p)]))
+                    };
+                    sources.push((syntax::Act::Input (chan.clone()), Rc::new(cont)));
+                    ticks.push(chan.clone());
+                }
+            }
+        }
+    }
+
+    let mut decs = Vec::new();
+    for (c, k) in chans.iter() {
+        decs.push(syntax::Declaration::NewChannel (c.clone(), *k));
+    }
+    for s in order.iter() {
+        let branches = roles.remove(s).unwrap_or_else(Vec::new);
+        decs.push(syntax::Declaration::Def (names[s].clone(), Vec::new(), Rc::new(summation(branches))));
+    }
+    let mut run : Vec<Rc<syntax::Process>> = Vec::new();
+    if !ticks.is_empty() {
+        let branches = ticks.iter()
+            .map(|c| (syntax::Act::Output (c.clone()), Rc::new(syntax::Process::Instance (clock.clone(), Vec::new()))))
+            .collect();
+        decs.push(syntax::Declaration::Def (clock.clone(), Vec::new(), Rc::new(summation(branches))));
+        run.push(Rc::new(syntax::Process::Instance (clock.clone(), Vec::new())));
+    }
+    if !sources.is_empty() {
+        decs.push(syntax::Declaration::Def (source.clone(), Vec::new(), Rc::new(summation(sources))));
+        run.push(Rc::new(syntax::Process::Instance (source.clone(), Vec::new())));
+    }
+    for s in order.iter() {
+        let inst = syntax::Process::Instance (names[s].clone(), Vec::new());
+        match initial[s] {
+            0 => (),
+            1 => run.push(Rc::new(inst)),
+            n => run.push(Rc::new(syntax::Process::Repetition (n, Rc::new(inst))))
+        }
+    }
+    let main = match run.len() {
+        0 => syntax::Process::Termination,
+        1 => (*run.remove(0)).clone(),
+        _ => syntax::Process::Parallel (Rc::new(run))
+    };
+    decs.push(syntax::Declaration::Run (Rc::new(main)));
+    Ok (syntax::Program::Prog (Rc::new(decs.into_iter().map(Rc::new).collect())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Reactions = Vec<(Vec<(String, usize)>, Vec<(String, usize)>, f64)>;
+
+    // species with their initial counts, and reactions as named reactants, products and rate
+    fn structure(c : &crn::Crn) -> (Vec<(String, usize)>, Reactions) {
+        let named = |side : &[(usize, usize)]| {
+            let mut v : Vec<(String, usize)> = side.iter().map(|(s, n)| (c.species[*s].name.clone(), *n)).collect();
+            v.sort();
+            v
+        };
+        let mut species : Vec<(String, usize)> = c.species.iter().map(|s| s.name.clone()).zip(c.initial.iter().cloned()).collect();
+        species.sort();
+        let mut reactions : Reactions = c.reactions.iter()
+            .map(|r| (named(&r.reactants), named(&r.products), r.rate))
+            .collect();
+        reactions.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        (species, reactions)
+    }
+
+    #[test]
+    fn export_then_import_keeps_the_reaction_network() {
+        let prog = crate::parse("new bind@2.0\n\
+            new unbind@0.5\n\
+            new dim@0.25\n\
+            let Na () = !bind; Naplus()\n\
+            let Cl () = ?bind; Clminus()\n\
+            let Naplus () = ?unbind; Na()\n\
+            let Clminus () = !unbind; Cl()\n\
+            let D () = do ?dim; Dimer() or !dim; end\n\
+            let Dimer () = end\n\
+            run (10 of Na() | 5 of Cl() | Naplus() | 4 of D())").unwrap();
+        let c = crn::Crn::from_program(&prog, 100).unwrap();
+        let doc = xml::parse(&export(&c).to_string()).unwrap();
+        let back = crn::Crn::from_program(&import(&doc).unwrap(), 100).unwrap();
+
+        let (species, reactions) = structure(&c);
+        assert_eq!(species, vec![
+            ("Cl()".to_string(), 5), ("Clminus()".to_string(), 0), ("D()".to_string(), 4),
+            ("Dimer()".to_string(), 0), ("Na()".to_string(), 10), ("Naplus()".to_string(), 1)]);
+        assert_eq!(reactions.len(), 3);
+        assert!(reactions.iter().any(|(r, p, k)| r == &vec![("D()".to_string(), 2)] && p == &vec![("Dimer()".to_string(), 1)] && *k == 0.25));
+        assert_eq!(structure(&back), (species, reactions));
+    }
+}
@@ -2,22 +2,38 @@ use rand;
 use rand::distributions::Distribution;
 use std::rc::Rc;
 use std::borrow::Borrow;
+use std::fs;
+use std::path::Path;
+use std::collections::BTreeMap;
 
 use super::symgen;
 use super::syntax;
 use super::ast;
-use super::values::*;
 use super::lambda::*;
 use super::machineterm;
 use super::store;
+use super::trace;
+use super::rng;
+use super::checkpoint;
+use super::checkpoint::{entry, value};
+
+fn evaluate(l : &Lambda, env : &Env) -> Result<Lambda, String> {
+    l.eval_in(env).map_err(|e| format!("runtime error: {}", e))
+}
 
 #[derive(Debug)]
 pub struct Simulator {
     pub time : f64,
     rngdist : rand::distributions::Uniform<f64>,
-    rng : rand::rngs::ThreadRng,
+    rng : rng::SplitMix,
     pub s : store::Store,
-    mt : Rc<machineterm::MachineTerm>
+    mt : Rc<machineterm::MachineTerm>,
+    actions : Vec<syntax::Directive>,
+    agenda : Vec<(f64, usize)>,
+    triggers : Vec<(syntax::Trigger, usize, bool)>,
+    // each instance whose body is not a single summation, by id: its definition and how
+    // many of the summations it broke into are still in the term
+    owners : BTreeMap<String, (String, usize)>
 }
 
 impl<'a> Simulator {
@@ -25,75 +41,99 @@ impl<'a> Simulator {
         Simulator {
             time: 0.0, 
             rngdist : rand::distributions::Uniform::new(0.0, 1.0),
-            rng : rand::thread_rng(),
+            rng : rng::SplitMix::new({ use rand::Rng; rand::thread_rng().gen() }),
             s: store::Store::new(), 
-            mt : Rc::new(machineterm::MachineTerm::empty())
+            mt : Rc::new(machineterm::MachineTerm::empty()),
+            actions : Vec::new(),
+            agenda : Vec::new(),
+            triggers : Vec::new(),
+            owners : BTreeMap::new()
         }
     }
-    fn construct<'b>(&mut self, proc : &ast::Process, term : Rc<machineterm::MachineTerm>) -> Rc<machineterm::MachineTerm> {
+    fn construct<'b>(&mut self, proc : &ast::Process, env : &Env, term : Rc<machineterm::MachineTerm>) -> Result<Rc<machineterm::MachineTerm>, String> {
         match &*term {
             &machineterm::MachineTerm::TopRestriction (ref c, r, ref mt) => 
-                Rc::new(machineterm::MachineTerm::TopRestriction (c.clone(), r, self.construct (proc, mt.clone()))),
+                Ok (Rc::new(machineterm::MachineTerm::TopRestriction (c.clone(), r, self.construct (proc, env, mt.clone())?))),
             &machineterm::MachineTerm::SummList (ref sl) => {
                 match proc {
                     ast::Process::Restriction (ref c, r, ref p) => {
                         let fresh : String = symgen::next();
-                        let pp = &mut p.substitute(&c, fresh.as_str());
-                        return Rc::new(machineterm::MachineTerm::TopRestriction
+                        self.s.add_channel(&fresh, *r);
+                        let env = env.bind_channel(c, &fresh);
+                        return Ok (Rc::new(machineterm::MachineTerm::TopRestriction
                             (fresh,
                             *r,
-                            self.construct(pp, Rc::new(machineterm::MachineTerm::SummList (sl.clone())))));
+                            self.construct(p, &env, Rc::new(machineterm::MachineTerm::SummList (sl.clone())))?)));
                     },
-                    ast::Process::LetVal (ref v, ref l, ref p) => {
-                        self.construct(&p.substitute(v, l.eval()), term)
+                    ast::Process::LetVal (ref pat, ref l, ref p) => {
+                        let x = evaluate(l, env)?;
+                        let bs = destructure(pat, &x).map_err(|e| format!("runtime error: {}", e))?;
+                        self.construct(p, &env.extend(bs), term)
+                    },
+                    ast::Process::Match (ref l, ref arms) => {
+                        let v = evaluate(l, env)?;
+                        match ast::Process::select(arms, &v, env) {
+                            Some ((p, env)) => self.construct(p, &env, term),
+                            None => Err (format!("runtime error: no case of the match accepts {}", v))
+                        }
                     },
                     ast::Process::Parallel (p1, p2) => {
-                        let mt1 = self.construct (p2, Rc::new(machineterm::MachineTerm::SummList (sl.clone())));
-                        return self.construct(p1, mt1);
+                        let mt1 = self.construct (p2, env, Rc::new(machineterm::MachineTerm::SummList (sl.clone())))?;
+                        return self.construct(p1, env, mt1);
                     },
                     ast::Process::Summation (apvec) => {
-                        let newsumm = Rc::new(machineterm::Summ (None, apvec.clone()));
+                        if let Some (id) = env.owner() {
+                            if let Some (o) = self.owners.get_mut(id) {
+                                o.1 += 1;
+                            }
+                        }
+                        let newsumm = Rc::new(machineterm::Summ (None, apvec.clone(), env.clone()));
                         let counts = newsumm.get_act_counts();
                         self.s.add_counts(counts);
                         let mut v = vec![newsumm];
                         v.extend_from_slice(sl);
-                        return Rc::new(machineterm::MachineTerm::SummList (v));
+                        return Ok (Rc::new(machineterm::MachineTerm::SummList (v)));
                     },
                     ast::Process::Instance (ref name, params) => {
                         self.s.create(name.to_string());
-                        let (pats, p) = match self.s.defs.get(name) {
-                            Some ((pats, p)) => {
-                                (pats, pats.iter().zip(params.iter()).fold(p.clone(), |p1, (pat, v)| Rc::new(p1.replace(pat, v))))
-                            },
-                            None => panic!()
-                        };
-                        match p.borrow() {
+                        let params = params.iter().map(|l| evaluate(l, env)).collect::<Result<Vec<Lambda>, _>>()?;
+                        let (p, env) = self.s.instantiate(name, &params).map_err(|e| format!("runtime error: {}", e))?;
+                        match &*p {
                             ast::Process::Summation (apvec) => {
-                                let newsumm = Rc::new(machineterm::Summ (Some (name.clone()), apvec.clone()));
+                                let newsumm = Rc::new(machineterm::Summ (Some ((name.clone(), params)), apvec.clone(), env));
                                 let counts = newsumm.get_act_counts();
                                 self.s.add_counts(counts);
                                 let mut v = vec![newsumm];
                                 v.extend_from_slice(sl);
-                                return Rc::new(machineterm::MachineTerm::SummList (v));
+                                return Ok (Rc::new(machineterm::MachineTerm::SummList (v)));
+                            },
+                            // an instance whose body is end stays counted, as in the reaction network
+                            ast::Process::Termination => self.construct(&p, &env, term),
+                            _ => {
+                                let id = symgen::next();
+                                self.owners.insert(id.clone(), (name.clone(), 0));
+                                let mt = self.construct(&p, &env.bind_owner(&id), term)?;
+                                self.disown(&id, 0);
+                                Ok (mt)
                             }
-                            _ => self.construct(&p.clone(), term)
                         }
                     },
                     ast::Process::Repetition (i, p) => {
-                        (0..*i).fold(term, |acc, _x| self.construct(p, acc))
+                        (0..*i).try_fold(term, |acc, _x| self.construct(p, env, acc))
                     },
                     ast::Process::Replication (a, p) => {
                         self.construct (
                             &mut ast::Process::Summation (
                                 Rc::new(vec![(a.clone(), Rc::new(ast::Process::Parallel ((*p).clone(), Rc::new(proc.clone()))))])),
+                            env,
                             Rc::new(machineterm::MachineTerm::SummList (sl.clone())))
                     },
-                    ast::Process::Termination => Rc::new(machineterm::MachineTerm::SummList (sl.clone()))
+                    ast::Process::Termination => Ok (Rc::new(machineterm::MachineTerm::SummList (sl.clone())))
                 }
             }
         }
     }
-    pub fn load(&mut self, p : &'a syntax::Program) {
+    pub fn load(&mut self, p : &'a syntax::Program) -> Result<(), String> {
         match *p {
             syntax::Program::Prog(ref decs) => {
                 let mut toplevelproc = Vec::new();
@@ -101,21 +141,126 @@ impl<'a> Simulator {
                     match (*d).borrow() {
                         syntax::Declaration::NewChannel (ref c, r) => self.s.add_channel((*c).borrow(), *r),
                         syntax::Declaration::Run (p) => toplevelproc.push(p),
-                        syntax::Declaration::Val (_, _) => panic!(),
+                        syntax::Declaration::Directive (t, ref d) => self.schedule(*t, d.clone()),
+                        syntax::Declaration::Trigger (ref t) => self.add_trigger(t.clone()),
+                        syntax::Declaration::Val (_, _) => return Err ("top level val declarations are not supported".to_string()),
+                        syntax::Declaration::Type (_, _) => (),
                         syntax::Declaration::Def (n, params, ref d) => {
                             self.s.defs.insert(n.to_string(), (params.to_vec(), Rc::new(ast::Process::from((*d).borrow()))));
                             self.s.instance_counts.insert(n.to_string(), 0);
                         }
                     }
                 }
-                self.mt = toplevelproc.iter().rev().fold(Rc::new(machineterm::MachineTerm::SummList(Vec::new())), |acc, x| {
+                self.mt = toplevelproc.iter().rev().try_fold(Rc::new(machineterm::MachineTerm::SummList(Vec::new())), |acc, x| {
                     let p : &syntax::Process = (**x).borrow();
-                    self.construct(&ast::Process::from(p), acc)
-                });
+                    self.construct(&ast::Process::from(p), &Env::new(), acc)
+                })?;
+                Ok (())
+            }
+        }
+    }
+    pub fn schedule(&mut self, time : f64, d : syntax::Directive) {
+        self.actions.push(d);
+        let a = self.actions.len() - 1;
+        self.enqueue(time, a);
+    }
+    pub fn add_trigger(&mut self, t : syntax::Trigger) {
+        self.actions.push(t.action.clone());
+        let a = self.actions.len() - 1;
+        self.triggers.push((t, a, true));
+    }
+    fn enqueue(&mut self, time : f64, a : usize) {
+        let mut i = self.agenda.len();
+        while i > 0 && self.agenda[i - 1].0 > time {
+            i -= 1;
+        }
+        self.agenda.insert(i, (time, a));
+    }
+    fn next_directive(&self) -> Option<f64> {
+        self.agenda.first().map(|(t, _)| *t)
+    }
+    fn check_triggers(&mut self) -> Result<(), String> {
+        let mut fired = Vec::new();
+        for (t, a, armed) in self.triggers.iter_mut() {
+            let holds = self.s.holds(&t.pred);
+            if *armed && holds {
+                *armed = false;
+                fired.push((self.time + t.delay, *a));
+            }
+            else if t.repeat && !holds {
+                *armed = true;
+            }
+        }
+        if !fired.is_empty() {
+            for (time, a) in fired {
+                self.enqueue(time, a);
+            }
+            let now = self.time;
+            self.apply_directives(now)?;
+        }
+        Ok (())
+    }
+    fn kill(&mut self, name : &str) -> Result<(), String> {
+        self.unwrap_restr();
+        let n = match *self.mt {
+            machineterm::MachineTerm::SummList (ref sl) => sl.len(),
+            _ => return Err (format!("cannot kill {} under a restriction", name))
+        };
+        for i in (0..n).rev() {
+            let hit = match *self.mt {
+                machineterm::MachineTerm::SummList (ref sl) => match sl[i].0 {
+                    Some ((ref m, _)) => m == name,
+                    None => sl[i].2.owner().and_then(|id| self.owners.get(id)).map_or(false, |(m, _)| m == name)
+                },
+                _ => false
+            };
+            if hit {
+                self.take(i)?;
+            }
+        }
+        // what is left of the count is instances with no summation in the term
+        self.owners.retain(|_, (n, _)| n != name);
+        if let Some (n) = self.s.instance_counts.get_mut(name) {
+            *n = 0;
+        }
+        Ok (())
+    }
+    // one of an instance's summations has gone; the instance goes with its last
+    fn disown(&mut self, id : &str, gone : usize) {
+        let left = match self.owners.get_mut(id) {
+            Some (o) => {
+                o.1 -= gone;
+                o.1
+            },
+            None => return
+        };
+        if left == 0 {
+            if let Some ((name, _)) = self.owners.remove(id) {
+                self.s.destroy(name);
             }
         }
     }
-    fn gillespie(&self, n1 : f64, n2 : f64) -> (String, f64) {
+    fn apply_directives(&mut self, limit : f64) -> Result<(), String> {
+        while let Some (t) = self.next_directive() {
+            if t > limit {
+                break;
+            }
+            let (_, a) = self.agenda.remove(0);
+            let d = self.actions[a].clone();
+            if t > self.time {
+                self.time = t;
+            }
+            match d {
+                syntax::Directive::Run (ref p) => {
+                    let pb : &syntax::Process = p.borrow();
+                    self.mt = self.construct(&ast::Process::from(pb), &Env::new(), self.mt.clone())?;
+                },
+                syntax::Directive::Kill (ref name) => self.kill(name)?
+            }
+        }
+        Ok (())
+    }
+    fn gillespie(&self, n1 : f64, n2 : f64) -> Result<(String, f64), String> {
         let activities = self.s.activities();
         let a0 = activities.iter().fold(0.0, |acc, (_v, a)| acc + a);
         let tau = (1.0 / a0) * (1.0 / n1).ln();
@@ -136,66 +281,442 @@ impl<'a> Simulator {
                     activities[0..i+1].iter().fold(0.0, |acc, x| acc + x.1)
                 };
             if test > lowerbnd && test <= upperbnd {
-                return (activities[i].0.to_string(), tau);
+                return Ok ((activities[i].0.to_string(), tau));
             }
         }
-        panic!();
+        // a draw that rounding puts past the last bound goes to the last channel that can react
+        match activities.iter().rev().find(|(_, a)| *a > 0.0) {
+            Some ((c, _)) => Ok ((c.to_string(), tau)),
+            None => Err ("no channel can react".to_string())
+        }
     }
-    pub fn reduce(&mut self) {
+    fn unwrap_restr(&mut self) {
+        while self.mt.is_restr() {
+            let (c, r) = self.mt.take_chan();
+            self.s.add_channel(&c.to_string(), r);
+            self.mt = self.mt.take_inner();
+        }
+    }
+    fn take(&mut self, i : usize) -> Result<Rc<machineterm::Summ>, String> {
+        let summ = Rc::get_mut(&mut self.mt).ok_or_else(|| "the machine term is shared".to_string())?.take_summ(i);
+        let counts = summ.get_act_counts();
+        self.s.remove_counts(counts);
+        Ok (summ)
+    }
+    fn fire(&mut self, si : &machineterm::Summ, islj : usize, so : &machineterm::Summ, oslj : usize) -> Result<(), String> {
+        let ip = si.index(islj);
+        let op = so.index(oslj);
+
+        self.mt = self.construct (&ip.1, &si.2, self.mt.clone())?;
+        self.mt = self.construct (&op.1, &so.2, self.mt.clone())?;
+
+        for summ in [si, so].iter() {
+            match summ.0 {
+                Some ((ref name, _)) => self.s.destroy(name.to_string()),
+                None => if let Some (id) = summ.2.owner() {
+                    self.disown(id, 1);
+                }
+            }
+        }
+        Ok (())
+    }
+    // nothing can react and no directive is left to change that
+    pub fn is_stuck(&self) -> bool {
+        !self.mt.is_restr() && self.agenda.is_empty() && self.s.activities().is_empty()
+    }
+    pub fn reduce(&mut self) -> Result<Option<trace::Event>, String> {
+        let e = self.step()?;
+        self.check_triggers()?;
+        Ok (e)
+    }
+    fn step(&mut self) -> Result<Option<trace::Event>, String> {
         let is_restr = self.mt.is_restr();
         let is_summlist = self.mt.is_summlist();
         if is_restr {
             let (c, r) = self.mt.take_chan();
             self.s.add_channel(&c.to_string(), r);
             self.mt = self.mt.take_inner();
+            Ok (None)
         }
         else if is_summlist {
             use rand::Rng;
+            if self.s.activities().is_empty() {
+                if let Some (t) = self.next_directive() {
+                    self.apply_directives(t)?;
+                    return Ok (None);
+                }
+            }
             let n1 = self.rngdist.sample(&mut self.rng);
             let n2 = self.rngdist.sample(&mut self.rng);
-            let (nextchan, tau) = self.gillespie(n1, n2);
+            let (nextchan, tau) = self.gillespie(n1, n2)?;
+            if let Some (t) = self.next_directive() {
+                if self.time + tau >= t {
+                    self.apply_directives(t)?;
+                    return Ok (None);
+                }
+            }
             let incount = match self.s.chans.get(&nextchan) {
                 Some (c) => c.incount,
-                None => panic!()
+                None => return Err (format!("unknown channel {}", nextchan))
             };
             let inputindex = self.rng.gen_range(0, incount);
             let (isli, islj) = self.mt.seek(ast::Act::Input(nextchan.clone()), inputindex);
-            let si = Rc::get_mut(&mut self.mt).unwrap().take_summ(isli);
-            let icounts_remove = si.get_act_counts();
-            self.s.remove_counts(icounts_remove);
+            let si = self.take(isli)?;
 
             let outcount = match self.s.chans.get(&nextchan) {
                 Some (c) => c.outcount,
-                None => panic!()
+                None => return Err (format!("unknown channel {}", nextchan))
             };
             let outputindex = self.rng.gen_range(0, outcount);
             let (osli, oslj) = self.mt.seek(ast::Act::Output(nextchan.clone()), outputindex);
-            let so = Rc::get_mut(&mut self.mt).unwrap().take_summ(osli);
-            let ocounts_remove = so.get_act_counts();
-            self.s.remove_counts(ocounts_remove);
-
-            let ip = si.index(islj);
-            let op = so.index(oslj);
+            let so = self.take(osli)?;
 
-            self.mt = self.construct (&ip.1, self.mt.clone());
-            self.mt = self.construct (&op.1, self.mt.clone());
+            self.fire(&si, islj, &so, oslj)?;
 
-            match *si {
-                machineterm::Summ (Some(ref name), _) => {
-                    self.s.destroy(name.to_string());
-                },
-                _ => ()
+            self.time += tau;
+            Ok (Some (trace::Event {
+                time : self.time,
+                chan : nextchan,
+                input : si.label(),
+                inindex : (isli, islj),
+                output : so.label(),
+                outindex : (osli, oslj)
+            }))
+        }
+        else {
+            Ok (None)
+        }
+    }
+    pub fn replay(&mut self, e : &trace::Event) -> Result<(), String> {
+        fn check(summ : &machineterm::Summ, j : usize, label : &str, act : ast::Act) -> Result<(), String> {
+            if summ.label() != label {
+                return Err (format!("expected {} but found {}", label, summ.label()));
             }
-            match *so {
-                machineterm::Summ (Some(ref name), _) => {
-                    self.s.destroy(name.to_string());
-                },
-                _ => ()
+            match (summ.1.get(j).map(|x| x.0.clone()), &act) {
+                (Some (ast::Act::Input (ref c1)), &ast::Act::Input (ref c2)) |
+                (Some (ast::Act::Output (ref c1)), &ast::Act::Output (ref c2)) if summ.2.channel(c1) == c2 => Ok (()),
+                _ => Err (format!("branch {} of {} does not act on {}", j, label, chan_of(&act)))
             }
-
-            self.time += tau;
         }
+        fn chan_of(a : &ast::Act) -> &str {
+            match a {
+                ast::Act::Input (c) | ast::Act::Output (c) => c
+            }
+        }
+        fn bounds(mt : &machineterm::MachineTerm, i : usize) -> Result<(), String> {
+            match mt {
+                machineterm::MachineTerm::SummList (sl) if i < sl.len() => Ok (()),
+                _ => Err (format!("summation index {} out of range", i))
+            }
+        }
+        // the steps the live run took before this event, each followed by a look at the triggers
+        loop {
+            if self.mt.is_restr() {
+                let (c, r) = self.mt.take_chan();
+                self.s.add_channel(&c, r);
+                self.mt = self.mt.take_inner();
+            }
+            else {
+                match self.next_directive() {
+                    Some (t) if t <= e.time => self.apply_directives(t)?,
+                    _ => break
+                }
+            }
+            self.check_triggers()?;
+        }
+        bounds(&self.mt, e.inindex.0)?;
+        let si = self.take(e.inindex.0)?;
+        check(&si, e.inindex.1, &e.input, ast::Act::Input (e.chan.clone()))?;
+        bounds(&self.mt, e.outindex.0)?;
+        let so = self.take(e.outindex.0)?;
+        check(&so, e.outindex.1, &e.output, ast::Act::Output (e.chan.clone()))?;
+        self.fire(&si, e.inindex.1, &so, e.outindex.1)?;
+        self.time = e.time;
+        self.check_triggers()
+    }
+    pub fn save<P : AsRef<Path>>(&self, path : P) -> Result<(), String> {
+        let chans = self.s.chans.iter().map(|(k, c)| {
+            entry("chan", vec![value(k), value(c.rate), value(c.incount), value(c.outcount), value(c.mixcount), value(c.ax)])
+        }).collect();
+        let counts = self.s.instance_counts.iter().map(|(k, n)| entry("count", vec![value(k), value(n)])).collect();
+        let doc = entry("checkpoint", vec![
+            entry("time", vec![value(self.time)]),
+            entry("symgen", vec![value(symgen::counter())]),
+            entry("rng", vec![value(self.rng.state)]),
+            entry("agenda", self.agenda.iter().map(|(t, a)| entry("at", vec![value(t), value(a)])).collect()),
+            entry("triggers", self.triggers.iter().map(|(_, _, armed)| value(armed)).collect()),
+            entry("chans", chans),
+            entry("counts", counts),
+            entry("owners", self.owners.iter().map(|(id, (n, k))| entry("owner", vec![value(id), value(n), value(k)])).collect()),
+            entry("term", vec![checkpoint::encode_term(&self.mt)])
+        ]);
+        fs::write(path, format!("{}\n", doc)).map_err(|e| e.to_string())
+    }
+    pub fn resume<P : AsRef<Path>>(&mut self, path : P) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let doc = checkpoint::parse(&text)?;
+        let one = |tag : &str| -> Result<checkpoint::Sexp, String> {
+            match doc.field(tag)?.first() {
+                Some (x) => Ok (x.clone()),
+                None => Err (format!("empty field {}", tag))
+            }
+        };
+        let time : f64 = one("time")?.num()?;
+        let counter : usize = one("symgen")?.num()?;
+        let state : u64 = one("rng")?.num()?;
+        let mut agenda = Vec::new();
+        for x in doc.field("agenda")?.iter() {
+            let (_, f) = x.tagged()?;
+            if f.len() != 2 {
+                return Err (format!("malformed agenda entry {}", x));
+            }
+            let a : usize = f[1].num()?;
+            if a >= self.actions.len() {
+                return Err (format!("agenda entry {} refers to an unknown action", x));
+            }
+            agenda.push((f[0].num()?, a));
+        }
+        let armed = doc.field("triggers")?.iter().map(|x| x.num()).collect::<Result<Vec<bool>, _>>()?;
+        if armed.len() != self.triggers.len() {
+            return Err (format!("checkpoint has {} triggers but the model has {}", armed.len(), self.triggers.len()));
+        }
+        let mt = checkpoint::decode_term(&one("term")?)?;
+        let mut chans = BTreeMap::new();
+        for c in doc.field("chans")?.iter() {
+            let (_, f) = c.tagged()?;
+            if f.len() != 6 {
+                return Err (format!("malformed channel record {}", c));
+            }
+            chans.insert(f[0].string()?, store::ChannelRecord {
+                rate : f[1].num()?,
+                incount : f[2].num()?,
+                outcount : f[3].num()?,
+                mixcount : f[4].num()?,
+                ax : f[5].num()?
+            });
+        }
+        let mut counts = BTreeMap::new();
+        for c in doc.field("counts")?.iter() {
+            let (_, f) = c.tagged()?;
+            if f.len() != 2 {
+                return Err (format!("malformed instance count {}", c));
+            }
+            counts.insert(f[0].string()?, f[1].num()?);
+        }
+        let mut owners = BTreeMap::new();
+        for o in doc.field("owners")?.iter() {
+            let (_, f) = o.tagged()?;
+            if f.len() != 3 {
+                return Err (format!("malformed instance owner {}", o));
+            }
+            owners.insert(f[0].string()?, (f[1].string()?, f[2].num()?));
+        }
+        self.time = time;
+        symgen::set(counter);
+        self.rng = rng::SplitMix::new(state);
+        self.agenda = agenda;
+        for (t, a) in self.triggers.iter_mut().zip(armed.into_iter()) {
+            t.2 = a;
+        }
+        self.s.chans = chans;
+        self.s.instance_counts = counts;
+        self.owners = owners;
+        self.mt = Rc::new(mt);
+        Ok (())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_errors_are_returned() {
+        let prog = crate::parse("new a@1.0\n\
+            let P (x) = val y = (x - 1) in !a; P(y)\n\
+            let Q () = ?a; Q()\n\
+            run (P(1) | Q())\n\
+            directive at 0.5 run P(true)").unwrap();
+        let mut sim = Simulator::new();
+        sim.load(&prog).unwrap();
+        let e = (0..1000).map(|_| sim.reduce()).find(|r| r.is_err()).unwrap().unwrap_err();
+        assert!(e.contains("in P") && e.contains("non-numeric operand"), "{}", e);
+        let prog = crate::parse("new a@1.0\nlet P (x) = !a; P(x)\nrun P(1, 2)").unwrap();
+        let e = Simulator::new().load(&prog).unwrap_err();
+        assert!(e.contains("P takes 1 parameters but is given 2"), "{}", e);
+    }
+
+    #[test]
+    fn restricted_channels_react_within_their_scope() {
+        let prog = crate::parse("new a@1.0
+            let Q () = ?a; let new c@2.0 in (!c; R() | ?c; S())
+            let R () = end
+            let S () = end
+            let A () = !a; A()
+            run (10 of Q() | A())").unwrap();
+        let mut sim = Simulator::new();
+        sim.load(&prog).unwrap();
+        // each Q takes an a, opens its own c, and then its two halves meet on it
+        for _ in 0..30 {
+            sim.reduce().unwrap();
+        }
+        assert_eq!(sim.s.instance_counts["Q"], 0);
+        assert_eq!(sim.s.instance_counts["R"], 10);
+        assert_eq!(sim.s.instance_counts["S"], 10);
+    }
+
+    #[test]
+    fn valid_models_do_not_panic() {
+        let prog = crate::parse("new a@1.0\nval k = 3\nlet P () = !a; P()\nrun P()").unwrap();
+        assert_eq!(Simulator::new().load(&prog).unwrap_err(), "top level val declarations are not supported");
+        let prog = crate::parse("new a@1.0\nrun ?a; end").unwrap();
+        let mut sim = Simulator::new();
+        sim.load(&prog).unwrap();
+        assert!(sim.is_stuck());
+        assert_eq!(sim.reduce().unwrap_err(), "no channel can react");
+    }
+
+    #[test]
+    fn resuming_a_checkpoint_continues_the_same_run() {
+        let prog = crate::parse("new a@1.0\n\
+            new b@2.0\n\
+            let P (n, s) = do !a; val m = (n + 1) in P(m, s) or ?a; Q(s) or ?b; Q(s)\n\
+            let Q (s) = do ?a; P(0, s) or !a; Q(s) or !b; Q(s)\n\
+            run (3 of P(0, \"x\") | 2 of Q(\"y\"))\n\
+            directive at 2.0 run Q(\"z\")\n\
+            whenever Q() > 3 run P(1, \"w\")").unwrap();
+        let start = || {
+            let mut sim = Simulator::new();
+            sim.load(&prog).unwrap();
+            sim.rng = rng::SplitMix::new(7);
+            sim
+        };
+        let events = |sim : &mut Simulator, n : usize| -> Vec<String> {
+            (0..n).map(|_| format!("{:?}", sim.reduce().unwrap())).collect()
+        };
+        let mut whole = start();
+        events(&mut whole, 50);
+        let after = events(&mut whole, 200);
+
+        let path = std::env::temp_dir().join(format!("spi-resume-test-{}", std::process::id()));
+        let mut first = start();
+        events(&mut first, 50);
+        first.save(&path).unwrap();
+        let mut resumed = start();
+        resumed.resume(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(resumed.rng.state, first.rng.state);
+        assert_eq!(events(&mut resumed, 200), after);
+        assert_eq!(resumed.rng.state, whole.rng.state);
+        assert_eq!(resumed.time, whole.time);
+        assert_eq!(resumed.s.instance_counts, whole.s.instance_counts);
+    }
 
+    #[test]
+    fn replaying_a_trace_reproduces_it() {
+        // the trigger comes true when the directive runs, before the next event
+        let prog = crate::parse("new a@1.0\n\
+            let P () = do !a; P() or ?a; P()\n\
+            let X () = ?a; end\n\
+            let Y () = !a; Y()\n\
+            run 4 of P()\n\
+            directive at 1.0 run 5 of X()\n\
+            when X() > 3 after 0.25 run Y()").unwrap();
+        let mut live = Simulator::new();
+        live.load(&prog).unwrap();
+        let mut recorded = Vec::new();
+        while recorded.len() < 100 {
+            if let Some (e) = live.reduce().unwrap() {
+                recorded.push((e, live.time, live.s.instance_counts.clone()));
+            }
+        }
+        assert!(recorded.iter().any(|(_, _, counts)| counts["Y"] > 0));
+        let mut replayed = Simulator::new();
+        replayed.load(&prog).unwrap();
+        for (e, time, counts) in recorded.iter() {
+            replayed.replay(e).unwrap();
+            assert_eq!(replayed.time, *time);
+            assert_eq!(&replayed.s.instance_counts, counts, "at time {}", time);
+        }
+    }
+
+    // steps the simulator until it is past the given time, and gives back the counts from just before
+    fn until(sim : &mut Simulator, time : f64) -> BTreeMap<String, usize> {
+        let mut before = sim.s.instance_counts.clone();
+        while sim.time < time {
+            before = sim.s.instance_counts.clone();
+            sim.reduce().unwrap();
+        }
+        before
+    }
+
+    #[test]
+    fn directives_apply_when_time_reaches_them() {
+        let prog = crate::parse("new a@1.0\n\
+            let P () = do !a; P() or ?a; P()\n\
+            let X () = end\n\
+            run 2 of P()\n\
+            directive at 0.5 run 3 of X()\n\
+            directive at 1.5 run 4 of X()").unwrap();
+        let mut sim = Simulator::new();
+        sim.load(&prog).unwrap();
+        assert_eq!(until(&mut sim, 0.5)["X"], 0);
+        assert_eq!(sim.time, 0.5);
+        assert_eq!(sim.s.instance_counts["X"], 3);
+        assert_eq!(until(&mut sim, 1.5)["X"], 3);
+        assert_eq!(sim.time, 1.5);
+        assert_eq!(sim.s.instance_counts["X"], 7);
+    }
+
+    #[test]
+    fn kill_removes_every_part_of_an_instance() {
+        let prog = crate::parse("new a@1.0\n\
+            new b@1.0\n\
+            let P () = do !a; P() or ?a; P()\n\
+            let Q (n) = (?b; Q(n) | !b; end)\n\
+            let R () = let new c@1.0 in (?c; R() | !c; end | !a; end)\n\
+            let S () = end\n\
+            run (2 of P() | 3 of Q(1) | 2 of R() | S())\n\
+            directive at 1.0 kill Q(_)\n\
+            directive at 1.0 kill R(_)\n\
+            directive at 1.0 kill S(_)").unwrap();
+        let mut sim = Simulator::new();
+        sim.load(&prog).unwrap();
+        assert_eq!((sim.s.instance_counts["Q"], sim.s.instance_counts["R"], sim.s.instance_counts["S"]), (3, 2, 1));
+        until(&mut sim, 1.0);
+        assert_eq!((sim.s.instance_counts["Q"], sim.s.instance_counts["R"], sim.s.instance_counts["S"]), (0, 0, 0));
+        // only the two P are left, talking to each other on a
+        assert!(sim.s.activities().iter().all(|(c, _)| c == "a"));
+        assert_eq!(sim.s.chans["a"].incount, 2);
+        assert_eq!(sim.s.chans["a"].outcount, 2);
+        assert_eq!(sim.s.chans["b"].incount + sim.s.chans["b"].outcount, 0);
+    }
+
+    #[test]
+    fn when_fires_once_and_whenever_each_time() {
+        // X alternates between 0 and 2, so the predicate comes true again and again
+        let prog = crate::parse("new a@1.0\n\
+            let P () = do !a; X() or ?a; P()\n\
+            let X () = do !a; P() or ?a; X()\n\
+            let Once () = end\n\
+            let Each () = end\n\
+            run 2 of P()\n\
+            when X() > 1 run Once()\n\
+            whenever X() > 1 run Each()").unwrap();
+        let mut sim = Simulator::new();
+        sim.load(&prog).unwrap();
+        let mut rises = 0;
+        let mut was = false;
+        for _ in 0..200 {
+            sim.reduce().unwrap();
+            let is = sim.s.instance_counts["X"] > 1;
+            if is && !was {
+                rises += 1;
+            }
+            was = is;
+        }
+        assert!(rises > 1);
+        assert_eq!(sim.s.instance_counts["Once"], 1);
+        assert_eq!(sim.s.instance_counts["Each"], rises);
+    }
+}
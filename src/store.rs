@@ -2,7 +2,9 @@ use std::collections::BTreeMap;
 use std::rc::Rc;
 
 use super::values::*;
+use super::lambda::*;
 use super::ast;
+use super::syntax;
 
 #[derive(Debug)]
 pub struct ChannelRecord {
@@ -24,15 +26,17 @@ impl Store {
     pub fn new() -> Store {
         Store {chans : BTreeMap::new(), defs : BTreeMap::new(), instance_counts : BTreeMap::new()}
     }
+    // a restricted channel is added as soon as its scope is built, before the actions under
+    // it are counted, and again when the restriction is lifted; the second time keeps the counts
     pub fn add_channel(&mut self, name : &str, rate : f64) {
-        self.chans.insert(name.to_string(),
+        self.chans.entry(name.to_string()).or_insert(
             ChannelRecord {
                 rate: rate,
                 incount : 0,
                 outcount : 0,
                 mixcount : 0,
                 ax : 0.0
-            });
+            }).rate = rate;
     }
     pub fn activities(&self) -> Vec<(String, f64)> {
         fn activity ((k, c) : (&str, &ChannelRecord)) -> Option<(String, f64)> {
@@ -65,11 +69,32 @@ impl Store {
             });
         }
     }
+    pub fn observables(&self) -> Vec<String> {
+        self.defs.keys().cloned().collect()
+    }
     pub fn create(&mut self, instance_name : String) {
         *self.instance_counts.entry(instance_name).or_insert(0) += 1;
     }
     pub fn destroy(&mut self, instance_name : String) {
         *self.instance_counts.entry(instance_name).or_insert(0) -= 1;
     }
+    // the body of a definition together with its parameters bound to the arguments;
+    // the body itself is shared, not copied
+    pub fn instantiate(&self, name : &str, args : &[Lambda]) -> Result<(Rc<ast::Process>, Env), String> {
+        let (pats, body) = match self.defs.get(name) {
+            Some (d) => d,
+            None => return Err (format!("{} is not defined", name))
+        };
+        if pats.len() != args.len() {
+            return Err (format!("{} takes {} parameters but is given {}", name, pats.len(), args.len()));
+        }
+        let mut env = Env::new();
+        for (pat, v) in pats.iter().zip(args.iter()) {
+            env = env.extend(destructure(pat, v).map_err(|e| format!("in {}: {}", name, e))?);
+        }
+        ast::Process::settle(body, env).map_err(|e| format!("in {}: {}", name, e))
+    }
+    pub fn holds(&self, p : &syntax::Predicate) -> bool {
+        p.eval(&|n| *self.instance_counts.get(n).unwrap_or(&0) as f64)
+    }
 }
-
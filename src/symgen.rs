@@ -21,3 +21,11 @@ pub fn reset() {
     SYMGEN.lock().unwrap().counter = 0;
 }
 
+
+pub fn counter() -> usize {
+    SYMGEN.lock().unwrap().counter
+}
+
+pub fn set(n : usize) {
+    SYMGEN.lock().unwrap().counter = n;
+}
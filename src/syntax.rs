@@ -1,4 +1,5 @@
 use std::rc::Rc;
+use std::fmt;
 
 use super::values::*;
 use super::lambda::*;
@@ -8,7 +9,78 @@ pub enum Declaration {
     NewChannel (String, f64),
     Run (Rc<Process>),
     Val (Pattern, Lambda),
-    Def (String, Vec<Pattern>, Rc<Process>)
+    Def (String, Vec<Pattern>, Rc<Process>),
+    Type (String, Vec<(String, Vec<String>)>),
+    Directive (f64, Directive),
+    Trigger (Trigger)
+}
+
+#[derive(Clone, Debug)]
+pub enum Directive {
+    Run (Rc<Process>),
+    Kill (String)
+}
+
+#[derive(Clone, Debug)]
+pub enum Observable {
+    Count (String),
+    Const (f64),
+    BinExpr (BinOp, Rc<Observable>, Rc<Observable>)
+}
+
+#[derive(Clone, Debug)]
+pub enum Predicate {
+    Compare (BinOp, Observable, Observable),
+    And (Rc<Predicate>, Rc<Predicate>),
+    Or (Rc<Predicate>, Rc<Predicate>)
+}
+
+#[derive(Clone, Debug)]
+pub struct Trigger {
+    pub pred : Predicate,
+    pub delay : f64,
+    pub repeat : bool,
+    pub action : Directive
+}
+
+impl Observable {
+    pub fn eval<F : Fn(&str) -> f64>(&self, count : &F) -> f64 {
+        match self {
+            Observable::Count (n) => count(n),
+            Observable::Const (c) => *c,
+            Observable::BinExpr (b, l, r) => {
+                let (x, y) = (l.eval(count), r.eval(count));
+                match b {
+                    BinOp::Plus => x + y,
+                    BinOp::Sub => x - y,
+                    BinOp::Times => x * y,
+                    BinOp::Div => x / y,
+                    _ => panic!("{} is not an arithmetic operator", b)
+                }
+            }
+        }
+    }
+}
+
+impl Predicate {
+    pub fn eval<F : Fn(&str) -> f64>(&self, count : &F) -> bool {
+        match self {
+            Predicate::Compare (b, l, r) => {
+                let (x, y) = (l.eval(count), r.eval(count));
+                match b {
+                    BinOp::Equal => x == y,
+                    BinOp::Less => x < y,
+                    BinOp::Greater => x > y,
+                    BinOp::LEq => x <= y,
+                    BinOp::GEq => x >= y,
+                    BinOp::NotEqual => x != y,
+                    _ => panic!("{} is not a comparison operator", b)
+                }
+            },
+            Predicate::And (p1, p2) => p1.eval(count) && p2.eval(count),
+            Predicate::Or (p1, p2) => p1.eval(count) || p2.eval(count)
+        }
+    }
 }
 
 pub type Summ = Vec<(Act, Rc<Process>)>;
@@ -28,6 +100,7 @@ pub enum Process {
     Instance (String, Vec<Lambda>),
     Repetition (usize, Rc<Process>),
     Replication (Act, Rc<Process>),
+    Match (Lambda, Vec<(Pattern, Rc<Process>)>),
     Termination
 }
 
@@ -37,3 +110,260 @@ pub enum Program {
 }
 
 
+
+fn join<T : fmt::Display>(v : &[T], sep : &str) -> String {
+    v.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(sep)
+}
+
+impl fmt::Display for Act {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Act::Input (c) => write!(f, "?{};", c),
+            Act::Output (c) => write!(f, "!{};", c)
+        }
+    }
+}
+
+impl fmt::Display for Process {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Process::Restriction (c, r, p) => write!(f, "let new {}@{} in {}", c, float_literal(*r), p),
+            Process::LetVal (pat, l, p) => write!(f, "val {} = {} in {}", pat, l.atom(), p),
+            Process::Parallel (ps) => match ps.len() {
+                0 => write!(f, "end"),
+                1 => write!(f, "{}", ps[0]),
+                _ => {
+                    let qs : Vec<String> = ps.iter().map(|q| q.bounded("|")).collect();
+                    write!(f, "({})", qs.join(" | "))
+                }
+            },
+            Process::Action (a, p) => write!(f, "{} {}", a, p),
+            Process::Choice (c) => {
+                let last = c.len() - 1;
+                let bs : Vec<String> = c.iter().enumerate()
+                    .map(|(i, (a, p))| if i < last { format!("{} {}", a, p.bounded("or")) } else { format!("{} {}", a, p) })
+                    .collect();
+                write!(f, "do {}", bs.join(" or "))
+            },
+            Process::Instance (n, params) => {
+                let ps : Vec<String> = params.iter().map(|l| l.atom()).collect();
+                write!(f, "{}({})", n, ps.join(", "))
+            },
+            Process::Repetition (i, p) => write!(f, "{} of {}", i, p),
+            Process::Replication (a, p) => write!(f, "replicate {} {}", a, p),
+            Process::Match (l, arms) => {
+                let cs : Vec<String> = arms.iter().map(|(pat, q)| format!("{} => {}", pat, q.bounded("|"))).collect();
+                write!(f, "match {} with {}", l, cs.join(" | "))
+            },
+            Process::Termination => write!(f, "end")
+        }
+    }
+}
+
+impl Process {
+    // a match runs on to the next `|` and a choice to the next `or`, and so does any process
+    // whose last continuation is one of them, so these need parentheses wherever `sep` can follow
+    fn bounded(&self, sep : &str) -> String {
+        if self.runs_on(sep) {
+            format!("({})", self)
+        }
+        else {
+            self.to_string()
+        }
+    }
+
+    fn runs_on(&self, sep : &str) -> bool {
+        match self {
+            Process::Match (_, arms) => sep == "|" || arms.last().map_or(false, |(_, q)| q.runs_on(sep)),
+            Process::Choice (c) => sep == "or" || c.last().map_or(false, |(_, q)| q.runs_on(sep)),
+            Process::Restriction (_, _, p) | Process::LetVal (_, _, p) | Process::Action (_, p)
+                | Process::Repetition (_, p) | Process::Replication (_, p) => p.runs_on(sep),
+            Process::Parallel (_) | Process::Instance (_, _) | Process::Termination => false
+        }
+    }
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Directive::Run (p) => write!(f, "run {}", p),
+            Directive::Kill (n) => write!(f, "kill {}(_)", n)
+        }
+    }
+}
+
+impl fmt::Display for Observable {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Observable::Count (n) => write!(f, "{}()", n),
+            Observable::Const (c) => write!(f, "{}", c),
+            Observable::BinExpr (b, l, r) => {
+                let operand = |o : &Observable, right : bool| match o {
+                    Observable::BinExpr (c, _, _) if c.precedence() < b.precedence() || (right && c.precedence() == b.precedence()) => format!("({})", o),
+                    _ => o.to_string()
+                };
+                write!(f, "{} {} {}", operand(l, false), b, operand(r, true))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Predicate::Compare (b, l, r) => write!(f, "{} {} {}", l, b, r),
+            Predicate::And (p1, p2) => write!(f, "{} and {}", p1, p2),
+            Predicate::Or (p1, p2) => write!(f, "{} or {}", p1, p2)
+        }
+    }
+}
+
+impl fmt::Display for Declaration {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Declaration::NewChannel (c, r) => write!(f, "new {}@{}", c, float_literal(*r)),
+            Declaration::Run (p) => write!(f, "run {}", p),
+            Declaration::Val (pat, l) => write!(f, "val {} = {}", pat, l.atom()),
+            Declaration::Def (n, pats, p) => match &**p {
+                Process::Choice (bs) => {
+                    write!(f, "let {} ({}) =", n, join(pats, ", "))?;
+                    for (i, (a, q)) in bs.iter().enumerate() {
+                        let q = if i + 1 < bs.len() { q.bounded("or") } else { q.to_string() };
+                        write!(f, "\n    {} {} {}", if i == 0 { "do" } else { "or" }, a, q)?;
+                    }
+                    Ok (())
+                },
+                Process::Match (l, arms) => {
+                    write!(f, "let {} ({}) = match {} with", n, join(pats, ", "), l)?;
+                    for (pat, q) in arms.iter() {
+                        write!(f, "\n    | {} => {}", pat, q.bounded("|"))?;
+                    }
+                    Ok (())
+                },
+                _ => write!(f, "let {} ({}) = {}", n, join(pats, ", "), p)
+            },
+            Declaration::Type (n, cs) => {
+                let cs : Vec<String> = cs.iter().map(|(c, fields)| match fields.len() {
+                    0 => c.clone(),
+                    _ => format!("{} of {}", c, fields.join(" * "))
+                }).collect();
+                write!(f, "type {} = {}", n, cs.join(" | "))
+            },
+            Declaration::Directive (t, d) => write!(f, "directive at {} {}", float_literal(*t), d),
+            Declaration::Trigger (t) => {
+                write!(f, "{} {}", if t.repeat { "whenever" } else { "when" }, t.pred)?;
+                if t.delay > 0.0 {
+                    write!(f, " after {}", float_literal(t.delay))?;
+                }
+                write!(f, " {}", t.action)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Program::Prog (decs) => {
+                fn kind(d : &Declaration) -> u8 {
+                    match d {
+                        Declaration::Type (_, _) => 0,
+                        Declaration::NewChannel (_, _) => 1,
+                        Declaration::Val (_, _) => 2,
+                        Declaration::Def (_, _, _) => 3,
+                        Declaration::Run (_) => 4,
+                        Declaration::Directive (_, _) | Declaration::Trigger (_) => 5
+                    }
+                }
+                for (i, d) in decs.iter().enumerate() {
+                    if i > 0 && kind(d) != kind(&decs[i - 1]) {
+                        writeln!(f)?;
+                    }
+                    writeln!(f, "{}", d)?;
+                }
+                Ok (())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // printing a program and reading it back gives the same tree, and printing that again the same text
+    fn round_trip(src : &str) {
+        let p = crate::parse(src).unwrap_or_else(|e| panic!("{}: {}", src, e));
+        let text = p.to_string();
+        let q = crate::parse(&text).unwrap_or_else(|e| panic!("{} does not read back: {}", text, e));
+        assert_eq!(format!("{:?}", p), format!("{:?}", q), "{}", text);
+        assert_eq!(q.to_string(), text);
+    }
+
+    #[test]
+    fn models_round_trip() {
+        round_trip(include_str!("../test.spi"));
+        round_trip(include_str!("../test2.spi"));
+        round_trip("new a@1.0\n\
+            new b@0.5\n\
+            let P (x, (y, _)) = do !a; P(x, (y, 1)) or ?b; (Q() | 2 of P(0, (1, 2)))\n\
+            let Q () = replicate ?a; let new c@2.0 in (!c; end | ?c; Q())\n\
+            directive at 1.5 run Q()\n\
+            when P() < 10 after 0.5 run 3 of Q()\n\
+            run (P(1, (2, 3)) | Q())");
+    }
+
+    #[test]
+    fn operators_keep_their_precedence_and_associativity() {
+        round_trip("new a@1.0\n\
+            let P (x) = val y = (x - (1 - 2) - 3 * (4 + 5) / 6 / (7 * 8)) in \
+            val z = ((x < 1) = (2 >= x)) in \
+            val w = ((x + 1) * 2 - x * (2 - 1)) in \
+            val v = (x <> 1 + 2) in !a; P(y)\n\
+            run P(0)");
+    }
+
+    #[test]
+    fn negative_literals_round_trip() {
+        round_trip("new a@1.0\n\
+            let P (x) = val y = (1 - -2 * -3.5) in val z = min(-1, x - 1) in val w = -1e-3 in !a; P(-4)\n\
+            run P(-1)");
+    }
+
+    #[test]
+    fn string_literals_round_trip() {
+        round_trip("new a@1.0\n\
+            let P (x) = val s = (\"say \\\"hi\\\"\\n\" + x) in val t = (\"// /* \\\\\" < s) in !a; P(\"\")\n\
+            run P(\"x\")");
+    }
+
+    #[test]
+    fn nested_matches_round_trip() {
+        round_trip("type t = A | B of t | C of int * t\n\
+            new a@1.0\n\
+            let P (x) = match x with\n\
+                A => end\n\
+              | B(y) => val n = (match y with A => (match x with B(A) => 1 | _ => 2) | B(_) => 3 | C(k, _) => k) in !a; P(y)\n\
+              | C(k, y) => match y with A => !a; P(A) | _ => (match x with C(_, A) => end | _ => !a; P(y))\n\
+            run P(B(C(1, A)))");
+    }
+
+    #[test]
+    fn curried_applications_round_trip() {
+        round_trip("new a@1.0\n\
+            let P (x) = val f = (fun x => fun y => x * y) in \
+            val g = f(2)(3) in val h = f(2, x) in val k = (fun z => z + 1)(x) in \
+            val m = map(f(2), [1, 2, 3]) in \
+            val n = (let rec go i j = if i = 0 then j else go(i - 1, j + 1) in go(x, 0)) in !a; P(n)\n\
+            run P(3)");
+    }
+
+    #[test]
+    fn nested_choices_round_trip() {
+        round_trip("type t = A | B\n\
+            new a@1.0\n\
+            new b@1.0\n\
+            let P () = do ?a; (do ?b; P() or !b; P()) or !a; P()\n\
+            let Q (x) = do ?a; let new c@1.0 in (do ?c; Q(x) or !c; Q(x)) or ?b; (match x with A => end | B => do ?a; Q(A) or !a; Q(B))\n\
+            let R (x) = match x with A => (?a; match x with A => R(B) | B => end) | B => (do ?a; R(A) or !b; match x with A => end | B => R(A))\n\
+            run (P() | Q(A) | R(B))");
+    }
+}
@@ -1,8 +1,10 @@
 use std::convert::TryFrom;
-use combine::{optional, Stream, Parser, parser, many1, many};
-use combine::error::{ParseError};
-use combine::char::{space, alpha_num, letter, digit};
-use combine::parser::item::{satisfy, satisfy_map};
+use combine::{optional, Stream, Parser, parser, many1, many, between, attempt, skip_many};
+use combine::error::{ParseError, StreamError};
+use combine::char::{space, alpha_num, letter, digit, string};
+use combine::parser::item::{satisfy, satisfy_map, any, position, one_of};
+use combine::parser::repeat::skip_until;
+use combine::stream::StreamErrorFor;
 
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum Keyword {
@@ -12,6 +14,10 @@ pub enum Keyword {
     Then,
     Else,
     Fun,
+    Rec,
+    Match,
+    With,
+    Type,
     New,
     Let,
     Val,
@@ -22,7 +28,13 @@ pub enum Keyword {
     Of,
     Replicate,
     Run,
-    End
+    End,
+    Directive,
+    At,
+    Kill,
+    When,
+    Whenever,
+    After
 }
 
 impl TryFrom<&str> for Keyword {
@@ -32,9 +44,13 @@ impl TryFrom<&str> for Keyword {
             "true" => Ok (Keyword::True),
             "false" => Ok (Keyword::False),
             "if" => Ok (Keyword::If),
-            "then" => Ok (Keyword::If),
+            "then" => Ok (Keyword::Then),
             "else" => Ok (Keyword::Else),
             "fun" => Ok (Keyword::Fun),
+            "rec" => Ok (Keyword::Rec),
+            "match" => Ok (Keyword::Match),
+            "with" => Ok (Keyword::With),
+            "type" => Ok (Keyword::Type),
             "new" => Ok (Keyword::New),
             "let" => Ok (Keyword::Let),
             "val" => Ok (Keyword::Val),
@@ -46,6 +62,12 @@ impl TryFrom<&str> for Keyword {
             "replicate" => Ok(Keyword::Replicate),
             "run" => Ok(Keyword::Run),
             "end" => Ok (Keyword::End),
+            "directive" => Ok (Keyword::Directive),
+            "at" => Ok (Keyword::At),
+            "kill" => Ok (Keyword::Kill),
+            "when" => Ok (Keyword::When),
+            "whenever" => Ok (Keyword::Whenever),
+            "after" => Ok (Keyword::After),
             _ => Err (())
         }
     }
@@ -64,6 +86,8 @@ pub enum Token {
     NotEqual,
     LPar,
     RPar,
+    LBracket,
+    RBracket,
     Colon,
     Semicolon,
     Pipe,
@@ -76,47 +100,63 @@ pub enum Token {
     RightArrow,
     Integer (i64),
     Float (f64),
+    Str (String),
     Keyword (Keyword),
     Identifier (String)
 }
 
-fn white_space<I>() -> impl Parser<Input = I>
+fn white_space<I>() -> impl Parser<Input = I, Output = ()>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    many(space()).map(|x : Vec<char>| x.into_iter().collect::<Vec<char>>())
-}
+    let line = attempt(string("//")).with(skip_many(satisfy(|c : char| c != '\n')));
+    let block = attempt(string("/*")).with(skip_until(attempt(string("*/")))).skip(string("*/"));
+    skip_many(space().map(|_| ()).or(line).or(block))
+}
+
+// longest operators first, so that the first match is the maximal munch
+const OPERATORS : [(&str, Token); 19] = [
+    ("<=", Token::LEq),
+    (">=", Token::GEq),
+    ("<>", Token::NotEqual),
+    ("=>", Token::RightArrow),
+    ("?", Token::QMark),
+    ("!", Token::ExMark),
+    ("@", Token::At),
+    ("=", Token::Equals),
+    ("<", Token::Less),
+    (">", Token::Greater),
+    (":", Token::Colon),
+    (";", Token::Semicolon),
+    ("|", Token::Pipe),
+    ("_", Token::Underscore),
+    (",", Token::Comma),
+    ("+", Token::Plus),
+    ("-", Token::Dash),
+    ("*", Token::Star),
+    ("/", Token::Slash)
+];
 
 parser! {
 fn symbol[I]()(I) -> Token
 where [I: Stream<Item = char>]
 {
-    let symbols = vec!['?', '!', '@', '=', ';', '|', '_', ',', '+', '-', '*', '/'];
-    many1(combine::parser::item::one_of(symbols)).skip(white_space()).map(|c : String| {
-        match c.as_str() {
-            "?" => Token::QMark,
-            "!" => Token::ExMark,
-            "@" => Token::At,
-            "=" => Token::Equals,
-            "<" => Token::Less,
-            ">" => Token::Greater,
-            "<=" => Token::LEq,
-            ">=" => Token::GEq,
-            "<>" => Token::NotEqual,
-            ":" => Token::Semicolon,
-            ";" => Token::Semicolon,
-            "|" => Token::Pipe,
-            "_" => Token::Underscore,
-            "," => Token::Comma,
-            "+" => Token::Plus,
-            "-" => Token::Dash,
-            "*" => Token::Star,
-            "/" => Token::Slash,
-            "=>" => Token::RightArrow,
-            _ => panic!("unexpected symbol {}", c)
+    combine::parser::function::parser(|input : &mut I| {
+        let mut last = None;
+        for (op, t) in OPERATORS.iter() {
+            let before = input.checkpoint();
+            match string(op).parse_stream(input) {
+                Ok ((_, consumed)) => return Ok ((t.clone(), consumed)),
+                Err (e) => {
+                    input.reset(before);
+                    last = Some (e.into_inner());
+                }
+            }
         }
+        Err (combine::error::Consumed::Empty (last.unwrap()))
     })
+    .skip(white_space())
 }
 }
 
@@ -144,6 +184,22 @@ where [I: Stream<Item = char>]
 }
 }
 
+parser! {
+fn leftbracket[I]()(I) -> Token
+where [I: Stream<Item = char>]
+{
+    lex_char('[').map(|_| Token::LBracket)
+}
+}
+
+parser! {
+fn rightbracket[I]()(I) -> Token
+where [I: Stream<Item = char>]
+{
+    lex_char(']').map(|_| Token::RBracket)
+}
+}
+
 parser! {
 fn number[I]()(I) -> Token
 where [I: Stream<Item = char>,
@@ -152,22 +208,49 @@ where [I: Stream<Item = char>,
       <I as combine::StreamOnce>::Range: std::fmt::Display,
       I: combine::RangeStreamOnce]
 {
-    (many1(digit()),
-    optional((lex_char('.'), many1(digit()))))
+    let fraction = attempt((combine::parser::char::char('.'), many1(digit())));
+    let exponent = attempt((one_of("eE".chars()), optional(one_of("+-".chars())), many1(digit())));
+    (many1(digit()), optional(fraction), optional(exponent))
         .skip(white_space())
-        .map(|(i, f) : (String, Option<(char, String)>)| {
-            match f {
-                Some ((_, s)) => {
-                    let mut ii = i.clone();
-                    ii.push_str(&s);
-                    Token::Float (ii.parse::<f64>().unwrap())
-                },
-                None => Token::Integer (i.parse::<i64>().unwrap())
+        .and_then(|(i, f, e) : (String, Option<(char, String)>, Option<(char, Option<char>, String)>)| {
+            let float = f.is_some() || e.is_some();
+            let mut n = i;
+            if let Some ((_, s)) = f {
+                n.push('.');
+                n.push_str(&s);
+            }
+            if let Some ((_, sign, s)) = e {
+                n.push('e');
+                n.extend(sign);
+                n.push_str(&s);
+            }
+            if float {
+                Ok (Token::Float (n.parse::<f64>().unwrap()))
+            }
+            else {
+                n.parse::<i64>().map(Token::Integer)
+                    .map_err(|_| StreamErrorFor::<I>::message_static_message("integer literal out of range"))
             }
         })
 }
 }
 
+parser! {
+fn string_literal[I]()(I) -> Token
+where [I: Stream<Item = char>]
+{
+    let escape = combine::parser::char::char('\\').with(any()).map(|c| match c {
+        'n' => '\n',
+        't' => '\t',
+        c => c
+    });
+    let plain = satisfy(|c : char| c != '"' && c != '\\');
+    between(combine::parser::char::char('"'), combine::parser::char::char('"'), many(plain.or(escape)))
+        .skip(white_space())
+        .map(|s : String| Token::Str (s))
+}
+}
+
 parser! {
 fn ip[I]()(I) -> String
 where [I: Stream<Item = char>,
@@ -176,11 +259,12 @@ where [I: Stream<Item = char>,
       <I as combine::StreamOnce>::Range: std::fmt::Display,
       I: combine::RangeStreamOnce]
 {
-    (many1(letter()), many(alpha_num()))
+    let rest = alpha_num().or(combine::parser::char::char('_')).or(combine::parser::char::char('\''));
+    (letter(), many(rest))
         .skip(white_space())
-        .map(|s : (String, String)| {
-            let mut t = s.0.clone();
-            t.push_str(&s.1);
+        .map(|(c, s) : (char, String)| {
+            let mut t = c.to_string();
+            t.push_str(&s);
             t
         })
 }
@@ -218,10 +302,13 @@ where [I: Stream<Item = char>,
       I: combine::RangeStreamOnce]
 {
     number()
+    .or(string_literal())
     .or(identifier())
     .or(symbol())
     .or(leftparen())
     .or(rightparen())
+    .or(leftbracket())
+    .or(rightbracket())
     .skip(white_space())
 }
 }
@@ -234,8 +321,47 @@ where [I: Stream<Item = char>,
       <I as combine::StreamOnce>::Range: std::fmt::Display,
       I: combine::RangeStreamOnce]
 {
-    many(tok())
+    white_space().with(many(tok()))
+}
+}
+
+// the line and column where the first comment starts; a // or /* inside a string literal opens none
+pub fn first_comment(src : &str) -> Option<(usize, usize)> {
+    let (mut line, mut column) = (1, 1);
+    let mut quoted = false;
+    let mut cs = src.chars().peekable();
+    while let Some (c) = cs.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => {
+                if let Some ('\n') = cs.next() {
+                    line += 1;
+                    column = 0;
+                }
+                else {
+                    column += 1;
+                }
+            },
+            '/' if !quoted && (cs.peek() == Some (&'/') || cs.peek() == Some (&'*')) => return Some ((line, column)),
+            '\n' => {
+                line += 1;
+                column = 0;
+            },
+            _ => ()
+        }
+        column += 1;
+    }
+    None
 }
+
+pub fn tokenize_spanned<I>() -> impl Parser<Input = I, Output = Vec<(I::Position, Token)>>
+where I: Stream<Item = char>,
+      I::Error: ParseError<I::Item, I::Range, I::Position>,
+      <I as combine::StreamOnce>::Range: combine::stream::Range,
+      <I as combine::StreamOnce>::Range: std::fmt::Display,
+      I: combine::RangeStreamOnce
+{
+    white_space().with(many((position(), tok())))
 }
 
 
@@ -305,6 +431,28 @@ where [I: Stream<Item = Token>,
 }
 }
 
+parser! {
+pub fn lbracket[I]()(I) -> Token
+where [I: Stream<Item = Token>,
+      I::Error: ParseError<I::Item, I::Range, I::Position>,
+      <I as combine::StreamOnce>::Range: combine::stream::Range,
+      I: combine::StreamOnce]
+{
+    satisfy(|t : Token| { match t { Token::LBracket => true, _ => false } })
+}
+}
+
+parser! {
+pub fn rbracket[I]()(I) -> Token
+where [I: Stream<Item = Token>,
+      I::Error: ParseError<I::Item, I::Range, I::Position>,
+      <I as combine::StreamOnce>::Range: combine::stream::Range,
+      I: combine::StreamOnce]
+{
+    satisfy(|t : Token| { match t { Token::RBracket => true, _ => false } })
+}
+}
+
 parser! {
 pub fn colon[I]()(I) -> Token
 where [I: Stream<Item = Token>,
@@ -338,6 +486,17 @@ where [I: Stream<Item = Token>,
 }
 }
 
+parser! {
+pub fn star[I]()(I) -> Token
+where [I: Stream<Item = Token>,
+      I::Error: ParseError<I::Item, I::Range, I::Position>,
+      <I as combine::StreamOnce>::Range: combine::stream::Range,
+      I: combine::StreamOnce]
+{
+    satisfy(|t : Token| { match t { Token::Star => true, _ => false } })
+}
+}
+
 parser! {
 pub fn underscore[I]()(I) -> Token
 where [I: Stream<Item = Token>,
@@ -393,6 +552,24 @@ where [I: Stream<Item = Token>,
 }
 }
 
+parser! {
+pub fn cmpop[I]()(I) -> Token
+where [I: Stream<Item = Token>,
+      I::Error: ParseError<I::Item, I::Range, I::Position>,
+      <I as combine::StreamOnce>::Range: combine::stream::Range,
+      I: combine::StreamOnce]
+{
+    satisfy(|t : Token| { match t { 
+        Token::Equals |
+        Token::Less |
+        Token::Greater |
+        Token::LEq |
+        Token::GEq |
+        Token::NotEqual => true, 
+        _ => false } })
+}
+}
+
 parser! {
 pub fn integer[I]()(I) -> i64
 where [I: Stream<Item = Token>,
@@ -417,6 +594,17 @@ where [I: Stream<Item = Token>,
 }
 }
 
+parser! {
+pub fn strlit[I]()(I) -> String
+where [I: Stream<Item = Token>,
+      I::Error: ParseError<I::Item, I::Range, I::Position>,
+      <I as combine::StreamOnce>::Range: combine::stream::Range,
+      I: combine::StreamOnce]
+{
+    satisfy_map(|t : Token| { match t { Token::Str (s) => Some (s), _ => None } })
+}
+}
+
 parser! {
 pub fn keyword[I](k : Keyword)(I) -> Token
 where [I: Stream<Item = Token>,
@@ -441,3 +629,143 @@ where [I: Stream<Item = Token>,
 }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use combine::Parser;
+    use combine::stream::state::State;
+    use super::*;
+    use super::Keyword;
+    use super::Token::*;
+
+    fn spanned(src : &str) -> Vec<((i32, i32), Token)> {
+        let (toks, rest) = tokenize_spanned().easy_parse(State::new(src)).unwrap();
+        assert!(rest.input.is_empty(), "{} was not fully lexed", src);
+        toks.into_iter().map(|(p, t)| ((p.line, p.column), t)).collect()
+    }
+
+    fn lex(src : &str) -> Vec<Token> {
+        spanned(src).into_iter().map(|(_, t)| t).collect()
+    }
+
+    fn ident(s : &str) -> Token {
+        Identifier (s.to_string())
+    }
+
+    #[test]
+    fn every_operator_lexes_alone() {
+        for (op, t) in OPERATORS.iter() {
+            assert_eq!(lex(op), vec![t.clone()], "{}", op);
+        }
+    }
+
+    #[test]
+    fn every_operator_lexes_between_operands() {
+        for (op, t) in OPERATORS.iter() {
+            assert_eq!(lex(&format!("7{}8", op)), vec![Integer (7), t.clone(), Integer (8)], "{}", op);
+            assert_eq!(lex(&format!("x {} y", op)), vec![ident("x"), t.clone(), ident("y")], "{}", op);
+        }
+    }
+
+    #[test]
+    fn longer_operators_win() {
+        assert_eq!(lex("a<=b"), vec![ident("a"), LEq, ident("b")]);
+        assert_eq!(lex("a<b"), vec![ident("a"), Less, ident("b")]);
+        assert_eq!(lex("a< =b"), vec![ident("a"), Less, Equals, ident("b")]);
+        assert_eq!(lex("a>=b"), vec![ident("a"), GEq, ident("b")]);
+        assert_eq!(lex("a<>b"), vec![ident("a"), NotEqual, ident("b")]);
+        assert_eq!(lex("a> <b"), vec![ident("a"), Greater, Less, ident("b")]);
+        assert_eq!(lex("a><b"), vec![ident("a"), Greater, Less, ident("b")]);
+        assert_eq!(lex("p=>q"), vec![ident("p"), RightArrow, ident("q")]);
+        assert_eq!(lex("<<="), vec![Less, LEq]);
+        assert_eq!(lex("<=>"), vec![LEq, Greater]);
+    }
+
+    #[test]
+    fn operators_after_actions_stay_apart() {
+        assert_eq!(lex("!c;-1"), vec![ExMark, ident("c"), Semicolon, Dash, Integer (1)]);
+        assert_eq!(lex("?c;!d"), vec![QMark, ident("c"), Semicolon, ExMark, ident("d")]);
+        assert_eq!(lex("|_,"), vec![Pipe, Underscore, Comma]);
+        assert_eq!(lex("+-*/"), vec![Plus, Dash, Star, Slash]);
+    }
+
+    #[test]
+    fn colon_is_not_a_semicolon() {
+        assert_eq!(lex("x : int; y"), vec![ident("x"), Colon, ident("int"), Semicolon, ident("y")]);
+    }
+
+    #[test]
+    fn keywords_lex_as_themselves() {
+        assert_eq!(lex("if c then 1 else 2"),
+            vec![Keyword (Keyword::If), ident("c"), Keyword (Keyword::Then), Integer (1), Keyword (Keyword::Else), Integer (2)]);
+        assert_eq!(lex("thence"), vec![ident("thence")]);
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        assert_eq!(lex("// nothing here"), vec![]);
+        assert_eq!(lex("a // b\nc"), vec![ident("a"), ident("c")]);
+        assert_eq!(lex("a /* b\n * c */ d"), vec![ident("a"), ident("d")]);
+        assert_eq!(lex("a/**/b"), vec![ident("a"), ident("b")]);
+        assert_eq!(lex("/* x */ 1 / 2 // y"), vec![Integer (1), Slash, Integer (2)]);
+        assert_eq!(first_comment("a / b\nc /* d */"), Some ((2, 3)));
+        assert_eq!(first_comment("a / b"), None);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(lex("42"), vec![Integer (42)]);
+        assert_eq!(lex("1e3"), vec![Float (1000.0)]);
+        assert_eq!(lex("1e-3"), vec![Float (0.001)]);
+        assert_eq!(lex("2.5E+2"), vec![Float (250.0)]);
+        assert_eq!(lex("-1"), vec![Dash, Integer (1)]);
+        assert_eq!(lex("3e"), vec![Integer (3), ident("e")]);
+    }
+
+    #[test]
+    fn integers_too_large_for_i64_are_errors() {
+        assert_eq!(lex("9223372036854775807"), vec![Integer (i64::max_value())]);
+        let e = tokenize_spanned().easy_parse(State::new("x = 99999999999999999999")).unwrap_err();
+        assert_eq!((e.position.line, e.position.column), (1, 5));
+        assert!(e.to_string().contains("integer literal out of range"), "{}", e);
+    }
+
+    #[test]
+    fn string_literals() {
+        assert_eq!(lex(r#""" "a b""#), vec![Str ("".to_string()), Str ("a b".to_string())]);
+        assert_eq!(lex(r#""say \"hi\"\n\t\\""#), vec![Str ("say \"hi\"\n\t\\".to_string())]);
+        assert_eq!(lex(r#"f("// not /* a comment")"#), vec![ident("f"), LPar, Str ("// not /* a comment".to_string()), RPar]);
+        assert_eq!(first_comment(r#"val s = "a // b" // c"#), Some ((1, 18)));
+        assert_eq!(first_comment("val s = \"a \\\" /* b\"\n/*"), Some ((2, 1)));
+    }
+
+    // the decimal point used to be dropped when the fraction was joined on, so 1.5 read as 15.0
+    #[test]
+    fn fractions_keep_their_decimal_point() {
+        assert_eq!(lex("1.5"), vec![Float (1.5)]);
+        assert_eq!(lex("0.25 10.0"), vec![Float (0.25), Float (10.0)]);
+        assert_eq!(lex("3.75e1"), vec![Float (37.5)]);
+    }
+
+    #[test]
+    fn identifiers_take_primes_and_underscores() {
+        assert_eq!(lex("x' na_plus x_1''"), vec![ident("x'"), ident("na_plus"), ident("x_1''")]);
+        assert_eq!(lex("_x"), vec![Underscore, ident("x")]);
+    }
+
+    #[test]
+    fn tokens_carry_their_positions() {
+        assert_eq!(spanned("  let P () =\n  // note\n\t!a; P()"), vec![
+            ((1, 3), Keyword (Keyword::Let)),
+            ((1, 7), ident("P")),
+            ((1, 9), LPar),
+            ((1, 10), RPar),
+            ((1, 12), Equals),
+            ((3, 2), ExMark),
+            ((3, 3), ident("a")),
+            ((3, 4), Semicolon),
+            ((3, 6), ident("P")),
+            ((3, 7), LPar),
+            ((3, 8), RPar)]);
+    }
+}
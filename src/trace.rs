@@ -0,0 +1,75 @@
+
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub time : f64,
+    pub chan : String,
+    pub input : String,
+    pub inindex : (usize, usize),
+    pub output : String,
+    pub outindex : (usize, usize)
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} {}.{} {} {}.{}",
+            self.time, self.chan,
+            self.input, self.inindex.0, self.inindex.1,
+            self.output, self.outindex.0, self.outindex.1)
+    }
+}
+
+fn index(s : &str) -> Result<(usize, usize), String> {
+    let mut it = s.splitn(2, '.');
+    match (it.next().map(|x| x.parse::<usize>()), it.next().map(|x| x.parse::<usize>())) {
+        (Some (Ok (i)), Some (Ok (j))) => Ok ((i, j)),
+        _ => Err (format!("malformed branch index {}", s))
+    }
+}
+
+impl FromStr for Event {
+    type Err = String;
+    fn from_str(s : &str) -> Result<Event, String> {
+        let fields : Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 6 {
+            return Err (format!("expected 6 fields in trace line: {}", s));
+        }
+        let time = fields[0].parse::<f64>().map_err(|_| format!("malformed time {}", fields[0]))?;
+        Ok (Event {
+            time : time,
+            chan : fields[1].to_string(),
+            input : fields[2].to_string(),
+            inindex : index(fields[3])?,
+            output : fields[4].to_string(),
+            outindex : index(fields[5])?
+        })
+    }
+}
+
+pub struct Log {
+    w : BufWriter<File>
+}
+
+impl Log {
+    pub fn new<P : AsRef<Path>>(path : P) -> Log {
+        Log { w : BufWriter::new(File::create(path).unwrap()) }
+    }
+    pub fn write(&mut self, e : &Event) {
+        writeln!(self.w, "{}", e).unwrap();
+    }
+}
+
+pub fn read<P : AsRef<Path>>(path : P) -> Result<Vec<Event>, String> {
+    let s = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    s.lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(n, l)| l.parse::<Event>().map_err(|e| format!("line {}: {}", n + 1, e)))
+        .collect()
+}
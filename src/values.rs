@@ -1,14 +1,18 @@
 use std::rc::Rc;
-
-pub trait Substitutable<I> {
-    fn substitute (&self, src : &str, dest : I) -> Self;
-}
+use std::fmt;
 
 #[derive(Clone, Debug)]
 pub enum Pattern {
     Wildcard,
     Name (String),
-    Tuple (Vec<Pattern>)
+    Tuple (Vec<Pattern>),
+    Constructor (String, Vec<Pattern>)
+}
+
+// the parser reads a capitalised name as a constructor; datatypes::check turns the ones
+// no type declares back into variables
+pub fn is_constructor(n : &str) -> bool {
+    n.chars().next().map_or(false, |c| c.is_uppercase())
 }
 
 #[derive(Clone, Debug)]
@@ -17,10 +21,30 @@ pub enum Type {
     TVar,
     Integer,
     Float,
+    Str,
     Bool,
     Channel (Option<Rc<Type>>),
     Constructor (Vec<Type>),
     Tuple,
+    List,
     Function (Rc<Type>, Rc<Type>)
 }
 
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Wildcard => write!(f, "_"),
+            Pattern::Name (n) => write!(f, "{}", n),
+            Pattern::Tuple (pl) => {
+                let ps : Vec<String> = pl.iter().map(|p| p.to_string()).collect();
+                write!(f, "({})", ps.join(", "))
+            },
+            Pattern::Constructor (c, pl) if pl.is_empty() => write!(f, "{}", c),
+            Pattern::Constructor (c, pl) => {
+                let ps : Vec<String> = pl.iter().map(|p| p.to_string()).collect();
+                write!(f, "{}({})", c, ps.join(", "))
+            }
+        }
+    }
+}
@@ -0,0 +1,289 @@
+
+use std::fmt;
+
+#[derive(Clone, Debug)]
+pub enum Node {
+    Element (Element),
+    Text (String)
+}
+
+#[derive(Clone, Debug)]
+pub struct Element {
+    pub name : String,
+    pub attrs : Vec<(String, String)>,
+    pub children : Vec<Node>
+}
+
+pub fn escape(s : &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c)
+        }
+    }
+    out
+}
+
+impl Element {
+    pub fn new(name : &str) -> Element {
+        Element { name : name.to_string(), attrs : Vec::new(), children : Vec::new() }
+    }
+    pub fn attr<T : ToString>(mut self, k : &str, v : T) -> Element {
+        self.attrs.push((k.to_string(), v.to_string()));
+        self
+    }
+    pub fn child(mut self, e : Element) -> Element {
+        self.children.push(Node::Element (e));
+        self
+    }
+    pub fn text<T : ToString>(mut self, t : T) -> Element {
+        self.children.push(Node::Text (t.to_string()));
+        self
+    }
+    fn write(&self, f : &mut fmt::Formatter, indent : usize) -> fmt::Result {
+        let pad = "  ".repeat(indent);
+        write!(f, "{}<{}", pad, self.name)?;
+        for (k, v) in self.attrs.iter() {
+            write!(f, " {}=\"{}\"", k, escape(v))?;
+        }
+        if self.children.is_empty() {
+            return writeln!(f, "/>");
+        }
+        if let [Node::Text (t)] = self.children.as_slice() {
+            return writeln!(f, ">{}</{}>", escape(t), self.name);
+        }
+        writeln!(f, ">")?;
+        for c in self.children.iter() {
+            match c {
+                Node::Element (e) => e.write(f, indent + 1)?,
+                Node::Text (t) => writeln!(f, "{}  {}", pad, escape(t))?
+            }
+        }
+        writeln!(f, "{}</{}>", pad, self.name)
+    }
+}
+
+impl fmt::Display for Element {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+pub struct Document (pub Element);
+
+impl fmt::Display for Document {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+        self.0.write(f, 0)
+    }
+}
+
+impl Element {
+    pub fn get(&self, k : &str) -> Option<&str> {
+        self.attrs.iter().find(|(a, _)| a == k).map(|(_, v)| v.as_str())
+    }
+    pub fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|c| match c {
+            Node::Element (e) => Some (e),
+            Node::Text (_) => None
+        })
+    }
+    pub fn find(&self, name : &str) -> Option<&Element> {
+        self.elements().find(|e| e.name == name)
+    }
+    pub fn find_all<'a>(&'a self, name : &'a str) -> impl Iterator<Item = &'a Element> + 'a {
+        self.elements().filter(move |e| e.name == name)
+    }
+    pub fn content(&self) -> String {
+        let mut s = String::new();
+        for c in self.children.iter() {
+            if let Node::Text (t) = c {
+                s.push_str(t);
+            }
+        }
+        s.trim().to_string()
+    }
+}
+
+struct Reader<'a> {
+    src : &'a str,
+    pos : usize
+}
+
+impl<'a> Reader<'a> {
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+    fn line(&self) -> usize {
+        self.src[..self.pos].matches('\n').count() + 1
+    }
+    fn error<T>(&self, msg : &str) -> Result<T, String> {
+        Err (format!("line {}: {}", self.line(), msg))
+    }
+    fn skip_ws(&mut self) {
+        let r = self.rest();
+        self.pos += r.len() - r.trim_start().len();
+    }
+    fn skip_past(&mut self, end : &str) -> Result<(), String> {
+        match self.rest().find(end) {
+            Some (i) => {
+                self.pos += i + end.len();
+                Ok (())
+            },
+            None => self.error(&format!("missing {}", end))
+        }
+    }
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_ws();
+            let r = self.rest();
+            if r.starts_with("<?") {
+                self.skip_past("?>")?;
+            }
+            else if r.starts_with("<!--") {
+                self.skip_past("-->")?;
+            }
+            else if r.starts_with("<!") {
+                self.skip_past(">")?;
+            }
+            else {
+                return Ok (());
+            }
+        }
+    }
+    fn name(&mut self) -> Result<String, String> {
+        let r = self.rest();
+        let n = r.find(|c : char| c.is_whitespace() || c == '/' || c == '>' || c == '=').unwrap_or(r.len());
+        if n == 0 {
+            return self.error("expected a name");
+        }
+        self.pos += n;
+        Ok (r[..n].to_string())
+    }
+    fn element(&mut self) -> Result<Element, String> {
+        if !self.rest().starts_with('<') {
+            return self.error("expected an element");
+        }
+        self.pos += 1;
+        let mut e = Element::new(&self.name()?);
+        loop {
+            self.skip_ws();
+            let r = self.rest();
+            if r.starts_with("/>") {
+                self.pos += 2;
+                return Ok (e);
+            }
+            if r.starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+            let k = self.name()?;
+            self.skip_ws();
+            if !self.rest().starts_with('=') {
+                return self.error(&format!("expected = after attribute {}", k));
+            }
+            self.pos += 1;
+            self.skip_ws();
+            let q = match self.rest().chars().next() {
+                Some (q) if q == '"' || q == '\'' => q,
+                _ => return self.error(&format!("expected a quoted value for attribute {}", k))
+            };
+            self.pos += 1;
+            let end = match self.rest().find(q) {
+                Some (end) => end,
+                None => return self.error("unterminated attribute value")
+            };
+            let v = unescape(&self.rest()[..end]);
+            self.pos += end + 1;
+            e.attrs.push((k, v?));
+        }
+        loop {
+            let r = self.rest();
+            if r.starts_with("</") {
+                self.pos += 2;
+                let n = self.name()?;
+                if n != e.name {
+                    return self.error(&format!("</{}> closes <{}>", n, e.name));
+                }
+                self.skip_ws();
+                return self.skip_past(">").map(|_| e);
+            }
+            else if r.starts_with("<!--") {
+                self.skip_past("-->")?;
+            }
+            else if r.starts_with("<![CDATA[") {
+                self.pos += 9;
+                let end = match self.rest().find("]]>") {
+                    Some (end) => end,
+                    None => return self.error("unterminated CDATA section")
+                };
+                e.children.push(Node::Text (self.rest()[..end].to_string()));
+                self.pos += end + 3;
+            }
+            else if r.starts_with("<?") {
+                self.skip_past("?>")?;
+            }
+            else if r.starts_with('<') {
+                let c = self.element()?;
+                e.children.push(Node::Element (c));
+            }
+            else if r.is_empty() {
+                return self.error(&format!("unterminated element <{}>", e.name));
+            }
+            else {
+                let end = r.find('<').unwrap_or(r.len());
+                let t = unescape(&r[..end])?;
+                self.pos += end;
+                if !t.trim().is_empty() {
+                    e.children.push(Node::Text (t));
+                }
+            }
+        }
+    }
+}
+
+fn unescape(s : &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some (i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        let end = match rest[i..].find(';') {
+            Some (end) => i + end,
+            None => return Err (format!("unterminated entity in {}", s))
+        };
+        let ent = &rest[i + 1..end];
+        let c = match ent {
+            "amp" => Some ('&'),
+            "lt" => Some ('<'),
+            "gt" => Some ('>'),
+            "quot" => Some ('"'),
+            "apos" => Some ('\''),
+            _ if ent.starts_with("#x") => u32::from_str_radix(&ent[2..], 16).ok().and_then(std::char::from_u32),
+            _ if ent.starts_with('#') => ent[1..].parse::<u32>().ok().and_then(std::char::from_u32),
+            _ => None
+        };
+        match c {
+            Some (c) => out.push(c),
+            None => return Err (format!("unknown entity &{};", ent))
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok (out)
+}
+
+pub fn parse(s : &str) -> Result<Element, String> {
+    let mut r = Reader { src : s, pos : 0 };
+    r.skip_misc()?;
+    let e = r.element()?;
+    r.skip_misc()?;
+    if !r.rest().is_empty() {
+        return r.error("content after the document element");
+    }
+    Ok (e)
+}